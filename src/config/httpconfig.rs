@@ -1,16 +1,34 @@
 use std::fs::File;
-use std::path::PathBuf;
 use std::io::Read;
-use std::env;
+use std::sync::Arc;
 
 extern crate toml;
 
+use config::location::Location;
+use config::redirect::Redirect;
+use config::rewrite::RewriteRule;
+use config::siteconfig::SiteConfig;
+use config::tlsconfig::TlsConfig;
+use config::upstream;
+use config::vhost;
+use config::vhost::VirtualHost;
+use proxy::balancer::UpstreamGroup;
+use tls::identity::ClientAuth;
+
 pub struct HttpConfig {
-    root_path: PathBuf,
-    index: String,
-    port: String
+    port: String,
+    keepalive_timeout: u64,
+    keepalive_requests: u64,
+    site: SiteConfig,
+    vhosts: Vec<VirtualHost>,
+    strict_hosts: bool,
+    upstreams: Vec<Arc<UpstreamGroup>>,
+    tls: Option<TlsConfig>
 }
 
+const DEFAULT_KEEPALIVE_TIMEOUT: u64 = 5;
+const DEFAULT_KEEPALIVE_REQUESTS: u64 = 100;
+
 impl HttpConfig {
     pub fn new_from_file(filename: String) -> Result<HttpConfig, String> {
         let mut file = match File::open(filename) {
@@ -36,18 +54,6 @@ impl HttpConfig {
             }
         };
 
-        let root_path = match http_sec.as_table().unwrap().get("root_path") {
-            Some(root_path) => root_path,
-            None => {
-                return Err(format!("Root path must be set in config file."));
-            }
-        };
-
-        let index = match http_sec.as_table().unwrap().get("index") {
-            Some(index) => index.as_str().unwrap(),
-            None => "index.html"
-        };
-
         let port = match http_sec.as_table().unwrap().get("port") {
             Some(port) => match(port.as_str()) {
                 Some(portString) => portString,
@@ -56,32 +62,143 @@ impl HttpConfig {
             None => "8000"
         };
 
-        let mut path = PathBuf::new();
-        path.push(root_path.as_str().unwrap());
+        // Idle timeout in seconds between two requests on the same connection.
+        let keepalive_timeout = match http_sec.as_table().unwrap().get("keepalive_timeout") {
+            Some(timeout) => match timeout.as_integer() {
+                Some(timeout) if timeout >= 0 => timeout as u64,
+                _ => {
+                    return Err(format!("keepalive_timeout must be a positive integer."));
+                }
+            },
+            None => DEFAULT_KEEPALIVE_TIMEOUT
+        };
+
+        let keepalive_requests = match http_sec.as_table().unwrap().get("keepalive_requests") {
+            Some(requests) => match requests.as_integer() {
+                Some(requests) if requests > 0 => requests as u64,
+                _ => {
+                    return Err(format!("keepalive_requests must be a strictly positive integer."));
+                }
+            },
+            None => DEFAULT_KEEPALIVE_REQUESTS
+        };
+
+        // Named before the locations that proxy to them.
+        let upstreams = upstream::parse_all(&conf)?;
+
+        let mut site = SiteConfig::from_toml(http_sec.as_table().unwrap(), None, "http")?;
+        let locations = Location::parse_all(http_sec.as_table().unwrap(), &site, &upstreams, "http")?;
+        site.set_locations(locations);
+        site.set_redirects(Redirect::parse_all(http_sec.as_table().unwrap(), "http")?);
+        site.set_rewrites(RewriteRule::parse_all(http_sec.as_table().unwrap(), "http")?);
+
+        // Requests for names no [[vhost]] has go to the default server: the
+        // vhost marked as such, or [http] itself unless hosts are strict.
+        let strict_hosts = match http_sec.as_table().unwrap().get("strict_hosts") {
+            Some(strict) => match strict.as_bool() {
+                Some(strict) => strict,
+                None => {
+                    return Err(format!("strict_hosts must be true or false."));
+                }
+            },
+            None => false
+        };
+
+        let mut vhosts = Vec::new();
+        if let Some(entries) = conf.get("vhost") {
+            let entries = match entries.as_slice() {
+                Some(entries) => entries,
+                None => {
+                    return Err(format!("vhost must be an array of tables."));
+                }
+            };
+            for entry in entries {
+                vhosts.push(VirtualHost::from_toml(entry, &site, &upstreams)?);
+            }
+        }
+        if vhosts.iter().filter(|vhost| vhost.is_default()).count() > 1 {
+            return Err(format!("Only one vhost can be the default server."));
+        }
+
+        let tls = match conf.get("tls") {
+            Some(section) => Some(TlsConfig::from_toml(section)?),
+            None => None
+        };
+
+        // Without TLS client authentication no certificate ever comes, so
+        // those locations would refuse every request.
+        let client_auth = tls.as_ref().map_or(ClientAuth::Off, |tls| tls.get_client_auth());
+        let sites = Some(&site).into_iter().chain(vhosts.iter().map(|vhost| vhost.get_site()));
+        let mut locations = sites.flat_map(|site| site.get_locations());
+        if client_auth == ClientAuth::Off && locations.any(|location| location.get_client_certificate().is_some()) {
+            return Err(format!("A location's client_certificate = \"required\" needs tls.client_auth to be on."));
+        }
+
         return Ok(HttpConfig {
-            root_path: path,
-            index: String::from(index),
-            port: String::from(port)
+            port: String::from(port),
+            keepalive_timeout: keepalive_timeout,
+            keepalive_requests: keepalive_requests,
+            site: site,
+            vhosts: vhosts,
+            strict_hosts: strict_hosts,
+            upstreams: upstreams,
+            tls: tls
         });
     }
 
     pub fn new_defaults() -> Option<HttpConfig> {
         return Some(HttpConfig {
-            root_path: env::current_dir().unwrap(),
-            index: String::from("index.html"),
-            port: String::from("8000")
+            port: String::from("8000"),
+            keepalive_timeout: DEFAULT_KEEPALIVE_TIMEOUT,
+            keepalive_requests: DEFAULT_KEEPALIVE_REQUESTS,
+            site: SiteConfig::new_defaults(),
+            vhosts: Vec::new(),
+            strict_hosts: false,
+            upstreams: Vec::new(),
+            tls: None
         });
     }
 
-    pub fn get_root_path(&self) -> Box<&str> {
-        return Box::new(self.root_path.to_str().unwrap());
+    pub fn get_port(&self) -> Box<&String> {
+        return Box::new(&self.port);
     }
 
-    pub fn get_index(&self) -> Box<&String> {
-        return Box::new(&self.index);
+    // A timeout of 0 disables the idle timeout altogether.
+    pub fn get_keepalive_timeout(&self) -> u64 {
+        return self.keepalive_timeout;
     }
 
-    pub fn get_port(&self) -> Box<&String> {
-        return Box::new(&self.port);
+    pub fn get_keepalive_requests(&self) -> u64 {
+        return self.keepalive_requests;
+    }
+
+    // Settings of the [http] section itself.
+    pub fn get_site(&self) -> &SiteConfig {
+        return &self.site;
+    }
+
+    // The vhost for a host name, as returned by vhost::host_name. None
+    // means the default server.
+    pub fn find_vhost(&self, name: &str) -> Option<&VirtualHost> {
+        return vhost::find(&self.vhosts, name);
+    }
+
+    // Site for requests no vhost claims, if any.
+    pub fn get_default_site(&self) -> Option<&SiteConfig> {
+        match self.vhosts.iter().find(|vhost| vhost.is_default()) {
+            Some(vhost) => return Some(vhost.get_site()),
+            None if self.strict_hosts => return None,
+            None => return Some(&self.site)
+        }
+    }
+
+    // The [[upstream]] groups, whichever locations proxy to them.
+    pub fn get_upstreams(&self) -> &[Arc<UpstreamGroup>] {
+        return &self.upstreams;
+    }
+
+    // HTTPS listener, if the [tls] section is there.
+    pub fn get_tls(&self) -> Option<&TlsConfig> {
+        return self.tls.as_ref();
     }
 }
@@ -0,0 +1,507 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+extern crate toml;
+
+use regex::{Regex, RegexBuilder};
+
+use config::redirect::Redirect;
+use config::siteconfig::SiteConfig;
+use config::string_list;
+use config::upstream;
+use config::upstream::{parse_pool, parse_timeout, DEFAULT_CONNECT_TIMEOUT, DEFAULT_READ_TIMEOUT};
+use files::cgi::CgiSettings;
+use http::auth::BasicAuth;
+use http::request::HttpMethod;
+use http::url::percent_encode;
+use proxy::balancer::UpstreamGroup;
+use proxy::upstream::{Upstream, UpstreamAddress};
+
+// How a location matches the request path, written nginx-style in front of
+// the path.
+#[derive(Clone)]
+pub enum LocationMatch {
+    // "= /favicon.ico"
+    Exact(String),
+    // "/static/", or "^~ /static/" to skip regex locations when it's the
+    // longest prefix.
+    Prefix(String, bool),
+    // "~ \.php$", or "~* \.(png|jpg)$" ignoring case.
+    Pattern(Regex),
+}
+
+impl LocationMatch {
+    pub fn parse(path: &str) -> Result<LocationMatch, String> {
+        let (modifier, rest) = match path.find(' ') {
+            Some(pos) => (&path[..pos], path[pos..].trim_start()),
+            None => ("", path),
+        };
+
+        let pattern = |case_insensitive| match RegexBuilder::new(rest).case_insensitive(case_insensitive).build() {
+            Ok(regex) => Ok(LocationMatch::Pattern(regex)),
+            Err(e) => Err(format!("Invalid location pattern {:?}: {}", rest, e)),
+        };
+
+        match modifier {
+            "=" => Ok(LocationMatch::Exact(rest.to_string())),
+            "^~" => Ok(LocationMatch::Prefix(rest.to_string(), true)),
+            "~" => pattern(false),
+            "~*" => pattern(true),
+            "" if path.starts_with('/') => Ok(LocationMatch::Prefix(path.to_string(), false)),
+            _ => Err(format!("Invalid location {:?}, expected a path with an optional =, ^~, ~ or ~* in front.", path)),
+        }
+    }
+
+    // Whether a decoded, normalized path is one of the location's.
+    pub fn matches(&self, path: &str) -> bool {
+        match *self {
+            LocationMatch::Exact(ref exact) => exact == path,
+            LocationMatch::Prefix(ref prefix, _) => path.starts_with(prefix.as_str()),
+            LocationMatch::Pattern(ref regex) => regex.is_match(path),
+        }
+    }
+}
+
+// Seconds a CGI script can run for.
+const DEFAULT_CGI_TIMEOUT: u64 = 60;
+
+// What answers the requests of a location.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Handler {
+    // Files under the root.
+    Static,
+    // An upstream server, see proxy_pass.
+    Proxy,
+    // CGI scripts under the root, see cgi_interpreter.
+    Cgi,
+    // Another URL, see redirect_to.
+    Redirect,
+}
+
+impl FromStr for Handler {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Handler, ()> {
+        match s {
+            "static" => Ok(Handler::Static),
+            "proxy" => Ok(Handler::Proxy),
+            "cgi" => Ok(Handler::Cgi),
+            "redirect" => Ok(Handler::Redirect),
+            _ => Err(()),
+        }
+    }
+}
+
+// Whether a location wants a client certificate, on top of what the TLS
+// listener asks for.
+#[derive(Clone)]
+pub struct CertificateRule {
+    // Subjects or alternative names let through, any verified certificate
+    // when empty.
+    pub subjects: Vec<String>,
+}
+
+// A [[http.location]] or [[vhost.location]] section: settings for the
+// requests whose path it matches.
+#[derive(Clone)]
+pub struct Location {
+    matcher: LocationMatch,
+    site: SiteConfig,
+    alias: bool,
+    methods: Vec<HttpMethod>,
+    headers: Vec<(String, String)>,
+    cache_control: Option<String>,
+    expires: Option<u64>,
+    auth: Option<BasicAuth>,
+    client_certificate: Option<CertificateRule>,
+    handler: Handler,
+    proxy: Option<Arc<UpstreamGroup>>,
+    cgi: Option<CgiSettings>,
+    redirect: Option<Redirect>
+}
+
+impl Location {
+    // Settings the location doesn't give are those of its server.
+    pub fn from_toml(section: &toml::Value, parent: &SiteConfig, upstreams: &[Arc<UpstreamGroup>], prefix: &str) -> Result<Location, String> {
+        let section = match section.as_table() {
+            Some(section) => section,
+            None => {
+                return Err(format!("{}.location must be an array of tables.", prefix));
+            }
+        };
+
+        let path = match section.get("path").and_then(|path| path.as_str()) {
+            Some(path) => path,
+            None => {
+                return Err(format!("{}.location.path must be set, e.g. \"/static/\" or \"~ \\\\.php$\".", prefix));
+            }
+        };
+        let matcher = LocationMatch::parse(path)?;
+        let prefix = format!("{}.location {:?}", prefix, path);
+
+        // The part of the path the location matched is replaced with the
+        // alias, where root_path would have it appended. The alias serves
+        // as the location's root.
+        let mut site_section = section.clone();
+        let alias = match section.get("alias") {
+            Some(alias) => match (alias.as_str(), &matcher) {
+                (_, &LocationMatch::Pattern(_)) => {
+                    return Err(format!("{}: alias can't be used with a regex location.", prefix));
+                },
+                (_, _) if section.contains_key("root_path") => {
+                    return Err(format!("{}: root_path and alias can't both be set.", prefix));
+                },
+                (Some(_), _) => {
+                    site_section.insert(String::from("root_path"), alias.clone());
+                    true
+                },
+                (None, _) => {
+                    return Err(format!("{}: alias must be a path.", prefix));
+                }
+            },
+            None => false
+        };
+
+        // Methods answered, others get a 405. All of them when left out.
+        let mut methods = Vec::new();
+        if let Some(names) = section.get("methods") {
+            for name in string_list(names, &format!("{}: methods", prefix))? {
+                match HttpMethod::from_str(&name) {
+                    Ok(method) => methods.push(method),
+                    Err(_) => {
+                        return Err(format!("{}: unknown method {:?}.", prefix, name));
+                    }
+                }
+            }
+        }
+
+        // Set on every response, replacing any the handler gave.
+        let mut headers = Vec::new();
+        if let Some(table) = section.get("headers") {
+            let table = match table.as_table() {
+                Some(table) => table,
+                None => {
+                    return Err(format!("{}: headers must be a table of name = \"value\".", prefix));
+                }
+            };
+            for (name, value) in table.iter() {
+                match value.as_str() {
+                    Some(value) => headers.push((name.clone(), value.to_string())),
+                    None => {
+                        return Err(format!("{}: header {} must be a string.", prefix, name));
+                    }
+                }
+            }
+        }
+
+        let cache_control = match section.get("cache_control") {
+            Some(cache_control) => match cache_control.as_str() {
+                Some(cache_control) => Some(cache_control.to_string()),
+                None => {
+                    return Err(format!("{}: cache_control must be a string.", prefix));
+                }
+            },
+            None => None
+        };
+
+        // Seconds successful responses may be cached for.
+        let expires = match section.get("expires") {
+            Some(expires) => match expires.as_integer() {
+                Some(expires) if expires >= 0 => Some(expires as u64),
+                _ => {
+                    return Err(format!("{}: expires must be a positive number of seconds.", prefix));
+                }
+            },
+            None => None
+        };
+
+        let auth = match (section.get("auth_basic"), section.get("auth_users_file")) {
+            (Some(realm), Some(users)) => match (realm.as_str(), users.as_str()) {
+                (Some(realm), Some(users)) => Some(BasicAuth::load(realm, users)?),
+                _ => {
+                    return Err(format!("{}: auth_basic must be a realm and auth_users_file a path.", prefix));
+                }
+            },
+            (None, None) => None,
+            _ => {
+                return Err(format!("{}: auth_basic and auth_users_file go together.", prefix));
+            }
+        };
+
+        // Only "required" makes sense here: the TLS listener already asks
+        // for certificates when client_auth is "optional".
+        let client_certificate = match section.get("client_certificate").map(|value| value.as_str()) {
+            Some(Some("required")) => Some(CertificateRule {
+                subjects: match section.get("client_subjects") {
+                    Some(subjects) => string_list(subjects, &format!("{}: client_subjects", prefix))?,
+                    None => Vec::new()
+                }
+            }),
+            Some(Some("off")) | None => None,
+            Some(_) => {
+                return Err(format!("{}: client_certificate must be \"required\" or \"off\".", prefix));
+            }
+        };
+        if client_certificate.is_none() && section.contains_key("client_subjects") {
+            return Err(format!("{}: client_subjects needs client_certificate = \"required\".", prefix));
+        }
+
+        // A single server, or an [[upstream]] group of them named here.
+        let proxy = match (section.get("proxy_pass"), section.get("upstream")) {
+            (Some(_), Some(_)) => {
+                return Err(format!("{}: proxy_pass and upstream can't both be set.", prefix));
+            },
+            (Some(address), None) => match address.as_str().map(UpstreamAddress::from_str) {
+                Some(Ok(address)) => Some(Arc::new(UpstreamGroup::single(Upstream::new(
+                    address,
+                    parse_timeout(section, "proxy_connect_timeout", &prefix, DEFAULT_CONNECT_TIMEOUT)?,
+                    parse_timeout(section, "proxy_read_timeout", &prefix, DEFAULT_READ_TIMEOUT)?
+                ).with_pool(parse_pool(section, "proxy_", &prefix)?)))),
+                _ => {
+                    return Err(format!("{}: proxy_pass must be \"http://host:port\" or \"unix:/path/to/socket\".", prefix));
+                }
+            },
+            (None, Some(name)) => match name.as_str().and_then(|name| upstream::find(upstreams, name)) {
+                Some(group) => Some(group.clone()),
+                None => {
+                    return Err(format!("{}: upstream must name an [[upstream]] section.", prefix));
+                }
+            },
+            (None, None) => None
+        };
+        // Servers of an [[upstream]] have its settings.
+        let upstream_keys = ["proxy_connect_timeout", "proxy_read_timeout", "proxy_max_idle", "proxy_idle_timeout", "proxy_max_lifetime"];
+        if let Some(key) = upstream_keys.iter().find(|&&key| section.contains_key(key)) {
+            if !section.contains_key("proxy_pass") {
+                return Err(format!("{}: {} goes with proxy_pass.", prefix, key));
+            }
+        }
+
+        // Locations with an upstream proxy to it, and those with a
+        // redirect_to redirect, unless told otherwise.
+        let handler = match section.get("handler") {
+            Some(handler) => match handler.as_str().map(Handler::from_str) {
+                Some(Ok(handler)) => handler,
+                _ => {
+                    return Err(format!("{}: handler must be \"static\", \"proxy\", \"cgi\" or \"redirect\".", prefix));
+                }
+            },
+            None if proxy.is_some() => Handler::Proxy,
+            None if section.contains_key("redirect_to") => Handler::Redirect,
+            None => Handler::Static
+        };
+        if (handler == Handler::Proxy) != proxy.is_some() {
+            return Err(format!("{}: proxy_pass or upstream goes with handler = \"proxy\".", prefix));
+        }
+
+        let redirect_keys = ["redirect_to", "redirect_status"];
+        let redirect = match handler {
+            Handler::Redirect => Some(Redirect::for_location(&matcher, section, &prefix)?),
+            _ => match redirect_keys.iter().find(|&&key| section.contains_key(key)) {
+                Some(key) => {
+                    return Err(format!("{}: {} goes with handler = \"redirect\".", prefix, key));
+                },
+                None => None
+            }
+        };
+
+        // Scripts are run by the interpreter if there's one, by themselves
+        // otherwise, and killed after cgi_timeout seconds.
+        let cgi_keys = ["cgi_interpreter", "cgi_timeout"];
+        let cgi = match handler {
+            Handler::Cgi => Some(CgiSettings {
+                scripts: matcher.clone(),
+                interpreter: match section.get("cgi_interpreter") {
+                    Some(interpreter) => match interpreter.as_str() {
+                        Some(interpreter) if !interpreter.is_empty() => Some(PathBuf::from(interpreter)),
+                        _ => {
+                            return Err(format!("{}: cgi_interpreter must be a path.", prefix));
+                        }
+                    },
+                    None => None
+                },
+                timeout: parse_timeout(section, "cgi_timeout", &prefix, DEFAULT_CGI_TIMEOUT)?
+            }),
+            _ => match cgi_keys.iter().find(|&&key| section.contains_key(key)) {
+                Some(key) => {
+                    return Err(format!("{}: {} goes with handler = \"cgi\".", prefix, key));
+                },
+                None => None
+            }
+        };
+
+        return Ok(Location {
+            matcher: matcher,
+            site: SiteConfig::from_toml(&site_section, Some(parent), &prefix)?,
+            alias: alias,
+            methods: methods,
+            headers: headers,
+            cache_control: cache_control,
+            expires: expires,
+            auth: auth,
+            client_certificate: client_certificate,
+            handler: handler,
+            proxy: proxy,
+            cgi: cgi,
+            redirect: redirect
+        });
+    }
+
+    // Parses the location array of a server section, if there's one.
+    pub fn parse_all(section: &toml::Table, parent: &SiteConfig, upstreams: &[Arc<UpstreamGroup>], prefix: &str) -> Result<Vec<Location>, String> {
+        let entries = match section.get("location") {
+            Some(entries) => match entries.as_slice() {
+                Some(entries) => entries,
+                None => {
+                    return Err(format!("{}.location must be an array of tables.", prefix));
+                }
+            },
+            None => return Ok(Vec::new())
+        };
+
+        let mut locations = Vec::new();
+        for entry in entries {
+            locations.push(Location::from_toml(entry, parent, upstreams, prefix)?);
+        }
+        return Ok(locations);
+    }
+
+    pub fn get_site(&self) -> &SiteConfig {
+        return &self.site;
+    }
+
+    // Path to look up under the location's root, percent-encoded again:
+    // the decoded path itself, or what's left of it once an aliased prefix
+    // is taken away.
+    pub fn map_path(&self, path: &str) -> String {
+        let rest = match self.matcher {
+            LocationMatch::Prefix(ref prefix, _) if self.alias => &path[prefix.len()..],
+            _ if self.alias => "",
+            _ => path,
+        };
+        rest.split('/').map(percent_encode).collect::<Vec<_>>().join("/")
+    }
+
+    // Empty when every method is allowed.
+    pub fn get_methods(&self) -> &[HttpMethod] {
+        return &self.methods;
+    }
+
+    pub fn get_headers(&self) -> &[(String, String)] {
+        return &self.headers;
+    }
+
+    pub fn get_cache_control(&self) -> Option<&str> {
+        return self.cache_control.as_deref();
+    }
+
+    pub fn get_expires(&self) -> Option<u64> {
+        return self.expires;
+    }
+
+    pub fn get_auth(&self) -> Option<&BasicAuth> {
+        return self.auth.as_ref();
+    }
+
+    pub fn get_client_certificate(&self) -> Option<&CertificateRule> {
+        return self.client_certificate.as_ref();
+    }
+
+    pub fn get_handler(&self) -> Handler {
+        return self.handler;
+    }
+
+    // Upstream of a proxy location.
+    pub fn get_proxy(&self) -> Option<&UpstreamGroup> {
+        return self.proxy.as_deref();
+    }
+
+    // Settings of a CGI location.
+    pub fn get_cgi(&self) -> Option<&CgiSettings> {
+        return self.cgi.as_ref();
+    }
+
+    // Target of a redirect location.
+    pub fn get_redirect(&self) -> Option<&Redirect> {
+        return self.redirect.as_ref();
+    }
+}
+
+// The location for a decoded, normalized path, picked the way nginx does:
+// an exact match wins outright, then the longest prefix if it has ^~, then
+// the first regex in configuration order and finally the longest prefix.
+pub fn find<'a>(locations: &'a [Location], path: &str) -> Option<&'a Location> {
+    if let Some(location) = locations.iter().find(|location| match location.matcher {
+        LocationMatch::Exact(ref exact) => exact == path,
+        _ => false,
+    }) {
+        return Some(location);
+    }
+
+    let longest = locations.iter()
+        .filter_map(|location| match location.matcher {
+            LocationMatch::Prefix(ref prefix, stop) if path.starts_with(prefix.as_str()) => Some((prefix.len(), stop, location)),
+            _ => None,
+        })
+        .fold(None, |best: Option<(usize, bool, &Location)>, candidate| match best {
+            Some(best) if best.0 >= candidate.0 => Some(best),
+            _ => Some(candidate),
+        });
+    if let Some((_, true, location)) = longest {
+        return Some(location);
+    }
+
+    let pattern = locations.iter().find(|location| match location.matcher {
+        LocationMatch::Pattern(ref regex) => regex.is_match(path),
+        _ => false,
+    });
+    pattern.or(longest.map(|longest| longest.2))
+}
+
+#[test]
+fn picks_locations_by_precedence() {
+    let site = SiteConfig::new_defaults();
+    let location = |path: &str| {
+        let mut section = toml::Table::new();
+        section.insert("path".to_string(), toml::Value::String(path.to_string()));
+        Location::from_toml(&toml::Value::Table(section), &site, &[], "http").ok().unwrap()
+    };
+    let locations = vec![
+        location("/"),
+        location("~* \\.(png|jpg)$"),
+        location("/images/"),
+        location("^~ /static/"),
+        location("= /favicon.png"),
+        location("/images/raw/"),
+    ];
+    let index = |path: &str| find(&locations, path).map(|found| locations.iter().position(|location| ::std::ptr::eq(location, found)).unwrap());
+
+    assert!(index("/favicon.png") == Some(4));
+    assert!(index("/static/logo.png") == Some(3));
+    assert!(index("/images/logo.PNG") == Some(1));
+    assert!(index("/images/raw/") == Some(5));
+    assert!(index("/images/") == Some(2));
+    assert!(index("/about") == Some(0));
+    assert!(find(&locations[1..2], "/about").is_none());
+    assert!(LocationMatch::parse("static/").is_err());
+}
+
+#[test]
+fn parses_cgi_and_redirect_locations() {
+    let site = SiteConfig::new_defaults();
+    let parse = |text: &str| toml::Parser::new(text).parse()
+        .and_then(|section| Location::from_toml(&toml::Value::Table(section), &site, &[], "http").ok());
+
+    let location = parse("path = \"~ \\\\.cgi$\"\nhandler = \"cgi\"\ncgi_interpreter = \"/bin/sh\"\n").unwrap();
+    assert!(location.get_handler() == Handler::Cgi);
+    assert!(location.get_cgi().map(|cgi| cgi.timeout) == Some(::std::time::Duration::from_secs(DEFAULT_CGI_TIMEOUT)));
+
+    let location = parse("path = \"/old/\"\nredirect_to = \"/new/\"\nredirect_status = 301\n").unwrap();
+    assert!(location.get_handler() == Handler::Redirect);
+    assert!(location.get_redirect().map(|redirect| redirect.get_status()) == Some(::http::response::HttpStatus::MOVED_PERMANENTLY));
+
+    assert!(parse("path = \"/\"\nhandler = \"redirect\"\n").is_none());
+    assert!(parse("path = \"/\"\ncgi_timeout = 5\n").is_none());
+    assert!(parse("path = \"/\"\nhandler = \"static\"\nredirect_to = \"/new/\"\n").is_none());
+}
@@ -18,4 +18,34 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
 
+extern crate toml;
+
 pub mod httpconfig;
+pub mod location;
+pub mod redirect;
+pub mod rewrite;
+pub mod siteconfig;
+pub mod tlsconfig;
+pub mod upstream;
+pub mod vhost;
+
+// Reads an array of strings, naming the key in the error.
+pub fn string_list(value: &toml::Value, key: &str) -> Result<Vec<String>, String> {
+    let items = match value.as_slice() {
+        Some(items) => items,
+        None => {
+            return Err(format!("{} must be an array of strings.", key));
+        }
+    };
+
+    let mut strings = Vec::new();
+    for item in items {
+        match item.as_str() {
+            Some(item) => strings.push(item.to_string()),
+            None => {
+                return Err(format!("{} must be an array of strings.", key));
+            }
+        }
+    }
+    return Ok(strings);
+}
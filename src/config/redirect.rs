@@ -0,0 +1,227 @@
+extern crate toml;
+
+use config::location::LocationMatch;
+use config::rewrite::{encode_path, encode_query};
+use config::vhost;
+use files::path;
+use http::request::HttpRequest;
+use http::response::HttpStatus;
+#[cfg(test)]
+use http::client::ClientInfo;
+#[cfg(test)]
+use http::traits::FromString;
+use http::traits::FromU16;
+
+// A [[http.redirect]] or [[vhost.redirect]] section: requests whose path
+// it matches are sent elsewhere before anything else is done with them.
+#[derive(Clone)]
+pub struct Redirect {
+    matcher: LocationMatch,
+    target: String,
+    status: HttpStatus,
+    scheme: Option<String>,
+    keep_query: bool
+}
+
+// A path on the same server or an absolute URL, where $host and $scheme
+// stand for those of the request.
+fn parse_target(section: &toml::Table, key: &str, prefix: &str) -> Result<String, String> {
+    match section.get(key).and_then(|to| to.as_str()) {
+        Some(to) if to.starts_with('/') || ["http://", "https://", "$scheme://"].iter().any(|scheme| to.starts_with(scheme)) => Ok(to.to_string()),
+        _ => Err(format!("{}: {} must be a path starting with / or an http://, https:// or $scheme:// URL.", prefix, key))
+    }
+}
+
+fn parse_status(section: &toml::Table, key: &str, prefix: &str) -> Result<HttpStatus, String> {
+    match section.get(key) {
+        Some(status) => match status.as_integer() {
+            Some(code @ (301 | 302 | 303 | 307 | 308)) => Ok(HttpStatus::from_u16(code as u16).unwrap()),
+            _ => Err(format!("{}: {} must be 301, 302, 303, 307 or 308.", prefix, key))
+        },
+        None => Ok(HttpStatus::FOUND)
+    }
+}
+
+impl Redirect {
+    pub fn from_toml(section: &toml::Value, prefix: &str) -> Result<Redirect, String> {
+        let section = match section.as_table() {
+            Some(section) => section,
+            None => {
+                return Err(format!("{}.redirect must be an array of tables.", prefix));
+            }
+        };
+
+        // Same syntax as location paths: "= /old" for that path only, a
+        // prefix whose remainder is appended to the target, or a regex
+        // whose captures the target can use.
+        let from = match section.get("from").and_then(|from| from.as_str()) {
+            Some(from) => from,
+            None => {
+                return Err(format!("{}.redirect.from must be set, e.g. \"= /old\" or \"/docs/\".", prefix));
+            }
+        };
+        let matcher = LocationMatch::parse(from)?;
+        let prefix = format!("{}.redirect {:?}", prefix, from);
+
+        let target = parse_target(section, "to", &prefix)?;
+        let status = parse_status(section, "status", &prefix)?;
+
+        // Only redirect requests that came over this scheme, e.g. "http"
+        // to send plain requests to the HTTPS listener.
+        let scheme = match section.get("scheme").map(|scheme| scheme.as_str()) {
+            Some(Some(scheme @ ("http" | "https"))) => Some(scheme.to_string()),
+            None => None,
+            Some(_) => {
+                return Err(format!("{}: scheme must be \"http\" or \"https\".", prefix));
+            }
+        };
+
+        // The request's query goes along unless the target has its own.
+        let keep_query = match section.get("keep_query") {
+            Some(keep_query) => match keep_query.as_bool() {
+                Some(keep_query) => keep_query,
+                None => {
+                    return Err(format!("{}: keep_query must be true or false.", prefix));
+                }
+            },
+            None => true
+        };
+
+        return Ok(Redirect {
+            matcher: matcher,
+            target: target,
+            status: status,
+            scheme: scheme,
+            keep_query: keep_query
+        });
+    }
+
+    // The redirect of a location with handler = "redirect", which matches
+    // the paths the location does.
+    pub fn for_location(matcher: &LocationMatch, section: &toml::Table, prefix: &str) -> Result<Redirect, String> {
+        return Ok(Redirect {
+            matcher: matcher.clone(),
+            target: parse_target(section, "redirect_to", prefix)?,
+            status: parse_status(section, "redirect_status", prefix)?,
+            scheme: None,
+            keep_query: true
+        });
+    }
+
+    pub fn get_status(&self) -> HttpStatus {
+        return self.status;
+    }
+
+    // Parses the redirect array of a server section, if there's one.
+    pub fn parse_all(section: &toml::Table, prefix: &str) -> Result<Vec<Redirect>, String> {
+        let entries = match section.get("redirect") {
+            Some(entries) => match entries.as_slice() {
+                Some(entries) => entries,
+                None => {
+                    return Err(format!("{}.redirect must be an array of tables.", prefix));
+                }
+            },
+            None => return Ok(Vec::new())
+        };
+
+        let mut redirects = Vec::new();
+        for entry in entries {
+            redirects.push(Redirect::from_toml(entry, prefix)?);
+        }
+        return Ok(redirects);
+    }
+
+    // Where the request for a decoded, normalized path goes, if the rule
+    // applies to it.
+    pub fn apply(&self, req: &HttpRequest, url_path: &str) -> Option<String> {
+        let scheme = req.client().scheme();
+        if self.scheme.as_deref().is_some_and(|expected| expected != scheme) {
+            return None;
+        }
+
+        // Host names can't hold a $, so they can't be taken for captures.
+        let mut target = self.target.replace("$scheme", scheme);
+        if target.contains("$host") {
+            let host = vhost::host_name(req.host()?)?;
+            target = target.replace("$host", &host);
+        }
+
+        let target = match self.matcher {
+            LocationMatch::Exact(ref exact) if exact == url_path => target,
+            LocationMatch::Prefix(ref prefix, _) if url_path.starts_with(prefix.as_str()) => {
+                format!("{}{}", target, &url_path[prefix.len()..])
+            },
+            LocationMatch::Pattern(ref regex) => {
+                let mut expanded = String::new();
+                regex.captures(url_path)?.expand(&target, &mut expanded);
+                expanded
+            },
+            _ => return None,
+        };
+
+        // The path was matched decoded, so it's encoded again past the
+        // scheme and authority of an absolute URL.
+        let (origin, rest) = match target.find("://") {
+            Some(pos) => target.split_at(target[pos + 3..].find('/').map_or(target.len(), |end| pos + 3 + end)),
+            None => target.split_at(0),
+        };
+        let (url_path, query) = match rest.find('?') {
+            Some(pos) => (&rest[..pos], Some(encode_query(&rest[pos + 1..]))),
+            None if self.keep_query => (rest, req.query.clone()),
+            None => (rest, None),
+        };
+
+        let mut location = format!("{}{}", origin, encode_path(url_path));
+        if let Some(query) = query.filter(|query| !query.is_empty()) {
+            location.push('?');
+            location.push_str(&query);
+        }
+        Some(location)
+    }
+}
+
+// The status and Location of the first rule, in configuration order, that
+// redirects the request. Paths that can't be normalized are never
+// redirected.
+pub fn find(redirects: &[Redirect], req: &HttpRequest) -> Option<(HttpStatus, String)> {
+    if redirects.is_empty() {
+        return None;
+    }
+    let url_path = path::normalize_path(&req.path).ok()?;
+    redirects.iter().find_map(|redirect| redirect.apply(req, &url_path).map(|location| (redirect.status, location)))
+}
+
+#[test]
+fn redirects_by_path_scheme_and_host() {
+    use std::sync::Arc;
+
+    let rule = |entries: &[(&str, toml::Value)]| {
+        let mut section = toml::Table::new();
+        for &(key, ref value) in entries {
+            section.insert(key.to_string(), value.clone());
+        }
+        Redirect::from_toml(&toml::Value::Table(section), "http").ok().unwrap()
+    };
+    let text = |s: &str| toml::Value::String(s.to_string());
+    let run = |redirects: &[Redirect], target: &str, secure: bool| {
+        let mut req = HttpRequest::from_string(format!("GET {} HTTP/1.1\r\nHost: Example.org:8000", target)).ok().unwrap();
+        let mut client = ClientInfo::new("127.0.0.1:1234");
+        client.secure = secure;
+        req.set_client(Arc::new(client));
+        find(redirects, &req).map(|(status, location)| (status.code(), location))
+    };
+
+    let redirects = vec![
+        rule(&[("from", text("= /old")), ("to", text("/new?from=old")), ("status", toml::Value::Integer(301))]),
+        rule(&[("from", text("/docs/")), ("to", text("/manual/"))]),
+        rule(&[("from", text("~ ^/users/(?P<id>\\d+)$")), ("to", text("/u/${id}/profile")), ("keep_query", toml::Value::Boolean(false))]),
+        rule(&[("from", text("/")), ("to", text("https://$host:8443/")), ("scheme", text("http")), ("status", toml::Value::Integer(308))]),
+    ];
+
+    assert!(run(&redirects, "/old?x=1", false) == Some((301, "/new?from=old".to_string())));
+    assert!(run(&redirects, "/docs/a%20b/./c?x=1", false) == Some((302, "/manual/a%20b/c?x=1".to_string())));
+    assert!(run(&redirects, "/users/42?x=1", true) == Some((302, "/u/42/profile".to_string())));
+    assert!(run(&redirects, "/users/me?x=1", false) == Some((308, "https://example.org:8443/users/me?x=1".to_string())));
+    assert!(run(&redirects, "/users/me", true).is_none());
+    assert!(Redirect::from_toml(&toml::Value::Table(toml::Table::new()), "http").is_err());
+}
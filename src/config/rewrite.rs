@@ -0,0 +1,278 @@
+use std::path::Path;
+
+extern crate toml;
+
+use regex::Regex;
+
+use config::string_list;
+use files::path;
+use files::path::SymlinkPolicy;
+use http::request::{HttpMethod, HttpRequest};
+#[cfg(test)]
+use http::traits::FromString;
+use http::url::percent_encode;
+
+// Passes over the rules before giving up on a request whose rewrites keep
+// restarting.
+const MAX_PASSES: usize = 10;
+
+// What happens once a rule rewrote the path.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RewriteFlag {
+    // Go on with the next rule.
+    Continue,
+    // Start over from the first rule with the new path.
+    Last,
+    // Stop rewriting.
+    Break,
+}
+
+// Condition on what the path, before the rule rewrites it, points to under
+// the server's root.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FileCondition {
+    // A file or directory.
+    Exists,
+    Missing,
+}
+
+#[derive(Clone)]
+pub struct RewriteRule {
+    pattern: Regex,
+    replacement: String,
+    query: Option<Regex>,
+    host: Option<Regex>,
+    methods: Vec<HttpMethod>,
+    file: Option<FileCondition>,
+    flag: RewriteFlag
+}
+
+fn parse_regex(section: &toml::Table, key: &str, prefix: &str) -> Result<Option<Regex>, String> {
+    match section.get(key) {
+        Some(value) => match value.as_str().map(Regex::new) {
+            Some(Ok(regex)) => Ok(Some(regex)),
+            Some(Err(e)) => Err(format!("{}: invalid {} pattern: {}", prefix, key, e)),
+            None => Err(format!("{}: {} must be a regex.", prefix, key))
+        },
+        None => Ok(None)
+    }
+}
+
+impl RewriteRule {
+    pub fn from_toml(section: &toml::Value, prefix: &str) -> Result<RewriteRule, String> {
+        let section = match section.as_table() {
+            Some(section) => section,
+            None => {
+                return Err(format!("{}.rewrite must be an array of tables.", prefix));
+            }
+        };
+
+        // Matched against the decoded, normalized path.
+        let pattern = match parse_regex(section, "pattern", prefix)? {
+            Some(pattern) => pattern,
+            None => {
+                return Err(format!("{}.rewrite.pattern must be set.", prefix));
+            }
+        };
+        let prefix = format!("{}.rewrite {:?}", prefix, pattern.as_str());
+
+        // A path with $1, ${name} and the like for the captures, and maybe a
+        // query string in place of the request's.
+        let replacement = match section.get("replacement").and_then(|replacement| replacement.as_str()) {
+            Some(replacement) if replacement.starts_with('/') => replacement.to_string(),
+            _ => {
+                return Err(format!("{}: replacement must be a path starting with /.", prefix));
+            }
+        };
+
+        let mut methods = Vec::new();
+        if let Some(names) = section.get("methods") {
+            for name in string_list(names, &format!("{}: methods", prefix))? {
+                match name.parse::<HttpMethod>() {
+                    Ok(method) => methods.push(method),
+                    Err(_) => {
+                        return Err(format!("{}: unknown method {:?}.", prefix, name));
+                    }
+                }
+            }
+        }
+
+        let file = match section.get("file").map(|file| file.as_str()) {
+            Some(Some("exists")) => Some(FileCondition::Exists),
+            Some(Some("missing")) => Some(FileCondition::Missing),
+            None => None,
+            Some(_) => {
+                return Err(format!("{}: file must be \"exists\" or \"missing\".", prefix));
+            }
+        };
+
+        let flag = match section.get("flag").map(|flag| flag.as_str()) {
+            Some(Some("last")) => RewriteFlag::Last,
+            Some(Some("break")) => RewriteFlag::Break,
+            Some(Some("continue")) | None => RewriteFlag::Continue,
+            Some(_) => {
+                return Err(format!("{}: flag must be \"continue\", \"last\" or \"break\".", prefix));
+            }
+        };
+
+        return Ok(RewriteRule {
+            pattern: pattern,
+            replacement: replacement,
+            query: parse_regex(section, "query", &prefix)?,
+            host: parse_regex(section, "host", &prefix)?,
+            methods: methods,
+            file: file,
+            flag: flag
+        });
+    }
+
+    // Parses the rewrite array of a server section, if there's one.
+    pub fn parse_all(section: &toml::Table, prefix: &str) -> Result<Vec<RewriteRule>, String> {
+        let entries = match section.get("rewrite") {
+            Some(entries) => match entries.as_slice() {
+                Some(entries) => entries,
+                None => {
+                    return Err(format!("{}.rewrite must be an array of tables.", prefix));
+                }
+            },
+            None => return Ok(Vec::new())
+        };
+
+        let mut rules = Vec::new();
+        for entry in entries {
+            rules.push(RewriteRule::from_toml(entry, prefix)?);
+        }
+        return Ok(rules);
+    }
+
+    // The rewritten path and query if the rule applies.
+    fn apply(&self, req: &HttpRequest, url_path: &str, query: Option<&str>, exists: &dyn Fn(&str) -> bool) -> Option<(String, Option<String>)> {
+        let captures = self.pattern.captures(url_path)?;
+
+        if !self.methods.is_empty() && !self.methods.contains(req.method()) {
+            return None;
+        }
+        if let Some(ref pattern) = self.query {
+            if !pattern.is_match(query.unwrap_or("")) {
+                return None;
+            }
+        }
+        if let Some(ref pattern) = self.host {
+            if !pattern.is_match(req.host().unwrap_or("")) {
+                return None;
+            }
+        }
+        match self.file {
+            Some(FileCondition::Exists) if !exists(url_path) => return None,
+            Some(FileCondition::Missing) if exists(url_path) => return None,
+            _ => {},
+        }
+
+        let mut expanded = String::new();
+        captures.expand(&self.replacement, &mut expanded);
+        match expanded.find('?') {
+            Some(pos) => Some((expanded[..pos].to_string(), Some(encode_query(&expanded[pos + 1..])))),
+            None => Some((expanded, query.map(|query| query.to_string()))),
+        }
+    }
+}
+
+// Rewrites the path and query of a request. Paths are matched decoded and
+// normalized, and encoded again once rewritten; paths that can't be are
+// left to the handler to refuse. Fails when the rules keep restarting, or
+// rewrite the path into one that can't be normalized.
+pub fn rewrite(rules: &[RewriteRule], req: &mut HttpRequest, root: &Path, symlinks: SymlinkPolicy, log: bool) -> Result<(), String> {
+    let mut url_path = match path::normalize_path(&req.path) {
+        Ok(url_path) if !rules.is_empty() => url_path,
+        _ => return Ok(()),
+    };
+    let mut query = req.query.clone();
+    let exists = |url_path: &str| path::resolve(root, &encode_path(url_path), symlinks).is_ok();
+
+    let mut changed = false;
+    let mut passes = 0;
+    'passes: loop {
+        passes += 1;
+        if passes > MAX_PASSES {
+            return Err(format!("rewrite loop on {}", req.path));
+        }
+
+        for (index, rule) in rules.iter().enumerate() {
+            let (new_path, new_query) = match rule.apply(req, &url_path, query.as_deref(), &exists) {
+                Some(rewritten) => rewritten,
+                None => continue,
+            };
+
+            if log {
+                info!("Rewrite rule #{} {:?} turned {} into {}", index + 1, rule.pattern.as_str(), url_path, new_path);
+            }
+            url_path = match path::normalize_path(&encode_path(&new_path)) {
+                Ok(url_path) => url_path,
+                Err(e) => return Err(format!("rule #{} rewrote {} into invalid path {:?}: {:?}", index + 1, req.path, new_path, e)),
+            };
+            query = new_query;
+            changed = true;
+
+            match rule.flag {
+                RewriteFlag::Continue => {},
+                RewriteFlag::Last => continue 'passes,
+                RewriteFlag::Break => break 'passes,
+            }
+        }
+        break;
+    }
+
+    if changed {
+        req.path = encode_path(&url_path);
+        req.query = query.filter(|query| !query.is_empty());
+    }
+    Ok(())
+}
+
+// Escapes what can't appear in a query string, such as the spaces of a
+// decoded capture. Existing escapes are left alone.
+pub fn encode_query(query: &str) -> String {
+    let mut encoded = String::with_capacity(query.len());
+    for &b in query.as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' |
+            b'-' | b'.' | b'_' | b'~' | b'!' | b'$' | b'&' | b'\'' | b'(' | b')' |
+            b'*' | b'+' | b',' | b';' | b'=' | b':' | b'@' | b'/' | b'?' | b'%' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+// Percent-encodes each segment of a decoded path.
+pub fn encode_path(url_path: &str) -> String {
+    url_path.split('/').map(percent_encode).collect::<Vec<_>>().join("/")
+}
+
+#[test]
+fn rewrites_with_captures_and_flags() {
+    let rule = |pattern: &str, replacement: &str, flag: &str| {
+        let mut section = toml::Table::new();
+        section.insert("pattern".to_string(), toml::Value::String(pattern.to_string()));
+        section.insert("replacement".to_string(), toml::Value::String(replacement.to_string()));
+        section.insert("flag".to_string(), toml::Value::String(flag.to_string()));
+        RewriteRule::from_toml(&toml::Value::Table(section), "http").ok().unwrap()
+    };
+    let run = |rules: &[RewriteRule], target: &str| {
+        let mut req = HttpRequest::from_string(format!("GET {} HTTP/1.1\r\nHost: a.test", target)).ok().unwrap();
+        rewrite(rules, &mut req, Path::new("/nonexistent"), SymlinkPolicy::WithinRoot, false)
+            .map(|()| (req.path.clone(), req.query.clone()))
+    };
+
+    let rules = vec![
+        rule("^/old/(.*)$", "/new/$1", "continue"),
+        rule("^/new/(?P<page>[^/]+)$", "/index.php?page=${page}", "break"),
+        rule("^/index.php$", "/never", "continue"),
+    ];
+    assert!(run(&rules, "/old/a%20b?x=1") == Ok(("/index.php".to_string(), Some("page=a%20b".to_string()))));
+    assert!(run(&rules, "/other?x=1") == Ok(("/other".to_string(), Some("x=1".to_string()))));
+    assert!(run(&rules, "/old/a/b") == Ok(("/new/a/b".to_string(), None)));
+
+    let looping = vec![rule("^/a$", "/b", "last"), rule("^/b$", "/a", "last")];
+    assert!(run(&looping, "/a").is_err());
+}
@@ -0,0 +1,350 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+extern crate toml;
+
+use config::location;
+use config::location::Location;
+use config::redirect::Redirect;
+use config::rewrite::RewriteRule;
+use config::string_list;
+use files::etag::EtagMode;
+use files::path::SymlinkPolicy;
+use http::accesslog::AccessLog;
+use http::compress::{Coding, CompressionSettings};
+use http::mime::MimeTypes;
+
+// What a server does with the requests it gets: the [http] section, or a
+// [[vhost]] overriding some of it.
+#[derive(Clone)]
+pub struct SiteConfig {
+    root_path: PathBuf,
+    index: String,
+    mime_types: MimeTypes,
+    symlinks: SymlinkPolicy,
+    autoindex: bool,
+    autoindex_hidden: bool,
+    etag: EtagMode,
+    compression: CompressionSettings,
+    precompressed: bool,
+    access_log: Option<AccessLog>,
+    redirects: Vec<Redirect>,
+    rewrites: Vec<RewriteRule>,
+    rewrite_log: bool,
+    locations: Vec<Location>
+}
+
+// The compression table of a site.
+fn parse_compression(section: &toml::Value, prefix: &str) -> Result<CompressionSettings, String> {
+    let mut compression = CompressionSettings::new();
+    let section = match section.as_table() {
+        Some(section) => section,
+        None => {
+            return Err(format!("{}.compression must be a table.", prefix));
+        }
+    };
+
+    // Compression is on as soon as the section is there.
+    match section.get("enabled") {
+        Some(enabled) => match enabled.as_bool() {
+            Some(enabled) => compression.set_enabled(enabled),
+            None => {
+                return Err(format!("{}.compression.enabled must be true or false.", prefix));
+            }
+        },
+        None => compression.set_enabled(true)
+    }
+
+    if let Some(codings) = section.get("codings") {
+        let mut parsed = Vec::new();
+        for coding in string_list(codings, &format!("{}.compression.codings", prefix))? {
+            match Coding::from_str(&coding) {
+                Ok(coding) => parsed.push(coding),
+                Err(_) => {
+                    return Err(format!("Unknown coding {:?}, expected \"br\", \"zstd\", \"gzip\" or \"deflate\".", coding));
+                }
+            }
+        }
+        compression.set_codings(parsed);
+    }
+
+    if let Some(types) = section.get("types") {
+        compression.set_types(string_list(types, &format!("{}.compression.types", prefix))?);
+    }
+
+    if let Some(min_size) = section.get("min_size") {
+        match min_size.as_integer() {
+            Some(min_size) if min_size >= 0 => compression.set_min_size(min_size as u64),
+            _ => {
+                return Err(format!("{}.compression.min_size must be a positive integer.", prefix));
+            }
+        }
+    }
+
+    if let Some(level) = section.get("level") {
+        match level.as_integer() {
+            Some(level) if (1..=9).contains(&level) => compression.set_level(level as u32),
+            _ => {
+                return Err(format!("{}.compression.level must be between 1 and 9.", prefix));
+            }
+        }
+    }
+
+    return Ok(compression);
+}
+
+fn parse_bool(section: &toml::Table, key: &str, prefix: &str, default: bool) -> Result<bool, String> {
+    match section.get(key) {
+        Some(value) => match value.as_bool() {
+            Some(value) => Ok(value),
+            None => Err(format!("{}.{} must be true or false.", prefix, key))
+        },
+        None => Ok(default)
+    }
+}
+
+impl SiteConfig {
+    // Settings missing from the section are taken from the parent, or get
+    // their default value when there's none. Only the root is mandatory.
+    // Redirects, rewrite rules and locations are never inherited, see
+    // set_redirects, set_rewrites and set_locations.
+    pub fn from_toml(section: &toml::Table, parent: Option<&SiteConfig>, prefix: &str) -> Result<SiteConfig, String> {
+        // Paths are checked against the canonical root, so it must exist.
+        let root_path = match section.get("root_path") {
+            Some(root_path) => match root_path.as_str() {
+                Some(root_path) => match PathBuf::from(root_path).canonicalize() {
+                    Ok(path) => path,
+                    Err(e) => {
+                        return Err(format!("Couldn't resolve root path {:?}: {}", root_path, e));
+                    }
+                },
+                None => {
+                    return Err(format!("{}.root_path must be a path.", prefix));
+                }
+            },
+            None => match parent {
+                Some(parent) => parent.root_path.clone(),
+                None => {
+                    return Err(format!("Root path must be set in config file."));
+                }
+            }
+        };
+
+        let index = match section.get("index") {
+            Some(index) => match index.as_str() {
+                Some(index) => String::from(index),
+                None => {
+                    return Err(format!("{}.index must be a file name.", prefix));
+                }
+            },
+            None => parent.map_or(String::from("index.html"), |parent| parent.index.clone())
+        };
+
+        // Built-in types, then a mime.types file, then per-extension overrides.
+        let mut mime_types = parent.map_or(MimeTypes::new(), |parent| parent.mime_types.clone());
+        if let Some(filename) = section.get("mime_types_file") {
+            match filename.as_str() {
+                Some(filename) => mime_types.load_file(filename)?,
+                None => {
+                    return Err(format!("{}.mime_types_file must be a path.", prefix));
+                }
+            }
+        }
+
+        if let Some(types) = section.get("mime_types") {
+            let types = match types.as_table() {
+                Some(types) => types,
+                None => {
+                    return Err(format!("{}.mime_types must be a table of extension = \"type\".", prefix));
+                }
+            };
+            for (extension, media_type) in types.iter() {
+                match media_type.as_str() {
+                    Some(media_type) => mime_types.insert(extension, media_type),
+                    None => {
+                        return Err(format!("MIME type for extension {} must be a string.", extension));
+                    }
+                }
+            }
+        }
+
+        if let Some(default_type) = section.get("default_type") {
+            match default_type.as_str() {
+                Some(default_type) => mime_types.set_default_type(default_type),
+                None => {
+                    return Err(format!("{}.default_type must be a string.", prefix));
+                }
+            }
+        }
+
+        if let Some(charset) = section.get("charset") {
+            match charset.as_str() {
+                Some(charset) => mime_types.set_charset(Some(charset.to_string())),
+                None => {
+                    return Err(format!("{}.charset must be a string.", prefix));
+                }
+            }
+        }
+
+        let symlinks = match section.get("symlinks") {
+            Some(symlinks) => match symlinks.as_str().map(SymlinkPolicy::from_str) {
+                Some(Ok(policy)) => policy,
+                _ => {
+                    return Err(format!("{}.symlinks must be one of \"follow\", \"within_root\" or \"deny\".", prefix));
+                }
+            },
+            None => parent.map_or(SymlinkPolicy::WithinRoot, |parent| parent.symlinks)
+        };
+
+        // Directory listings are off unless asked for.
+        let autoindex = parse_bool(section, "autoindex", prefix, parent.is_some_and(|parent| parent.autoindex))?;
+        let autoindex_hidden = parse_bool(section, "autoindex_hidden", prefix, parent.is_some_and(|parent| parent.autoindex_hidden))?;
+
+        let etag = match section.get("etag") {
+            Some(etag) => match etag.as_str().map(EtagMode::from_str) {
+                Some(Ok(mode)) => mode,
+                _ => {
+                    return Err(format!("{}.etag must be one of \"metadata\", \"content\" or \"off\".", prefix));
+                }
+            },
+            None => parent.map_or(EtagMode::Metadata, |parent| parent.etag)
+        };
+
+        let compression = match section.get("compression") {
+            Some(compression) => parse_compression(compression, prefix)?,
+            None => parent.map_or(CompressionSettings::new(), |parent| parent.compression.clone())
+        };
+
+        // Serve file.br, file.zst or file.gz in place of file when present.
+        let precompressed = parse_bool(section, "precompressed", prefix, parent.is_some_and(|parent| parent.precompressed))?;
+
+        // Requests are always logged to syslog, and to this file too if set.
+        let access_log = match section.get("access_log") {
+            Some(access_log) => match access_log.as_str() {
+                Some(access_log) => Some(AccessLog::open(access_log)?),
+                None => {
+                    return Err(format!("{}.access_log must be a path.", prefix));
+                }
+            },
+            None => parent.and_then(|parent| parent.access_log.clone())
+        };
+
+        // Log which rewrite rules fired.
+        let rewrite_log = parse_bool(section, "rewrite_log", prefix, parent.is_some_and(|parent| parent.rewrite_log))?;
+
+        return Ok(SiteConfig {
+            root_path: root_path,
+            index: index,
+            mime_types: mime_types,
+            symlinks: symlinks,
+            autoindex: autoindex,
+            autoindex_hidden: autoindex_hidden,
+            etag: etag,
+            compression: compression,
+            precompressed: precompressed,
+            access_log: access_log,
+            redirects: Vec::new(),
+            rewrites: Vec::new(),
+            rewrite_log: rewrite_log,
+            locations: Vec::new()
+        });
+    }
+
+    // Serves the current directory with the default settings.
+    pub fn new_defaults() -> SiteConfig {
+        return SiteConfig {
+            root_path: env::current_dir().unwrap().canonicalize().unwrap(),
+            index: String::from("index.html"),
+            mime_types: MimeTypes::new(),
+            symlinks: SymlinkPolicy::WithinRoot,
+            autoindex: false,
+            autoindex_hidden: false,
+            etag: EtagMode::Metadata,
+            compression: CompressionSettings::new(),
+            precompressed: false,
+            access_log: None,
+            redirects: Vec::new(),
+            rewrites: Vec::new(),
+            rewrite_log: false,
+            locations: Vec::new()
+        };
+    }
+
+    // Canonical path of the document root.
+    pub fn get_root_path(&self) -> &Path {
+        return &self.root_path;
+    }
+
+    pub fn get_index(&self) -> &str {
+        return &self.index;
+    }
+
+    pub fn get_mime_types(&self) -> &MimeTypes {
+        return &self.mime_types;
+    }
+
+    pub fn get_symlinks(&self) -> SymlinkPolicy {
+        return self.symlinks;
+    }
+
+    pub fn get_autoindex(&self) -> bool {
+        return self.autoindex;
+    }
+
+    // Whether listings include dotfiles.
+    pub fn get_autoindex_hidden(&self) -> bool {
+        return self.autoindex_hidden;
+    }
+
+    pub fn get_etag(&self) -> EtagMode {
+        return self.etag;
+    }
+
+    pub fn get_compression(&self) -> &CompressionSettings {
+        return &self.compression;
+    }
+
+    pub fn get_precompressed(&self) -> bool {
+        return self.precompressed;
+    }
+
+    pub fn get_access_log(&self) -> Option<&AccessLog> {
+        return self.access_log.as_ref();
+    }
+
+    // Checked before rewrites, the first one that matches wins.
+    pub fn set_redirects(&mut self, redirects: Vec<Redirect>) {
+        self.redirects = redirects;
+    }
+
+    pub fn get_redirects(&self) -> &[Redirect] {
+        return &self.redirects;
+    }
+
+    // Applied in order to request paths, before locations are looked up.
+    pub fn set_rewrites(&mut self, rewrites: Vec<RewriteRule>) {
+        self.rewrites = rewrites;
+    }
+
+    pub fn get_rewrites(&self) -> &[RewriteRule] {
+        return &self.rewrites;
+    }
+
+    pub fn get_rewrite_log(&self) -> bool {
+        return self.rewrite_log;
+    }
+
+    pub fn set_locations(&mut self, locations: Vec<Location>) {
+        self.locations = locations;
+    }
+
+    pub fn get_locations(&self) -> &[Location] {
+        return &self.locations;
+    }
+
+    // Location for a decoded and normalized path.
+    pub fn find_location(&self, path: &str) -> Option<&Location> {
+        return location::find(&self.locations, path);
+    }
+}
@@ -0,0 +1,255 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+extern crate toml;
+
+use config::string_list;
+use tls::identity::ClientAuth;
+
+const DEFAULT_TLS_PORT: &str = "8443";
+const DEFAULT_RELOAD_INTERVAL: u64 = 60;
+
+// A certificate chain and its key, picked for the names it's valid for.
+#[derive(Clone)]
+pub struct CertificateConfig {
+    certificate: PathBuf,
+    key: PathBuf,
+    names: Vec<String>
+}
+
+// Settings of the HTTPS listener, from the [tls] section.
+#[derive(Clone)]
+pub struct TlsConfig {
+    port: String,
+    default_certificate: CertificateConfig,
+    certificates: Vec<CertificateConfig>,
+    reload_interval: u64,
+    alpn: Vec<String>,
+    versions: Vec<String>,
+    ciphers: Vec<String>,
+    client_auth: ClientAuth,
+    client_ca: Option<PathBuf>,
+    client_subjects: Vec<String>
+}
+
+// PEM files: the certificate chain, leaf first, and its private key. Names
+// are taken from the certificate unless given.
+fn parse_certificate(section: &toml::Table, prefix: &str) -> Result<CertificateConfig, String> {
+    let certificate = match section.get("certificate").and_then(|certificate| certificate.as_str()) {
+        Some(certificate) => PathBuf::from(certificate),
+        None => {
+            return Err(format!("{}.certificate must be the path of a PEM certificate chain.", prefix));
+        }
+    };
+
+    let key = match section.get("key").and_then(|key| key.as_str()) {
+        Some(key) => PathBuf::from(key),
+        None => {
+            return Err(format!("{}.key must be the path of a PEM private key.", prefix));
+        }
+    };
+
+    let names = match section.get("names") {
+        Some(names) => string_list(names, &format!("{}.names", prefix))?,
+        None => Vec::new()
+    };
+
+    return Ok(CertificateConfig {
+        certificate: certificate,
+        key: key,
+        names: names
+    });
+}
+
+impl CertificateConfig {
+    pub fn get_certificate(&self) -> &Path {
+        return &self.certificate;
+    }
+
+    pub fn get_key(&self) -> &Path {
+        return &self.key;
+    }
+
+    // Host names, "*.example.org" style wildcards included. Empty when they
+    // should be read from the certificate.
+    pub fn get_names(&self) -> &[String] {
+        return &self.names;
+    }
+}
+
+impl TlsConfig {
+    pub fn from_toml(section: &toml::Value) -> Result<TlsConfig, String> {
+        let section = match section.as_table() {
+            Some(section) => section,
+            None => {
+                return Err(format!("tls must be a table."));
+            }
+        };
+
+        let port = match section.get("port") {
+            Some(port) => match port.as_str() {
+                Some(port) => port,
+                None => {
+                    return Err(format!("tls.port must be a string."));
+                }
+            },
+            None => DEFAULT_TLS_PORT
+        };
+
+        // Sent to clients that don't use SNI or ask for a name no other
+        // certificate covers.
+        let default_certificate = parse_certificate(section, "tls")?;
+
+        // Extra [[tls.certificates]], picked by SNI.
+        let mut certificates = Vec::new();
+        if let Some(entries) = section.get("certificates") {
+            let entries = match entries.as_slice() {
+                Some(entries) => entries,
+                None => {
+                    return Err(format!("tls.certificates must be an array of tables."));
+                }
+            };
+            for entry in entries {
+                match entry.as_table() {
+                    Some(entry) => certificates.push(parse_certificate(entry, "tls.certificates")?),
+                    None => {
+                        return Err(format!("tls.certificates must be an array of tables."));
+                    }
+                }
+            }
+        }
+
+        // Seconds between checks for renewed certificate files, 0 to never
+        // reload them.
+        let reload_interval = match section.get("reload_interval") {
+            Some(interval) => match interval.as_integer() {
+                Some(interval) if interval >= 0 => interval as u64,
+                _ => {
+                    return Err(format!("tls.reload_interval must be a positive integer."));
+                }
+            },
+            None => DEFAULT_RELOAD_INTERVAL
+        };
+
+        // Only HTTP/1.x is spoken, so h2 can't be offered.
+        let alpn = match section.get("alpn") {
+            Some(alpn) => string_list(alpn, "tls.alpn")?,
+            None => vec![String::from("http/1.1")]
+        };
+        for protocol in &alpn {
+            if protocol != "http/1.1" && protocol != "http/1.0" {
+                return Err(format!("Unsupported ALPN protocol {:?}, expected \"http/1.1\" or \"http/1.0\".", protocol));
+            }
+        }
+
+        let versions = match section.get("versions") {
+            Some(versions) => string_list(versions, "tls.versions")?,
+            None => vec![String::from("1.2"), String::from("1.3")]
+        };
+        if versions.is_empty() {
+            return Err(format!("tls.versions can't be empty."));
+        }
+        for version in &versions {
+            if version != "1.2" && version != "1.3" {
+                return Err(format!("Unsupported TLS version {:?}, expected \"1.2\" or \"1.3\".", version));
+            }
+        }
+
+        // Cipher suites by their IANA name. All safe ones are allowed when
+        // this is left out.
+        let ciphers = match section.get("ciphers") {
+            Some(ciphers) => string_list(ciphers, "tls.ciphers")?,
+            None => Vec::new()
+        };
+
+        // Client certificates are checked against the CAs of client_ca.
+        let client_auth = match section.get("client_auth") {
+            Some(client_auth) => match client_auth.as_str().map(ClientAuth::from_str) {
+                Some(Ok(client_auth)) => client_auth,
+                _ => {
+                    return Err(format!("tls.client_auth must be one of \"off\", \"optional\" or \"required\"."));
+                }
+            },
+            None => ClientAuth::Off
+        };
+
+        let client_ca = match section.get("client_ca") {
+            Some(client_ca) => match client_ca.as_str() {
+                Some(client_ca) => Some(PathBuf::from(client_ca)),
+                None => {
+                    return Err(format!("tls.client_ca must be the path of a PEM CA bundle."));
+                }
+            },
+            None => None
+        };
+        if client_auth != ClientAuth::Off && client_ca.is_none() {
+            return Err(format!("tls.client_ca must be set when tls.client_auth is on."));
+        }
+
+        // Subjects or alternative names let through; any verified
+        // certificate is when this is left out.
+        let client_subjects = match section.get("client_subjects") {
+            Some(subjects) => string_list(subjects, "tls.client_subjects")?,
+            None => Vec::new()
+        };
+        if !client_subjects.is_empty() && client_auth == ClientAuth::Off {
+            return Err(format!("tls.client_subjects needs tls.client_auth to be on."));
+        }
+
+        return Ok(TlsConfig {
+            port: String::from(port),
+            default_certificate: default_certificate,
+            certificates: certificates,
+            reload_interval: reload_interval,
+            alpn: alpn,
+            versions: versions,
+            ciphers: ciphers,
+            client_auth: client_auth,
+            client_ca: client_ca,
+            client_subjects: client_subjects
+        });
+    }
+
+    pub fn get_port(&self) -> &str {
+        return &self.port;
+    }
+
+    pub fn get_default_certificate(&self) -> &CertificateConfig {
+        return &self.default_certificate;
+    }
+
+    pub fn get_certificates(&self) -> &[CertificateConfig] {
+        return &self.certificates;
+    }
+
+    pub fn get_reload_interval(&self) -> u64 {
+        return self.reload_interval;
+    }
+
+    pub fn get_alpn(&self) -> &[String] {
+        return &self.alpn;
+    }
+
+    // "1.2" and/or "1.3".
+    pub fn get_versions(&self) -> &[String] {
+        return &self.versions;
+    }
+
+    // Empty when the defaults should be used.
+    pub fn get_ciphers(&self) -> &[String] {
+        return &self.ciphers;
+    }
+
+    pub fn get_client_auth(&self) -> ClientAuth {
+        return self.client_auth;
+    }
+
+    pub fn get_client_ca(&self) -> Option<&Path> {
+        return self.client_ca.as_deref();
+    }
+
+    // Empty when any verified certificate is accepted.
+    pub fn get_client_subjects(&self) -> &[String] {
+        return &self.client_subjects;
+    }
+}
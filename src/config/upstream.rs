@@ -0,0 +1,246 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+extern crate toml;
+
+use proxy::balancer::{FailurePolicy, HashKey, HealthCheck, Strategy, UpstreamGroup};
+use proxy::upstream::{PoolSettings, Upstream, UpstreamAddress};
+
+// Seconds to wait for an upstream server to accept a connection, then for
+// each read of its response.
+pub const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+pub const DEFAULT_READ_TIMEOUT: u64 = 60;
+
+// Idle connections kept open to each server, and the seconds they can wait
+// for a request and be used at all.
+const DEFAULT_MAX_IDLE: u64 = 16;
+const DEFAULT_IDLE_TIMEOUT: u64 = 30;
+const DEFAULT_MAX_LIFETIME: u64 = 600;
+
+const DEFAULT_MAX_FAILS: u64 = 1;
+const DEFAULT_FAIL_TIMEOUT: u64 = 10;
+const DEFAULT_HEALTH_INTERVAL: u64 = 10;
+const DEFAULT_HEALTH_TIMEOUT: u64 = 5;
+
+// Hash rings get points for every unit of weight, so it can't be just any
+// number.
+const MAX_WEIGHT: i64 = 1000;
+
+pub fn parse_timeout(section: &toml::Table, key: &str, prefix: &str, default: u64) -> Result<Duration, String> {
+    match section.get(key) {
+        Some(timeout) => match timeout.as_integer() {
+            Some(timeout) if timeout > 0 => Ok(Duration::from_secs(timeout as u64)),
+            _ => Err(format!("{}: {} must be a strictly positive number of seconds.", prefix, key))
+        },
+        None => Ok(Duration::from_secs(default))
+    }
+}
+
+// Keep-alive connections to the servers, from keys named after key_prefix,
+// e.g. "proxy_" for "proxy_max_idle". A max_idle of 0 opens a connection
+// per request.
+pub fn parse_pool(section: &toml::Table, key_prefix: &str, prefix: &str) -> Result<PoolSettings, String> {
+    let key = format!("{}max_idle", key_prefix);
+    let max_idle = match section.get(&key) {
+        Some(max_idle) => match max_idle.as_integer() {
+            Some(max_idle) if max_idle >= 0 => max_idle as usize,
+            _ => {
+                return Err(format!("{}: {} must be a positive integer.", prefix, key));
+            }
+        },
+        None => DEFAULT_MAX_IDLE as usize
+    };
+
+    return Ok(PoolSettings {
+        max_idle: max_idle,
+        idle_timeout: parse_timeout(section, &format!("{}idle_timeout", key_prefix), prefix, DEFAULT_IDLE_TIMEOUT)?,
+        max_lifetime: parse_timeout(section, &format!("{}max_lifetime", key_prefix), prefix, DEFAULT_MAX_LIFETIME)?
+    });
+}
+
+fn parse_health_check(section: &toml::Value, prefix: &str) -> Result<HealthCheck, String> {
+    let section = match section.as_table() {
+        Some(section) => section,
+        None => {
+            return Err(format!("{}: health_check must be a table.", prefix));
+        }
+    };
+    let prefix = format!("{}: health_check", prefix);
+
+    let path = match section.get("path").and_then(|path| path.as_str()) {
+        Some(path) if path.starts_with('/') => path.to_string(),
+        _ => {
+            return Err(format!("{}.path must be a path starting with /.", prefix));
+        }
+    };
+
+    return Ok(HealthCheck {
+        path: path,
+        interval: parse_timeout(section, "interval", &prefix, DEFAULT_HEALTH_INTERVAL)?,
+        timeout: parse_timeout(section, "timeout", &prefix, DEFAULT_HEALTH_TIMEOUT)?
+    });
+}
+
+fn parse_server(section: &toml::Value, connect_timeout: Duration, read_timeout: Duration, pool: PoolSettings, prefix: &str) -> Result<(Upstream, u32), String> {
+    let section = match section.as_table() {
+        Some(section) => section,
+        None => {
+            return Err(format!("{}: server must be an array of tables.", prefix));
+        }
+    };
+
+    let address = match section.get("address").and_then(|address| address.as_str()).map(UpstreamAddress::from_str) {
+        Some(Ok(address)) => address,
+        _ => {
+            return Err(format!("{}: server.address must be \"http://host:port\" or \"unix:/path/to/socket\".", prefix));
+        }
+    };
+
+    let weight = match section.get("weight") {
+        Some(weight) => match weight.as_integer() {
+            Some(weight) if weight > 0 && weight <= MAX_WEIGHT => weight as u32,
+            _ => {
+                return Err(format!("{}: weight of {} must be an integer from 1 to {}.", prefix, address, MAX_WEIGHT));
+            }
+        },
+        None => 1
+    };
+
+    return Ok((Upstream::new(address, connect_timeout, read_timeout).with_pool(pool), weight));
+}
+
+// An [[upstream]] section: servers a proxy location can spread its requests
+// over, by naming the group.
+fn parse_group(section: &toml::Value, names: &[String]) -> Result<UpstreamGroup, String> {
+    let section = match section.as_table() {
+        Some(section) => section,
+        None => {
+            return Err(format!("upstream must be an array of tables."));
+        }
+    };
+
+    let name = match section.get("name").and_then(|name| name.as_str()) {
+        Some(name) if !name.is_empty() => name,
+        _ => {
+            return Err(format!("upstream.name must be set."));
+        }
+    };
+    if names.iter().any(|other| other == name) {
+        return Err(format!("There are two upstreams named {:?}.", name));
+    }
+    let prefix = format!("upstream {:?}", name);
+
+    // Sticky requests are hashed on the client's address, a header or a
+    // cookie, e.g. "ip", "header:X-User-Id" or "cookie:session".
+    let hash_key = match section.get("hash_key") {
+        Some(key) => match key.as_str().map(HashKey::from_str) {
+            Some(Ok(key)) => Some(key),
+            _ => {
+                return Err(format!("{}: hash_key must be \"ip\", \"header:<name>\" or \"cookie:<name>\".", prefix));
+            }
+        },
+        None => None
+    };
+    let strategy = match (section.get("strategy").map(|strategy| strategy.as_str()), hash_key) {
+        (Some(Some("hash")), Some(key)) => Strategy::Hash(key),
+        (Some(Some("hash")), None) => {
+            return Err(format!("{}: strategy = \"hash\" needs a hash_key.", prefix));
+        },
+        (_, Some(_)) => {
+            return Err(format!("{}: hash_key goes with strategy = \"hash\".", prefix));
+        },
+        (Some(Some("round_robin")), None) | (None, None) => Strategy::RoundRobin,
+        (Some(Some("weighted")), None) => Strategy::Weighted,
+        (Some(Some("least_conn")), None) => Strategy::LeastConnections,
+        (Some(_), None) => {
+            return Err(format!("{}: strategy must be \"round_robin\", \"weighted\", \"least_conn\" or \"hash\".", prefix));
+        }
+    };
+
+    let connect_timeout = parse_timeout(section, "connect_timeout", &prefix, DEFAULT_CONNECT_TIMEOUT)?;
+    let read_timeout = parse_timeout(section, "read_timeout", &prefix, DEFAULT_READ_TIMEOUT)?;
+    let pool = parse_pool(section, "", &prefix)?;
+
+    // Servers failing max_fails requests in a row are left out for
+    // fail_timeout seconds; 0 keeps them in whatever happens.
+    let max_fails = match section.get("max_fails") {
+        Some(max_fails) => match max_fails.as_integer() {
+            Some(max_fails) if max_fails >= 0 && max_fails <= u32::MAX as i64 => max_fails as u32,
+            _ => {
+                return Err(format!("{}: max_fails must be a positive integer.", prefix));
+            }
+        },
+        None => DEFAULT_MAX_FAILS as u32
+    };
+    let failure_policy = FailurePolicy {
+        max_fails: max_fails,
+        fail_timeout: parse_timeout(section, "fail_timeout", &prefix, DEFAULT_FAIL_TIMEOUT)?
+    };
+
+    let health_check = match section.get("health_check") {
+        Some(check) => Some(parse_health_check(check, &prefix)?),
+        None => None
+    };
+
+    let entries = match section.get("server").and_then(|servers| servers.as_slice()) {
+        Some(entries) if !entries.is_empty() => entries,
+        _ => {
+            return Err(format!("{}: needs at least one [[upstream.server]].", prefix));
+        }
+    };
+    let mut servers = Vec::new();
+    for entry in entries {
+        servers.push(parse_server(entry, connect_timeout, read_timeout, pool, &prefix)?);
+    }
+
+    return Ok(UpstreamGroup::new(name, strategy, servers, failure_policy, health_check));
+}
+
+// Parses the [[upstream]] sections of the configuration, if there are any.
+pub fn parse_all(conf: &toml::Table) -> Result<Vec<Arc<UpstreamGroup>>, String> {
+    let entries = match conf.get("upstream") {
+        Some(entries) => match entries.as_slice() {
+            Some(entries) => entries,
+            None => {
+                return Err(format!("upstream must be an array of tables."));
+            }
+        },
+        None => return Ok(Vec::new())
+    };
+
+    let mut groups: Vec<Arc<UpstreamGroup>> = Vec::new();
+    let mut names = Vec::new();
+    for entry in entries {
+        let group = parse_group(entry, &names)?;
+        names.push(group.name().to_string());
+        groups.push(Arc::new(group));
+    }
+    return Ok(groups);
+}
+
+pub fn find<'a>(groups: &'a [Arc<UpstreamGroup>], name: &str) -> Option<&'a Arc<UpstreamGroup>> {
+    groups.iter().find(|group| group.name() == name)
+}
+
+#[test]
+fn parses_upstream_groups() {
+    let parse = |text: &str| toml::Parser::new(text).parse().ok_or(String::new()).and_then(|conf| parse_all(&conf));
+
+    let groups = parse("[[upstream]]\nname = \"app\"\nstrategy = \"hash\"\nhash_key = \"cookie:session\"\n\
+                        [upstream.health_check]\npath = \"/health\"\n\
+                        [[upstream.server]]\naddress = \"http://10.0.0.1:8080\"\nweight = 2\n\
+                        [[upstream.server]]\naddress = \"unix:/run/app.sock\"\n").ok().unwrap();
+    assert!(groups.len() == 1 && groups[0].members().len() == 2);
+    assert!(groups[0].health_check().map(|check| check.interval) == Some(Duration::from_secs(DEFAULT_HEALTH_INTERVAL)));
+    assert!(find(&groups, "app").is_some() && find(&groups, "other").is_none());
+
+    let server = "[[upstream.server]]\naddress = \"http://10.0.0.1\"\n";
+    assert!(parse(&format!("[[upstream]]\nname = \"app\"\nstrategy = \"hash\"\n{}", server)).is_err());
+    assert!(parse(&format!("[[upstream]]\nname = \"app\"\nhash_key = \"ip\"\n{}", server)).is_err());
+    assert!(parse(&format!("[[upstream]]\nname = \"app\"\n{}[[upstream]]\nname = \"app\"\n{}", server, server)).is_err());
+    assert!(parse(&format!("[[upstream]]\nname = \"app\"\nmax_idle = -1\n{}", server)).is_err());
+    assert!(parse("[[upstream]]\nname = \"app\"\n").is_err());
+    assert!(parse(&format!("[[upstream]]\nname = \"app\"\n{}weight = 1000\n", server)).is_ok());
+    assert!(parse(&format!("[[upstream]]\nname = \"app\"\n{}weight = 1001\n", server)).is_err());
+}
@@ -0,0 +1,209 @@
+extern crate toml;
+
+#[cfg(test)]
+use std::ptr;
+use std::sync::Arc;
+
+use regex::Regex;
+
+use config::location::Location;
+use config::redirect::Redirect;
+use config::rewrite::RewriteRule;
+use config::siteconfig::SiteConfig;
+use config::string_list;
+use proxy::balancer::UpstreamGroup;
+
+// A name a [[vhost]] answers to.
+pub enum ServerName {
+    // "example.org"
+    Exact(String),
+    // "*.example.org", matching any number of labels before the suffix.
+    Suffix(String),
+    // "www.example.*", matching any number of labels after the prefix.
+    Prefix(String),
+    // "~^(www\.)?example\.(org|net)$"
+    Pattern(Regex),
+}
+
+impl ServerName {
+    pub fn parse(name: &str) -> Result<ServerName, String> {
+        if let Some(pattern) = name.strip_prefix('~') {
+            return match Regex::new(pattern) {
+                Ok(regex) => Ok(ServerName::Pattern(regex)),
+                Err(e) => Err(format!("Invalid server name pattern {:?}: {}", pattern, e)),
+            };
+        }
+
+        let name = name.to_lowercase();
+        if let Some(suffix) = name.strip_prefix("*.") {
+            Ok(ServerName::Suffix(format!(".{}", suffix)))
+        } else if let Some(prefix) = name.strip_suffix(".*") {
+            Ok(ServerName::Prefix(format!("{}.", prefix)))
+        } else if name.contains('*') {
+            Err(format!("Invalid server name {:?}, wildcards go at the start or the end.", name))
+        } else {
+            Ok(ServerName::Exact(name))
+        }
+    }
+}
+
+pub struct VirtualHost {
+    names: Vec<ServerName>,
+    default: bool,
+    site: SiteConfig
+}
+
+impl VirtualHost {
+    // Settings the [[vhost]] doesn't give are those of [http].
+    pub fn from_toml(section: &toml::Value, parent: &SiteConfig, upstreams: &[Arc<UpstreamGroup>]) -> Result<VirtualHost, String> {
+        let section = match section.as_table() {
+            Some(section) => section,
+            None => {
+                return Err(format!("vhost must be an array of tables."));
+            }
+        };
+
+        let mut names = Vec::new();
+        if let Some(server_names) = section.get("server_names") {
+            for name in string_list(server_names, "vhost.server_names")? {
+                names.push(ServerName::parse(&name)?);
+            }
+        }
+
+        // The default server gets requests for names no vhost has.
+        let default = match section.get("default") {
+            Some(default) => match default.as_bool() {
+                Some(default) => default,
+                None => {
+                    return Err(format!("vhost.default must be true or false."));
+                }
+            },
+            None => false
+        };
+
+        if names.is_empty() && !default {
+            return Err(format!("vhost.server_names must list at least one name."));
+        }
+
+        // Locations, redirects and rewrites of [http] aren't inherited,
+        // only its settings.
+        let mut site = SiteConfig::from_toml(section, Some(parent), "vhost")?;
+        let locations = Location::parse_all(section, &site, upstreams, "vhost")?;
+        site.set_locations(locations);
+        site.set_redirects(Redirect::parse_all(section, "vhost")?);
+        site.set_rewrites(RewriteRule::parse_all(section, "vhost")?);
+
+        return Ok(VirtualHost {
+            names: names,
+            default: default,
+            site: site
+        });
+    }
+
+    pub fn is_default(&self) -> bool {
+        return self.default;
+    }
+
+    pub fn get_site(&self) -> &SiteConfig {
+        return &self.site;
+    }
+}
+
+// Lowercase host name of a Host header or authority, without the port or
+// a trailing dot. None when it isn't a valid host.
+pub fn host_name(host: &str) -> Option<String> {
+    let name = if host.starts_with('[') {
+        // IPv6 literal, e.g. "[::1]:8000".
+        let end = host.find(']')?;
+        match &host[end + 1..] {
+            "" => &host[..end + 1],
+            port if port.starts_with(':') => &host[..end + 1],
+            _ => return None,
+        }
+    } else {
+        match host.rfind(':') {
+            Some(pos) if host[pos + 1..].bytes().all(|b| b.is_ascii_digit()) => &host[..pos],
+            Some(_) => return None,
+            None => host,
+        }
+    };
+
+    let name = name.trim_end_matches('.');
+    let valid = name.bytes().all(|b| b.is_ascii_alphanumeric() || b"-._:[]".contains(&b));
+    if name.is_empty() || !valid {
+        return None;
+    }
+    Some(name.to_lowercase())
+}
+
+// The vhost serving a host name, the way nginx picks it: an exact name,
+// then the longest leading wildcard, the longest trailing wildcard and
+// finally the first pattern that matches, in configuration order.
+pub fn find<'a>(vhosts: &'a [VirtualHost], name: &str) -> Option<&'a VirtualHost> {
+    let names = || vhosts.iter().flat_map(|vhost| vhost.names.iter().map(move |server_name| (server_name, vhost)));
+
+    let exact = names().find(|item| match *item.0 {
+        ServerName::Exact(ref exact) => exact == name,
+        _ => false,
+    });
+    if let Some(item) = exact {
+        return Some(item.1);
+    }
+
+    let dotted = format!(".{}", name);
+    let suffix = names()
+        .filter_map(|item| match *item.0 {
+            ServerName::Suffix(ref suffix) if dotted.ends_with(suffix.as_str()) && dotted != *suffix => Some((suffix.len(), item.1)),
+            _ => None,
+        })
+        .max_by_key(|candidate| candidate.0);
+    if let Some(candidate) = suffix {
+        return Some(candidate.1);
+    }
+
+    let prefix = names()
+        .filter_map(|item| match *item.0 {
+            ServerName::Prefix(ref prefix) if name.starts_with(prefix.as_str()) && name != &prefix[..prefix.len() - 1] => Some((prefix.len(), item.1)),
+            _ => None,
+        })
+        .max_by_key(|candidate| candidate.0);
+    if let Some(candidate) = prefix {
+        return Some(candidate.1);
+    }
+
+    names()
+        .find(|item| match *item.0 {
+            ServerName::Pattern(ref regex) => regex.is_match(name),
+            _ => false,
+        })
+        .map(|item| item.1)
+}
+
+#[test]
+fn picks_vhosts_by_precedence() {
+    let site = SiteConfig::new_defaults();
+    let vhost = |names: &[&str]| VirtualHost {
+        names: names.iter().map(|name| ServerName::parse(name).ok().unwrap()).collect(),
+        default: false,
+        site: site.clone(),
+    };
+    let vhosts = vec![
+        vhost(&["~^api\\d+\\.example\\.(com|net)$"]),
+        vhost(&["*.example.org"]),
+        vhost(&["*.static.example.org", "www.example.*"]),
+        vhost(&["www.example.org"]),
+    ];
+    let index = |name: &str| find(&vhosts, name).map(|found| vhosts.iter().position(|vhost| ptr::eq(vhost, found)).unwrap());
+
+    assert!(index("www.example.org") == Some(3));
+    assert!(index("img.static.example.org") == Some(2));
+    assert!(index("api1.example.org") == Some(1));
+    assert!(index("api1.example.net") == Some(0));
+    assert!(index("www.example.net") == Some(2));
+    assert!(index("example.org") == None);
+
+    assert!(host_name("WWW.Example.org.:8080") == Some("www.example.org".to_string()));
+    assert!(host_name("[::1]:8000") == Some("[::1]".to_string()));
+    assert!(host_name("bad host") == None);
+    assert!(host_name("example.org:http") == None);
+}
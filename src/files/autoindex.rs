@@ -0,0 +1,217 @@
+// Copyright (c) 2015 Guillaume Pasquet
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// Directory listings, as HTML or JSON.
+
+use std::cmp::Ordering;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use http::date;
+use http::url::percent_encode;
+
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+impl SortKey {
+    pub fn from_query(value: Option<&str>) -> SortKey {
+        match value {
+            Some("size") => SortKey::Size,
+            Some("mtime") => SortKey::Modified,
+            _ => SortKey::Name,
+        }
+    }
+
+    fn as_query(&self) -> &'static str {
+        match *self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Modified => "mtime",
+        }
+    }
+}
+
+// Reads a directory. Hidden entries (starting with a dot) are skipped unless
+// asked for, as are names that aren't valid UTF-8.
+pub fn list(dir: &Path, show_hidden: bool) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        if name.starts_with('.') && !show_hidden {
+            continue;
+        }
+
+        // Follows links, so a link to a directory is listed as one.
+        let metadata = match fs::metadata(entry.path()) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+
+        entries.push(Entry {
+            name: name,
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().unwrap_or(UNIX_EPOCH),
+        });
+    }
+
+    Ok(entries)
+}
+
+// Directories always come first.
+pub fn sort(entries: &mut Vec<Entry>, key: SortKey, descending: bool) {
+    entries.sort_by(|a, b| {
+        let order = match key {
+            SortKey::Name => a.name.cmp(&b.name),
+            SortKey::Size => a.size.cmp(&b.size).then_with(|| a.name.cmp(&b.name)),
+            SortKey::Modified => a.modified.cmp(&b.modified).then_with(|| a.name.cmp(&b.name)),
+        };
+        let order = if descending { order.reverse() } else { order };
+
+        match (a.is_dir, b.is_dir) {
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            _ => order,
+        }
+    });
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+}
+
+// `url_path` is the decoded path of the directory, ending with a slash.
+pub fn render_html(url_path: &str, entries: &[Entry], key: SortKey, descending: bool) -> String {
+    let title = html_escape(url_path);
+
+    // Clicking the current sort column flips the order.
+    let column = |label: &str, column_key: SortKey| {
+        let order = if column_key == key && !descending { "desc" } else { "asc" };
+        format!("<th><a href=\"?sort={}&amp;order={}\">{}</a></th>", column_key.as_query(), order, label)
+    };
+
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {}</title></head>\n<body>\n<h1>Index of {}</h1>\n<table>\n<tr>{}{}{}</tr>\n",
+        title, title,
+        column("Name", SortKey::Name),
+        column("Size", SortKey::Size),
+        column("Last modified", SortKey::Modified)
+    );
+
+    if url_path != "/" {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td>-</td><td></td></tr>\n");
+    }
+
+    // Links start with ./ so a name like "javascript:..." can't be taken for
+    // a scheme.
+    for entry in entries {
+        let suffix = if entry.is_dir { "/" } else { "" };
+        let size = if entry.is_dir { "-".to_string() } else { entry.size.to_string() };
+        html.push_str(&format!(
+            "<tr><td><a href=\"./{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            html_escape(&percent_encode(&entry.name)), suffix,
+            html_escape(&entry.name), suffix,
+            size,
+            date::format_http_date(entry.modified)
+        ));
+    }
+
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+pub fn render_json(entries: &[Entry]) -> String {
+    let items: Vec<String> = entries.iter()
+        .map(|entry| format!(
+            "{{\"name\":\"{}\",\"type\":\"{}\",\"size\":{},\"mtime\":{}}}",
+            json_escape(&entry.name),
+            if entry.is_dir { "directory" } else { "file" },
+            entry.size,
+            unix_time(entry.modified)
+        ))
+        .collect();
+
+    format!("[{}]", items.join(","))
+}
+
+#[test]
+fn renders_sorted_listings() {
+    let mut entries = vec![
+        Entry { name: "b.txt".to_string(), is_dir: false, size: 10, modified: UNIX_EPOCH },
+        Entry { name: "a <b>.txt".to_string(), is_dir: false, size: 20, modified: UNIX_EPOCH },
+        Entry { name: "z\"dir".to_string(), is_dir: true, size: 0, modified: UNIX_EPOCH },
+        Entry { name: "javascript:alert(1)".to_string(), is_dir: false, size: 1, modified: UNIX_EPOCH },
+    ];
+
+    sort(&mut entries, SortKey::Size, true);
+    let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+    assert!(names == vec!["z\"dir", "a <b>.txt", "b.txt", "javascript:alert(1)"]);
+
+    let html = render_html("/files/", &entries, SortKey::Size, true);
+    assert!(html.contains("<a href=\"./a%20%3Cb%3E.txt\">a &lt;b&gt;.txt</a>"));
+    assert!(html.contains("<a href=\"./javascript:alert(1)\">") && !html.contains("href=\"javascript:"));
+    assert!(html.contains("<a href=\"?sort=size&amp;order=asc\">Size</a>"));
+    assert!(html.contains("<a href=\"../\">"));
+
+    let json = render_json(&entries[..1]);
+    assert!(json == "[{\"name\":\"z\\\"dir\",\"type\":\"directory\",\"size\":0,\"mtime\":0}]");
+}
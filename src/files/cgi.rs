@@ -0,0 +1,393 @@
+// Copyright (c) 2015 Guillaume Pasquet
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// Runs CGI scripts (RFC 3875) for locations with handler = "cgi".
+
+use std::collections::BTreeMap;
+use std::env;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::mpsc;
+use std::sync::mpsc::{RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+#[cfg(unix)]
+use libc;
+
+use config::location::LocationMatch;
+use config::siteconfig::SiteConfig;
+use config::vhost;
+use files::path;
+use files::path::ResolveError;
+use files::serve::resolve_error_response;
+use http::parser::MAX_HEAD_SIZE;
+use http::request::HttpRequest;
+use http::response::{HttpResponse, HttpStatus};
+use http::traits::FromU16;
+
+// Request bodies are read in full before the script starts, so it can't
+// block writing its output while the server is still writing its input.
+const MAX_BODY_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Clone)]
+pub struct CgiSettings {
+    // What the path of the script itself must match, the location's path.
+    // Other files along the path are never run, e.g. /uploads/a.jpg for
+    // /uploads/a.jpg/x.php.
+    pub scripts: LocationMatch,
+    // Program scripts are given to, e.g. /usr/bin/php-cgi. Without one the
+    // scripts are run themselves.
+    pub interpreter: Option<PathBuf>,
+    // Scripts still running after that long are killed.
+    pub timeout: Duration,
+}
+
+// The script a request path points to, and what's left of the path after
+// it.
+struct Script {
+    file: PathBuf,
+    name: String,
+    path_info: String,
+}
+
+// Walks the path under the root until it meets a file, which is the
+// script if its own path matches the location; the rest is its PATH_INFO.
+// A directory runs its index.
+fn find_script(site: &SiteConfig, url_path: &str, file_path: &str, scripts: &LocationMatch) -> Result<Script, ResolveError> {
+    let root = site.get_root_path();
+    let segments = path::normalize(file_path)?;
+
+    let mut file = root.to_path_buf();
+    for (index, segment) in segments.iter().enumerate() {
+        file.push(segment);
+        path::check(root, &file, site.get_symlinks())?;
+        if file.is_file() {
+            let rest = &segments[index + 1..];
+            let mut path_info: String = rest.iter().map(|segment| format!("/{}", segment)).collect();
+            if !rest.is_empty() && url_path.ends_with('/') {
+                path_info.push('/');
+            }
+            let name = url_path.strip_suffix(path_info.as_str()).unwrap_or(url_path).to_string();
+            if !scripts.matches(&name) {
+                return Err(ResolveError::NotFound);
+            }
+            return Ok(Script { file: file, name: name, path_info: path_info });
+        }
+    }
+
+    file.push(site.get_index());
+    path::check(root, &file, site.get_symlinks())?;
+    if !file.is_file() {
+        return Err(ResolveError::NotFound);
+    }
+    let name = format!("{}/{}", url_path.trim_end_matches('/'), site.get_index());
+    if !scripts.matches(&name) {
+        return Err(ResolveError::NotFound);
+    }
+    Ok(Script { file: file, name: name, path_info: String::new() })
+}
+
+// The meta-variables of RFC 3875, 4.1, along with a header's worth of
+// HTTP_ ones.
+fn environment(req: &HttpRequest, site: &SiteConfig, script: &Script, content_length: usize) -> Vec<(String, String)> {
+    let client = req.client();
+    let host = req.host().unwrap_or("");
+    let default_port = if client.secure { "443" } else { "80" };
+    let port = match host.rfind(':') {
+        Some(pos) if !host[pos..].contains(']') => &host[pos + 1..],
+        _ => default_port,
+    };
+    let (remote_addr, remote_port) = match client.address.parse::<SocketAddr>() {
+        Ok(address) => (address.ip().to_string(), address.port().to_string()),
+        Err(_) => (client.address.clone(), String::new()),
+    };
+
+    let mut vars = vec![
+        ("GATEWAY_INTERFACE", String::from("CGI/1.1")),
+        ("SERVER_SOFTWARE", String::from("irontray")),
+        ("SERVER_PROTOCOL", req.version().to_string()),
+        ("SERVER_NAME", vhost::host_name(host).unwrap_or_else(|| String::from("localhost"))),
+        ("SERVER_PORT", port.to_string()),
+        ("REQUEST_METHOD", req.method().to_string()),
+        ("REQUEST_URI", req.target.clone()),
+        ("SCRIPT_NAME", script.name.clone()),
+        ("SCRIPT_FILENAME", script.file.to_string_lossy().into_owned()),
+        ("DOCUMENT_ROOT", site.get_root_path().to_string_lossy().into_owned()),
+        ("QUERY_STRING", req.query.clone().unwrap_or_default()),
+        ("REMOTE_ADDR", remote_addr),
+        ("REMOTE_PORT", remote_port),
+        // PHP refuses to run as CGI without it.
+        ("REDIRECT_STATUS", String::from("200")),
+    ];
+    if !script.path_info.is_empty() {
+        vars.push(("PATH_INFO", script.path_info.clone()));
+    }
+    if content_length > 0 {
+        vars.push(("CONTENT_LENGTH", content_length.to_string()));
+    }
+    if let Some(content_type) = req.headers.get("Content-Type") {
+        vars.push(("CONTENT_TYPE", content_type.to_string()));
+    }
+    if client.secure {
+        vars.push(("HTTPS", String::from("on")));
+    }
+    let mut vars: Vec<(String, String)> = vars.into_iter().map(|(name, value)| (name.to_string(), value)).collect();
+
+    // Names with an underscore would pass for others once converted, and
+    // Proxy would become HTTP_PROXY, which many programs take for their
+    // proxy. Credentials stay with the server.
+    let mut headers: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for header in req.headers.iter() {
+        let name = header.name.to_ascii_uppercase();
+        if name.contains('_') || ["CONTENT-TYPE", "CONTENT-LENGTH", "PROXY", "AUTHORIZATION"].contains(&name.as_str()) {
+            continue;
+        }
+        headers.entry(format!("HTTP_{}", name.replace('-', "_"))).or_default().push(&header.value);
+    }
+    for (name, values) in headers {
+        vars.push((name, values.join(", ")));
+    }
+    vars
+}
+
+// Output of a script, read as the response is sent. Once it's dropped the
+// script is left to exit if its output was read in full, and killed
+// otherwise.
+struct CgiOutput {
+    stdout: BufReader<ChildStdout>,
+    finished: bool,
+    done: Sender<bool>,
+}
+
+impl Read for CgiOutput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.stdout.read(buf)?;
+        if len == 0 {
+            self.finished = true;
+        }
+        Ok(len)
+    }
+}
+
+impl Drop for CgiOutput {
+    fn drop(&mut self) {
+        let _ = self.done.send(self.finished);
+    }
+}
+
+// Kills the script along with anything it started, which could otherwise
+// keep its output open.
+#[cfg(unix)]
+fn kill(child: &mut Child) {
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+}
+
+// Reaps the script, killing it if it runs for too long or its output isn't
+// wanted anymore.
+fn watch(mut child: Child, name: String, timeout: Duration) -> Sender<bool> {
+    let (done, finished) = mpsc::channel();
+    thread::spawn(move || {
+        match finished.recv_timeout(timeout) {
+            Ok(true) => {},
+            Ok(false) | Err(RecvTimeoutError::Disconnected) => kill(&mut child),
+            Err(RecvTimeoutError::Timeout) => {
+                error!("CGI script {} still running after {}s, killing it", name, timeout.as_secs());
+                kill(&mut child);
+            },
+        }
+        let _ = child.wait();
+    });
+    done
+}
+
+// Reads a line of the script's header, without its line ending.
+fn read_line(output: &mut CgiOutput, size: &mut usize) -> Result<String, String> {
+    let mut line = Vec::new();
+    output.stdout.by_ref().take((MAX_HEAD_SIZE - *size) as u64 + 1).read_until(b'\n', &mut line)
+        .map_err(|e| format!("couldn't read its output: {}", e))?;
+    *size += line.len();
+    if *size > MAX_HEAD_SIZE || line.pop() != Some(b'\n') {
+        return Err(String::from("header too large or cut short"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map_err(|_| String::from("invalid bytes in its header"))
+}
+
+// The response a script's header describes (RFC 3875, 6.3), with the rest
+// of its output as the body.
+fn parse_output(mut output: CgiOutput) -> Result<HttpResponse, String> {
+    let mut size = 0;
+    let mut status = None;
+    let mut length = None;
+    let mut redirect = false;
+    let mut builder = HttpResponse::builder();
+
+    loop {
+        let line = read_line(&mut output, &mut size)?;
+        if line.is_empty() {
+            break;
+        }
+        let mut field = line.splitn(2, ':');
+        let name = field.next().unwrap_or("").trim();
+        let value = match field.next() {
+            Some(value) if !name.is_empty() => value.trim(),
+            _ => return Err(format!("invalid header line {:?}", line)),
+        };
+
+        match name.to_ascii_lowercase().as_str() {
+            "status" => {
+                let code = value.get(..3).and_then(|code| code.parse::<u16>().ok()).filter(|code| (200..600).contains(code));
+                let code = code.ok_or_else(|| format!("invalid status {:?}", value))?;
+                // Unknown codes are understood as the x00 of their class.
+                status = Some(HttpStatus::from_u16(code).or_else(|_| HttpStatus::from_u16(code / 100 * 100)).unwrap());
+            },
+            "content-length" => {
+                length = Some(value.parse::<u64>().map_err(|_| format!("invalid Content-Length {:?}", value))?);
+            },
+            "connection" | "keep-alive" | "transfer-encoding" => {},
+            _ => {
+                redirect |= name.eq_ignore_ascii_case("location");
+                builder = builder.header(name, value);
+            },
+        }
+    }
+
+    let status = status.unwrap_or(if redirect { HttpStatus::FOUND } else { HttpStatus::OK });
+    Ok(builder.status(status).stream(Box::new(output), length))
+}
+
+// Runs the script the request path points to and streams its output back.
+pub fn run(req: &mut HttpRequest, site: &SiteConfig, url_path: &str, file_path: &str, settings: &CgiSettings) -> HttpResponse {
+    let script = match find_script(site, url_path, file_path, &settings.scripts) {
+        Ok(script) => script,
+        Err(e) => return resolve_error_response(&e),
+    };
+
+    let mut body = Vec::new();
+    if let Err(e) = req.body.by_ref().take(MAX_BODY_SIZE + 1).read_to_end(&mut body) {
+        error!("Couldn't read the body for CGI script {}: {}", script.name, e);
+        return HttpResponse::quick_error(HttpStatus::BAD_REQUEST, String::from("Broken request body"));
+    }
+    if body.len() as u64 > MAX_BODY_SIZE {
+        return HttpResponse::quick_error(HttpStatus::REQUEST_ENTITY_TOO_LARGE, String::from("Request body too large"));
+    }
+
+    let mut command = match settings.interpreter {
+        Some(ref interpreter) => {
+            let mut command = Command::new(interpreter);
+            command.arg(&script.file);
+            command
+        },
+        None => Command::new(&script.file),
+    };
+    command.env_clear()
+        .envs(environment(req, site, &script, body.len()))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit());
+    // Scripts run with #!/usr/bin/env need a PATH.
+    if let Some(search_path) = env::var_os("PATH") {
+        command.env("PATH", search_path);
+    }
+    if let Some(dir) = script.file.parent() {
+        command.current_dir(dir);
+    }
+    // In a process group of its own, for kill() to reach its children.
+    #[cfg(unix)]
+    command.process_group(0);
+
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            error!("Couldn't run CGI script {}: {}", script.name, e);
+            return HttpResponse::quick_server_error(String::from("Couldn't run the script"));
+        }
+    };
+
+    let mut stdin = child.stdin.take().unwrap();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let done = watch(child, script.name.clone(), settings.timeout);
+
+    // Written from another thread, so the script can start answering
+    // before it read everything. A script may stop reading and close its
+    // input, but one left with part of the body is killed rather than let
+    // answer as if that was all of it.
+    let failed = done.clone();
+    let name = script.name.clone();
+    thread::spawn(move || match stdin.write_all(&body) {
+        Ok(()) => {},
+        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => {},
+        Err(e) => {
+            error!("Couldn't send the body to CGI script {}: {}", name, e);
+            let _ = failed.send(false);
+        }
+    });
+    let output = CgiOutput {
+        stdout: stdout,
+        finished: false,
+        done: done,
+    };
+
+    match parse_output(output) {
+        Ok(response) => response,
+        Err(e) => {
+            error!("CGI script {} failed: {}", script.name, e);
+            HttpResponse::quick_error(HttpStatus::BAD_GATEWAY, String::from("Bad gateway"))
+        }
+    }
+}
+
+#[test]
+fn only_runs_scripts_matching_the_location() {
+    extern crate toml;
+    use std::fs;
+
+    let root = env::temp_dir().join(format!("irontray-cgi-test-{}", ::std::process::id()));
+    fs::create_dir_all(root.join("uploads")).unwrap();
+    fs::write(root.join("uploads/a.jpg"), "<?php echo 1;").unwrap();
+    fs::write(root.join("run.php"), "<?php echo 2;").unwrap();
+
+    let mut section = toml::Table::new();
+    section.insert(String::from("root_path"), toml::Value::String(root.to_string_lossy().into_owned()));
+    let site = SiteConfig::from_toml(&section, None, "http").ok().unwrap();
+    let scripts = LocationMatch::parse("~ \\.php(/|$)").ok().unwrap();
+    let find = |url_path: &str| find_script(&site, url_path, url_path, &scripts).map(|script| (script.name, script.path_info));
+
+    assert!(find("/uploads/a.jpg/x.php").err() == Some(ResolveError::NotFound));
+    assert!(find("/run.php/a/b/").ok() == Some((String::from("/run.php"), String::from("/a/b/"))));
+    assert!(find("/run.php").ok() == Some((String::from("/run.php"), String::new())));
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,104 @@
+// Copyright (c) 2015 Guillaume Pasquet
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// Entity tags for files on disk.
+
+use std::fs::{File, Metadata};
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::str::FromStr;
+use std::time::UNIX_EPOCH;
+
+// How file entity tags are computed.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EtagMode {
+    // From the inode, size and modification time. Cheap, but changes when
+    // the same content is copied to another server.
+    Metadata,
+    // From a hash of the content, read in full on every request.
+    Content,
+    // No ETag is sent.
+    Off,
+}
+
+impl FromStr for EtagMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<EtagMode, ()> {
+        match s {
+            "metadata" => Ok(EtagMode::Metadata),
+            "content"  => Ok(EtagMode::Content),
+            "off"      => Ok(EtagMode::Off),
+            _ => Err(()),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn inode(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+
+#[cfg(not(unix))]
+fn inode(_: &Metadata) -> u64 {
+    0
+}
+
+// 64-bit FNV-1a, stable across builds unlike the std hasher.
+fn content_hash(file: &mut File) -> io::Result<u64> {
+    let mut hash = 0xcbf29ce484222325u64;
+    let mut buf = vec![0u8; 65536];
+
+    loop {
+        let len = match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        for &b in &buf[..len] {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    file.seek(SeekFrom::Start(0))?;
+    Ok(hash)
+}
+
+// Strong ETag of an open file, including the quotes. The file is left at
+// its start.
+pub fn compute(file: &mut File, metadata: &Metadata, mode: EtagMode) -> io::Result<Option<String>> {
+    match mode {
+        EtagMode::Metadata => {
+            let modified = metadata.modified()?
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or(0);
+            Ok(Some(format!("\"{:x}-{:x}-{:x}\"", inode(metadata), metadata.len(), modified)))
+        },
+        EtagMode::Content => {
+            let hash = content_hash(file)?;
+            Ok(Some(format!("\"{:016x}-{:x}\"", hash, metadata.len())))
+        },
+        EtagMode::Off => Ok(None),
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright (c) 2015 Guillaume Pasquet
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// Maps request paths to files under a document root without letting them
+// escape it.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use http::url::percent_decode;
+
+// What to do with symbolic links found under the root.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SymlinkPolicy {
+    // Follow every link, wherever it points to.
+    Follow,
+    // Follow links as long as their target is inside the root.
+    WithinRoot,
+    // Refuse any path going through a link.
+    Deny,
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<SymlinkPolicy, ()> {
+        match s {
+            "follow"      => Ok(SymlinkPolicy::Follow),
+            "within_root" => Ok(SymlinkPolicy::WithinRoot),
+            "deny"        => Ok(SymlinkPolicy::Deny),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub enum ResolveError {
+    // The path can't be decoded.
+    BadRequest,
+    // The path tries to leave the root, or goes through a refused link.
+    Forbidden,
+    NotFound,
+}
+
+fn io_error(e: io::Error) -> ResolveError {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => ResolveError::Forbidden,
+        _ => ResolveError::NotFound,
+    }
+}
+
+// Decodes the path of a request target and removes dot segments. Fails with
+// Forbidden if ".." would climb above the root.
+pub fn normalize(target: &str) -> Result<Vec<String>, ResolveError> {
+    let path = target.split(|c| c == '?' || c == '#').next().unwrap_or("");
+    let mut segments: Vec<String> = Vec::new();
+
+    for raw in path.split('/') {
+        let decoded = percent_decode(raw).map_err(|_| ResolveError::BadRequest)?;
+        let segment = String::from_utf8(decoded).map_err(|_| ResolveError::BadRequest)?;
+
+        if segment.contains('\0') {
+            return Err(ResolveError::BadRequest);
+        }
+        // An encoded slash would make the segment mean something else once
+        // handed to the filesystem.
+        if segment.contains('/') {
+            return Err(ResolveError::NotFound);
+        }
+
+        match segment.as_str() {
+            "" | "." => {},
+            ".." => {
+                if segments.pop().is_none() {
+                    return Err(ResolveError::Forbidden);
+                }
+            },
+            _ => segments.push(segment),
+        }
+    }
+
+    Ok(segments)
+}
+
+// The normalized path as a string, e.g. "/a/c/" for "/a/./b/../c/", to
+// match it against locations. A trailing slash is kept.
+pub fn normalize_path(target: &str) -> Result<String, ResolveError> {
+    let segments = normalize(target)?;
+    let path = target.split(['?', '#']).next().unwrap_or("");
+
+    let mut normalized = format!("/{}", segments.join("/"));
+    if path.ends_with('/') && !segments.is_empty() {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+// Checks that an existing path is acceptable under the symlink policy. The
+// root is expected to be canonical already.
+pub fn check(root: &Path, path: &Path, policy: SymlinkPolicy) -> Result<(), ResolveError> {
+    let real = path.canonicalize().map_err(io_error)?;
+
+    match policy {
+        SymlinkPolicy::Follow => Ok(()),
+        SymlinkPolicy::WithinRoot => {
+            if real.starts_with(root) {
+                Ok(())
+            } else {
+                Err(ResolveError::Forbidden)
+            }
+        },
+        SymlinkPolicy::Deny => {
+            let relative = path.strip_prefix(root).map_err(|_| ResolveError::Forbidden)?;
+            let mut current = root.to_path_buf();
+            for component in relative.components() {
+                current.push(component);
+                let metadata = fs::symlink_metadata(&current).map_err(io_error)?;
+                if metadata.file_type().is_symlink() {
+                    return Err(ResolveError::Forbidden);
+                }
+            }
+            Ok(())
+        },
+    }
+}
+
+// Finds the file a request target points to under the root.
+pub fn resolve(root: &Path, target: &str, policy: SymlinkPolicy) -> Result<PathBuf, ResolveError> {
+    let mut path = root.to_path_buf();
+    for segment in normalize(target)? {
+        path.push(segment);
+    }
+
+    check(root, &path, policy)?;
+    Ok(path)
+}
+
+#[test]
+fn normalize_removes_dot_segments() {
+    assert!(normalize_path("/a/./b/../%63/") == Ok("/a/c/".to_string()));
+    assert!(normalize_path("/") == Ok("/".to_string()));
+    assert!(normalize("/a/./b/../c%20d?x=../..").unwrap() == vec!["a", "c d"]);
+    assert!(normalize("//a//b/").unwrap() == vec!["a", "b"]);
+    assert!(normalize("/a/../..").unwrap_err() == ResolveError::Forbidden);
+    assert!(normalize("/%2e%2e/etc/passwd").unwrap_err() == ResolveError::Forbidden);
+    assert!(normalize("/a%2f..%2f..").unwrap_err() == ResolveError::NotFound);
+    assert!(normalize("/a%00.html").unwrap_err() == ResolveError::BadRequest);
+    assert!(normalize("/%ff").unwrap_err() == ResolveError::BadRequest);
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinks_follow_the_policy() {
+    use std::env;
+    use std::os::unix::fs::symlink;
+
+    let base = env::temp_dir().join(format!("irontray-path-test-{}", ::std::process::id()));
+    let root = base.join("root");
+    fs::create_dir_all(root.join("dir")).unwrap();
+    fs::write(base.join("secret"), "secret").unwrap();
+    fs::write(root.join("dir/page.html"), "page").unwrap();
+    symlink(base.join("secret"), root.join("outside")).unwrap();
+    symlink(root.join("dir/page.html"), root.join("inside")).unwrap();
+    let root = root.canonicalize().unwrap();
+
+    assert!(resolve(&root, "/dir/page.html", SymlinkPolicy::Deny).is_ok());
+    assert!(resolve(&root, "/inside", SymlinkPolicy::WithinRoot).is_ok());
+    assert!(resolve(&root, "/inside", SymlinkPolicy::Deny).unwrap_err() == ResolveError::Forbidden);
+    assert!(resolve(&root, "/outside", SymlinkPolicy::WithinRoot).unwrap_err() == ResolveError::Forbidden);
+    assert!(resolve(&root, "/outside", SymlinkPolicy::Follow).is_ok());
+    assert!(resolve(&root, "/missing", SymlinkPolicy::Follow).unwrap_err() == ResolveError::NotFound);
+
+    fs::remove_dir_all(&base).unwrap();
+}
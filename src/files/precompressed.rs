@@ -0,0 +1,167 @@
+// Copyright (c) 2015 Guillaume Pasquet
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// Precompressed siblings of static files, e.g. app.js.br next to app.js,
+// served in place of the file to clients that accept their coding.
+
+use std::ffi::OsString;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use files::path;
+use files::path::SymlinkPolicy;
+use http::compress::{Coding, CompressionSettings, Encoder};
+use http::mime::MimeTypes;
+
+// Codings looked for, most preferred first.
+const CODINGS: [Coding; 3] = [Coding::Brotli, Coding::Zstd, Coding::Gzip];
+
+// Path of the sibling holding the file compressed with that coding.
+pub fn sidecar_path(file: &Path, coding: Coding) -> Option<PathBuf> {
+    coding.extension().map(|extension| {
+        let mut name = OsString::from(file.as_os_str());
+        name.push(".");
+        name.push(extension);
+        PathBuf::from(name)
+    })
+}
+
+fn is_sidecar(file: &Path) -> bool {
+    let extension = file.extension().and_then(|extension| extension.to_str());
+    CODINGS.iter().any(|coding| coding.extension() == extension)
+}
+
+// Sidecars of a file that can be served, in order of preference. They go
+// through the same checks as the file itself.
+pub fn available(root: &Path, file: &Path, policy: SymlinkPolicy) -> Vec<(Coding, PathBuf)> {
+    CODINGS.iter()
+        .filter_map(|&coding| sidecar_path(file, coding).map(|sidecar| (coding, sidecar)))
+        .filter(|(_, sidecar)| sidecar.is_file() && path::check(root, sidecar, policy).is_ok())
+        .collect()
+}
+
+#[derive(Default)]
+pub struct Summary {
+    pub written: usize,
+    pub up_to_date: usize,
+    // Sidecars that came out no smaller than the file.
+    pub discarded: usize,
+}
+
+fn is_up_to_date(source: &fs::Metadata, sidecar: &Path) -> bool {
+    let sidecar_modified = fs::metadata(sidecar).and_then(|metadata| metadata.modified());
+    match (source.modified(), sidecar_modified) {
+        (Ok(source), Ok(sidecar)) => sidecar >= source,
+        _ => false,
+    }
+}
+
+// Compresses to a temporary file first so a half-written sidecar is never
+// served.
+fn write_sidecar(file: &Path, sidecar: &Path, coding: Coding, length: u64, summary: &mut Summary) -> io::Result<()> {
+    let mut temp = OsString::from(sidecar.as_os_str());
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+
+    let written = {
+        let mut encoder = Encoder::new(coding, coding.best_level(), File::create(&temp)?)?;
+        io::copy(&mut File::open(file)?, &mut encoder)?;
+        encoder.finish()?.metadata()?.len()
+    };
+
+    if written >= length {
+        fs::remove_file(&temp)?;
+        if sidecar.exists() {
+            fs::remove_file(sidecar)?;
+        }
+        summary.discarded += 1;
+    } else {
+        fs::rename(&temp, sidecar)?;
+        summary.written += 1;
+    }
+    Ok(())
+}
+
+fn compress_file(file: &Path, metadata: &fs::Metadata, summary: &mut Summary) -> io::Result<()> {
+    for &coding in CODINGS.iter() {
+        let sidecar = match sidecar_path(file, coding) {
+            Some(sidecar) => sidecar,
+            None => continue,
+        };
+
+        if is_up_to_date(metadata, &sidecar) {
+            summary.up_to_date += 1;
+        } else {
+            write_sidecar(file, &sidecar, coding, metadata.len(), summary)?;
+        }
+    }
+    Ok(())
+}
+
+// Creates or refreshes the sidecars of every compressible file under a
+// directory. Symbolic links aren't followed.
+pub fn compress_dir(dir: &Path, mime_types: &MimeTypes, settings: &CompressionSettings, summary: &mut Summary) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let file = entry.path();
+
+        if file_type.is_dir() {
+            compress_dir(&file, mime_types, settings, summary)?;
+        } else if file_type.is_file() && !is_sidecar(&file) {
+            let metadata = entry.metadata()?;
+            if metadata.len() >= settings.min_size() && settings.is_compressible(&mime_types.content_type(&file)) {
+                compress_file(&file, &metadata, summary)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn writes_and_finds_sidecars() {
+    use std::env;
+    use std::io::Write;
+    use std::process;
+
+    let root = env::temp_dir().join(format!("irontray-precompressed-{}", process::id()));
+    fs::create_dir_all(root.join("css")).unwrap();
+    let root = root.canonicalize().unwrap();
+    let style = root.join("css/site.css");
+    File::create(&style).unwrap().write_all("body { color: red; }\n".repeat(200).as_bytes()).unwrap();
+    File::create(root.join("logo.png")).unwrap().write_all(&[0u8; 4096]).unwrap();
+
+    let mut summary = Summary::default();
+    compress_dir(&root, &MimeTypes::new(), &CompressionSettings::new(), &mut summary).unwrap();
+    assert!(summary.written == 3);
+    assert!(!root.join("logo.png.gz").exists());
+
+    let codings: Vec<Coding> = available(&root, &style, SymlinkPolicy::WithinRoot).iter().map(|sidecar| sidecar.0).collect();
+    assert!(codings == vec![Coding::Brotli, Coding::Zstd, Coding::Gzip]);
+
+    // Nothing to do the second time around.
+    let mut summary = Summary::default();
+    compress_dir(&root, &MimeTypes::new(), &CompressionSettings::new(), &mut summary).unwrap();
+    assert!(summary.written == 0 && summary.up_to_date == 3);
+
+    fs::remove_dir_all(&root).unwrap();
+}
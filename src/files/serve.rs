@@ -0,0 +1,342 @@
+// Copyright (c) 2015 Guillaume Pasquet
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// Serves files and directories from the document root.
+
+use std::fs::{File, Metadata};
+use std::io;
+use std::io::{ErrorKind, Seek, SeekFrom};
+use std::path::Path;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use config::siteconfig::SiteConfig;
+use files::autoindex;
+use files::autoindex::SortKey;
+use files::etag;
+use files::path;
+use files::precompressed;
+use files::path::ResolveError;
+use http::compress;
+use http::compress::Coding;
+use http::conditional;
+use http::date;
+use http::range;
+use http::range::{ByteRange, RangeError};
+use http::request::{HttpMethod, HttpRequest};
+use http::response::{HttpResponse, HttpStatus, Segment};
+use http::url::percent_encode;
+
+fn file_error_response(e: &io::Error) -> HttpResponse {
+    match e.kind() {
+        ErrorKind::NotFound => HttpResponse::quick_not_found("File not found!".to_string()),
+        ErrorKind::PermissionDenied => HttpResponse::quick_error(HttpStatus::FORBIDDEN, "Forbidden".to_string()),
+        _ => HttpResponse::quick_server_error("Couldn't read file!".to_string()),
+    }
+}
+
+pub fn resolve_error_response(e: &ResolveError) -> HttpResponse {
+    match *e {
+        ResolveError::BadRequest => HttpResponse::quick_error(HttpStatus::BAD_REQUEST, "Bad request".to_string()),
+        ResolveError::Forbidden => HttpResponse::quick_error(HttpStatus::FORBIDDEN, "Forbidden".to_string()),
+        ResolveError::NotFound => HttpResponse::quick_not_found("File not found!".to_string()),
+    }
+}
+
+// Relative links in a directory page only work if its URL ends with a slash.
+// The location is built from the normalized path, as a raw "//evil.com/.."
+// would send the client to another host.
+fn directory_redirect(req: &HttpRequest) -> HttpResponse {
+    let normalized = match path::normalize_path(&req.path) {
+        Ok(normalized) => normalized,
+        Err(e) => return resolve_error_response(&e),
+    };
+    let mut encoded = normalized.split('/').map(percent_encode).collect::<Vec<String>>().join("/");
+    if !encoded.ends_with('/') {
+        encoded.push('/');
+    }
+
+    let location = match req.query {
+        Some(ref query) => format!("{}?{}", encoded, query),
+        None => encoded,
+    };
+
+    HttpResponse::redirect(HttpStatus::MOVED_PERMANENTLY, &location)
+}
+
+// JSON is only picked when the client prefers it over HTML.
+fn wants_json(req: &HttpRequest) -> bool {
+    let mut json = 0.0;
+    let mut html = 0.0;
+
+    for (media_type, quality) in req.headers.get_weighted("Accept") {
+        match media_type.as_str() {
+            "application/json" => json = quality,
+            "text/html" => html = quality,
+            "*/*" if html == 0.0 => html = quality,
+            _ => {}
+        }
+    }
+
+    json > html
+}
+
+fn directory_listing(req: &HttpRequest, config: &SiteConfig, dir: &Path) -> HttpResponse {
+    let mut entries = match autoindex::list(dir, config.get_autoindex_hidden()) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Couldn't list directory {:?}: {}", dir, e);
+            return file_error_response(&e);
+        }
+    };
+
+    let params = req.query_params();
+    let key = SortKey::from_query(params.get("sort"));
+    let descending = params.get("order") == Some("desc");
+    autoindex::sort(&mut entries, key, descending);
+
+    if wants_json(req) {
+        return HttpResponse::builder()
+            .header("Content-Type", "application/json")
+            .header("Vary", "Accept")
+            .body(autoindex::render_json(&entries));
+    }
+
+    let url_path = req.decoded_path().unwrap_or_else(|| req.path.clone());
+    HttpResponse::builder()
+        .header("Content-Type", "text/html; charset=utf-8")
+        .header("Vary", "Accept")
+        .body(autoindex::render_html(&url_path, &entries, key, descending))
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+}
+
+// A Range header only applies if the If-Range validator still matches the
+// file. ETags are compared strongly, and dates have a one-second resolution so
+// they must match exactly.
+fn if_range_matches(req: &HttpRequest, metadata: &Metadata, etag: Option<&str>) -> bool {
+    let value = match req.headers.get("If-Range") {
+        Some(value) => value,
+        None => return true,
+    };
+
+    if value.starts_with('"') || value.starts_with("W/") {
+        return etag.map_or(false, |etag| conditional::strong_match(value, etag));
+    }
+
+    match (date::parse_http_date(value), metadata.modified()) {
+        (Some(since), Ok(modified)) => seconds(since) == seconds(modified),
+        _ => false,
+    }
+}
+
+fn multipart_boundary() -> String {
+    let elapsed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("{:x}{:08x}{:x}", elapsed.as_secs(), elapsed.subsec_nanos(), process::id())
+}
+
+fn range_not_satisfiable(length: u64) -> HttpResponse {
+    HttpResponse::builder()
+        .status(HttpStatus::REQUEST_RANGE_NOT_SATISFIABLE)
+        .header("Content-Range", &format!("bytes */{}", length))
+        .header("Content-Type", "text/html")
+        .body("Requested range not satisfiable".to_string())
+}
+
+// A single range is sent as is, several as a multipart/byteranges body.
+fn partial_response(mut file: File, content_type: &str, ranges: Vec<ByteRange>, length: u64) -> HttpResponse {
+    if ranges.len() == 1 {
+        let range = ranges[0];
+        if let Err(e) = file.seek(SeekFrom::Start(range.start)) {
+            return file_error_response(&e);
+        }
+
+        return HttpResponse::builder()
+            .status(HttpStatus::PARTIAL_CONTENT)
+            .header("Accept-Ranges", "bytes")
+            .header("Content-Type", content_type)
+            .header("Content-Range", &range.content_range(length))
+            .file(file, range.len());
+    }
+
+    let boundary = multipart_boundary();
+    let mut segments = Vec::new();
+    for range in ranges {
+        let part_head = format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+            boundary, content_type, range.content_range(length)
+        );
+        segments.push(Segment::Bytes(part_head.into_bytes()));
+        segments.push(Segment::FileRange(range.start, range.len()));
+    }
+    segments.push(Segment::Bytes(format!("\r\n--{}--\r\n", boundary).into_bytes()));
+
+    HttpResponse::builder()
+        .status(HttpStatus::PARTIAL_CONTENT)
+        .header("Accept-Ranges", "bytes")
+        .header("Content-Type", &format!("multipart/byteranges; boundary={}", boundary))
+        .segments(file, segments)
+}
+
+// The whole file, or the part of it asked for with a Range header. Ranges are
+// only honoured on GET and an invalid Range header is ignored.
+fn file_response(req: &HttpRequest, file: File, metadata: &Metadata, content_type: &str, etag: Option<&str>) -> HttpResponse {
+    let length = metadata.len();
+    let range_header = match *req.method() {
+        HttpMethod::GET => req.headers.get("Range"),
+        _ => None,
+    };
+
+    if let Some(header) = range_header {
+        if if_range_matches(req, metadata, etag) {
+            match range::parse(header, length) {
+                Ok(ranges) => return partial_response(file, content_type, ranges, length),
+                Err(RangeError::Unsatisfiable) => return range_not_satisfiable(length),
+                Err(RangeError::Invalid) => {},
+            }
+        }
+    }
+
+    HttpResponse::builder()
+        .header("Accept-Ranges", "bytes")
+        .header("Content-Type", content_type)
+        .file(file, length)
+}
+
+// The compressed bytes are a representation of their own, with an ETag
+// derived from the file's.
+fn etag_variant(etag: &str, coding: Coding) -> String {
+    format!("{}-{}\"", etag.trim_end_matches('"'), coding.name())
+}
+
+// The file's precompressed sibling, with the type of the original.
+fn precompressed_response(req: &HttpRequest, sidecar: &Path, coding: Coding, content_type: &str, etag: Option<&str>) -> io::Result<HttpResponse> {
+    let file = File::open(sidecar)?;
+    let metadata = file.metadata()?;
+
+    let mut response = file_response(req, file, &metadata, content_type, etag);
+    response.headers.set("Content-Encoding", coding.name());
+    Ok(response)
+}
+
+// Answers with the file at path under the root. The path is the request's,
+// unless a location aliased part of it.
+pub fn serve(req: &HttpRequest, config: &SiteConfig, path: &str) -> HttpResponse {
+    let root = config.get_root_path();
+    let mut file_path = match path::resolve(root, path, config.get_symlinks()) {
+        Ok(file_path) => file_path,
+        Err(e) => return resolve_error_response(&e),
+    };
+
+    if file_path.is_dir() {
+        if !req.path.ends_with('/') {
+            return directory_redirect(req);
+        }
+
+        let dir = file_path.clone();
+        file_path.push(config.get_index());
+        match path::check(root, &file_path, config.get_symlinks()) {
+            Ok(()) => {},
+            Err(ResolveError::NotFound) if config.get_autoindex() => {
+                return directory_listing(req, config, &dir);
+            },
+            Err(e) => return resolve_error_response(&e),
+        }
+    }
+
+    let mut file = match File::open(&file_path) {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Couldn't open file {:?}: {}", file_path, e);
+            return file_error_response(&e);
+        }
+    };
+
+    let metadata = match file.metadata() {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            error!("Couldn't stat file {:?}: {}", file_path, e);
+            return file_error_response(&e);
+        }
+    };
+
+    if !metadata.is_file() {
+        return HttpResponse::quick_not_found("File not found!".to_string());
+    }
+
+    let etag = match etag::compute(&mut file, &metadata, config.get_etag()) {
+        Ok(etag) => etag,
+        Err(e) => {
+            error!("Couldn't compute ETag of {:?}: {}", file_path, e);
+            return file_error_response(&e);
+        }
+    };
+
+    let content_type = config.get_mime_types().content_type(&file_path);
+    let sidecars = if config.get_precompressed() {
+        precompressed::available(root, &file_path, config.get_symlinks())
+    } else {
+        Vec::new()
+    };
+    let offered: Vec<Coding> = sidecars.iter().map(|sidecar| sidecar.0).collect();
+
+    let (mut response, etag) = match compress::negotiate(&req.headers, &offered) {
+        Some(coding) => {
+            let sidecar = &sidecars[offered.iter().position(|&offer| offer == coding).unwrap()].1;
+            let etag = etag.map(|etag| etag_variant(&etag, coding));
+            match precompressed_response(req, sidecar, coding, &content_type, etag.as_ref().map(|etag| etag.as_str())) {
+                Ok(response) => (response, etag),
+                Err(e) => {
+                    error!("Couldn't open file {:?}: {}", sidecar, e);
+                    return file_error_response(&e);
+                }
+            }
+        },
+        None => (file_response(req, file, &metadata, &content_type, etag.as_ref().map(|etag| etag.as_str())), etag),
+    };
+
+    if !sidecars.is_empty() {
+        response.headers.add("Vary", "Accept-Encoding");
+    }
+    if let Some(ref etag) = etag {
+        response.headers.set("ETag", etag);
+    }
+    if let Ok(modified) = metadata.modified() {
+        response.headers.set("Last-Modified", &date::format_http_date(modified));
+    }
+    response
+}
+
+#[test]
+fn redirects_directories_to_their_normalized_path() {
+    use http::traits::FromString;
+
+    let location = |target: &str| {
+        let req = HttpRequest::from_string(format!("GET {} HTTP/1.1\r\nHost: example.org", target)).ok().unwrap();
+        let response = directory_redirect(&req);
+        response.headers.get("Location").map(String::from)
+    };
+
+    assert!(location("//evil.com/..") == Some(String::from("/")));
+    assert!(location("//evil.com/%2e%2e?a=1") == Some(String::from("/?a=1")));
+    assert!(location("/a/./b%20c") == Some(String::from("/a/b%20c/")));
+}
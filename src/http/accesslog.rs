@@ -0,0 +1,58 @@
+// Copyright (c) 2015 Guillaume Pasquet
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+// Access log files. Each server can have its own; lines are appended as
+// responses are sent, from any connection thread.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use http::date;
+
+#[derive(Clone)]
+pub struct AccessLog {
+    path: String,
+    file: Arc<Mutex<File>>,
+}
+
+impl AccessLog {
+    pub fn open(path: &str) -> Result<AccessLog, String> {
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => Ok(AccessLog { path: path.to_string(), file: Arc::new(Mutex::new(file)) }),
+            Err(e) => Err(format!("Couldn't open access log {}: {}", path, e)),
+        }
+    }
+
+    // Writes the same line as syslog gets, with the time in front, e.g.
+    // `[Sun, 06 Nov 1994 08:49:37 GMT] 127.0.0.1:4242 GET / HTTP/1.1 "curl" 200 512`.
+    pub fn write(&self, client: &str, request: &str, status: u16, length: u64) {
+        let line = format!("[{}] {} {} {} {}\n", date::now(), client, request, status, length);
+        let mut file = match self.file.lock() {
+            Ok(file) => file,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            error!("Couldn't write to access log {}: {}", self.path, e);
+        }
+    }
+}
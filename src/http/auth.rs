@@ -0,0 +1,160 @@
+// Copyright (c) 2015 Guillaume Pasquet
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+// HTTP Basic authentication (RFC 7617) against an htpasswd file. Bcrypt
+// ("htpasswd -B") and SHA-1 ("htpasswd -s") entries are understood.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use bcrypt::HashParts;
+use ring::digest;
+
+use http::headers::HttpHeaders;
+
+// A user's password, as stored in the file.
+enum PasswordHash {
+    // "$2y$..." and the other bcrypt variants.
+    Bcrypt(String),
+    // SHA-1 digest of "{SHA}base64-digest".
+    Sha1(Vec<u8>),
+}
+
+impl PasswordHash {
+    fn parse(hash: &str) -> Result<PasswordHash, ()> {
+        if let Some(digest) = hash.strip_prefix("{SHA}") {
+            return STANDARD.decode(digest).map(PasswordHash::Sha1).map_err(|_| ());
+        }
+        if hash.starts_with("$2") {
+            return HashParts::from_str(hash).map(|_| PasswordHash::Bcrypt(hash.to_string())).map_err(|_| ());
+        }
+        Err(())
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        match *self {
+            PasswordHash::Bcrypt(ref hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            PasswordHash::Sha1(ref expected) => {
+                let digest = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, password.as_bytes());
+                same_digest(digest.as_ref(), expected)
+            },
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct BasicAuth {
+    realm: String,
+    users: Arc<HashMap<String, PasswordHash>>,
+}
+
+// Compares two digests without stopping at the first difference.
+fn same_digest(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+impl BasicAuth {
+    // Reads "user:$2y$..." or "user:{SHA}base64-digest" lines, '#' starting
+    // a comment.
+    pub fn parse(realm: &str, content: &str) -> Result<BasicAuth, String> {
+        let mut users = HashMap::new();
+
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.splitn(2, ':');
+            let user = fields.next().unwrap_or("");
+            let hash = match fields.next().map(PasswordHash::parse) {
+                Some(Ok(hash)) => hash,
+                _ => return Err(format!("Unsupported password hash for user {:?}, expected bcrypt or {{SHA}}", user)),
+            };
+            users.insert(user.to_string(), hash);
+        }
+
+        Ok(BasicAuth { realm: realm.to_string(), users: Arc::new(users) })
+    }
+
+    pub fn load(realm: &str, path: &str) -> Result<BasicAuth, String> {
+        let mut content = String::new();
+        match File::open(path).and_then(|mut file| file.read_to_string(&mut content)) {
+            Ok(_) => BasicAuth::parse(realm, &content),
+            Err(e) => Err(format!("Failed to read users file {}: {}", path, e)),
+        }
+    }
+
+    // The user the request authenticates as, if the credentials are right.
+    pub fn check(&self, headers: &HttpHeaders) -> Option<String> {
+        let value = headers.authorization()?;
+        let (scheme, credentials) = value.trim().split_at(value.trim().find(' ')?);
+        if !scheme.eq_ignore_ascii_case("Basic") {
+            return None;
+        }
+
+        let decoded = String::from_utf8(STANDARD.decode(credentials.trim()).ok()?).ok()?;
+        let mut credentials = decoded.splitn(2, ':');
+        let user = credentials.next()?;
+        let password = credentials.next()?;
+        if self.users.get(user)?.verify(password) {
+            Some(user.to_string())
+        } else {
+            None
+        }
+    }
+
+    // Value of the WWW-Authenticate header sent with a 401.
+    pub fn challenge(&self) -> String {
+        format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+#[test]
+fn checks_basic_credentials() {
+    // htpasswd -nbs alice secret
+    let auth = BasicAuth::parse("admin", "# users\nalice:{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=\n").ok().unwrap();
+    let check = |authorization: &str| {
+        let mut headers = HttpHeaders::new();
+        headers.add("Authorization", authorization);
+        auth.check(&headers)
+    };
+
+    assert!(check("Basic YWxpY2U6c2VjcmV0") == Some("alice".to_string()));
+    assert!(check("basic  YWxpY2U6c2VjcmV0") == Some("alice".to_string()));
+    assert!(check("Basic YWxpY2U6d3Jvbmc=") == None);
+    assert!(check("Bearer YWxpY2U6c2VjcmV0") == None);
+    assert!(BasicAuth::parse("admin", "bob:$apr1$xyz").is_err());
+    assert!(BasicAuth::parse("admin", "bob:$2y$05$xyz").is_err());
+
+    // htpasswd -nbB -C 4 bob hunter2
+    let auth = BasicAuth::parse("admin", "bob:$2y$04$5q9VR8XVRXJGdXp4F4Ox.O6LpRX0U.38W3Bq8Mk/D.1f47P5VjaeK\n").ok().unwrap();
+    let mut headers = HttpHeaders::new();
+    headers.add("Authorization", &format!("Basic {}", STANDARD.encode("bob:hunter2")));
+    assert!(auth.check(&headers) == Some("bob".to_string()));
+    headers.set("Authorization", &format!("Basic {}", STANDARD.encode("bob:hunter3")));
+    assert!(auth.check(&headers) == None);
+}
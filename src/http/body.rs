@@ -0,0 +1,335 @@
+// Copyright (c) 2015 Guillaume Pasquet
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// Streaming reader for request bodies. The body is read straight off the
+// client connection as the handler asks for it, either a fixed number of
+// bytes (Content-Length) or a sequence of chunks (Transfer-Encoding: chunked).
+
+use std::cmp;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+
+use http::headers::HttpHeaders;
+use http::protocol::HttpVersion;
+use http::response::HttpStatus;
+
+// Longest chunk-size or trailer line accepted.
+const MAX_LINE_SIZE: u64 = 8192;
+
+// Most of an unread body skipped to keep the connection open. Past that,
+// closing it is cheaper than reading what nobody asked for.
+const MAX_DRAIN_SIZE: u64 = 1024 * 1024;
+
+// Where bodies are read from: a buffered client connection that can also
+// answer interim responses.
+pub trait Connection: BufRead {
+    fn send_continue(&mut self) -> io::Result<()>;
+}
+
+impl<C: Connection + ?Sized> Connection for &mut C {
+    fn send_continue(&mut self) -> io::Result<()> {
+        (**self).send_continue()
+    }
+}
+
+impl<S: Read + Write> Connection for BufReader<S> {
+    fn send_continue(&mut self) -> io::Result<()> {
+        let interim = format!("{} {}\r\n\r\n", HttpVersion::HTTP1dot1.to_string(), HttpStatus::CONTINUE.to_string());
+        let stream = self.get_mut();
+        stream.write_all(interim.as_bytes())?;
+        stream.flush()
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum Framing {
+    NoBody,
+    Length(u64),
+    Chunked,
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum ChunkState {
+    Size,
+    Data(u64),
+    DataEnd,
+    Trailers,
+    Done,
+}
+
+pub struct Body<'a> {
+    source: Option<Box<dyn Connection + 'a>>,
+    framing: Framing,
+    remaining: u64,
+    chunk: ChunkState,
+    expect_continue: bool,
+    trailers: HttpHeaders,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+impl<'a> Body<'a> {
+    // A body that isn't attached to a connection yet.
+    pub fn detached(framing: Framing, expect_continue: bool) -> Body<'a> {
+        Body {
+            source: None,
+            framing: framing,
+            remaining: match framing {
+                Framing::Length(length) => length,
+                _ => 0,
+            },
+            chunk: ChunkState::Size,
+            expect_continue: expect_continue,
+            trailers: HttpHeaders::new(),
+        }
+    }
+
+    pub fn attach<'b>(self, source: &'b mut dyn Connection) -> Body<'b> {
+        self.attach_owned(Box::new(source))
+    }
+
+    // Same as attach, for a body that owns its connection, such as the
+    // response of an upstream server.
+    pub fn attach_owned<'b>(self, source: Box<dyn Connection + 'b>) -> Body<'b> {
+        Body {
+            source: Some(source),
+            framing: self.framing,
+            remaining: self.remaining,
+            chunk: self.chunk,
+            expect_continue: self.expect_continue,
+            trailers: self.trailers,
+        }
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    // Whether every byte of the body has been read.
+    pub fn is_complete(&self) -> bool {
+        match self.framing {
+            Framing::NoBody => true,
+            Framing::Length(_) => self.remaining == 0,
+            Framing::Chunked => self.chunk == ChunkState::Done,
+        }
+    }
+
+    // Trailer fields of a chunked body, available once it's been read.
+    pub fn trailers(&self) -> &HttpHeaders {
+        &self.trailers
+    }
+
+    // Skips whatever is left of the body so the next request can be read.
+    // Returns false when the connection can't be reused: the client is still
+    // waiting for a 100 Continue that never came, the body is broken, or
+    // more than MAX_DRAIN_SIZE of it is left.
+    pub fn finish(&mut self) -> bool {
+        if self.is_complete() {
+            return true;
+        }
+        if self.expect_continue {
+            return false;
+        }
+
+        match io::copy(&mut Read::by_ref(self).take(MAX_DRAIN_SIZE), &mut io::sink()) {
+            Ok(_) => self.is_complete(),
+            Err(_) => false,
+        }
+    }
+
+    fn source(&mut self) -> io::Result<&mut dyn Connection> {
+        match self.source {
+            Some(ref mut source) => Ok(&mut **source),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "body is not attached to a connection")),
+        }
+    }
+
+    // The client only sends the body once told to go ahead, which is done on
+    // the first read so handlers can refuse the request without reading it.
+    fn start(&mut self) -> io::Result<()> {
+        if self.expect_continue {
+            self.source()?.send_continue()?;
+            self.expect_continue = false;
+        }
+        Ok(())
+    }
+
+    fn read_data(&mut self, buf: &mut [u8], remaining: u64) -> io::Result<usize> {
+        let max = cmp::min(buf.len() as u64, remaining) as usize;
+        let len = self.source()?.read(&mut buf[..max])?;
+        if len == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed in the middle of the body"));
+        }
+        Ok(len)
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = Vec::new();
+        self.source()?.take(MAX_LINE_SIZE).read_until(b'\n', &mut line)?;
+        if line.last() != Some(&b'\n') {
+            return Err(invalid("unterminated line in chunked body"));
+        }
+
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        String::from_utf8(line).map_err(|_| invalid("invalid bytes in chunked body"))
+    }
+
+    // Chunk extensions are allowed but ignored.
+    fn read_chunk_size(&mut self) -> io::Result<u64> {
+        let line = self.read_line()?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        if size.is_empty() || size.len() > 16 {
+            return Err(invalid("invalid chunk size"));
+        }
+        u64::from_str_radix(size, 16).map_err(|_| invalid("invalid chunk size"))
+    }
+
+    fn read_trailer(&mut self) -> io::Result<bool> {
+        let line = self.read_line()?;
+        if line.is_empty() {
+            return Ok(false);
+        }
+
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        match parts.next() {
+            Some(value) if !name.is_empty() && !name.contains(|c: char| c.is_whitespace()) => {
+                self.trailers.add(name, value.trim());
+                Ok(true)
+            },
+            _ => Err(invalid("invalid trailer field")),
+        }
+    }
+
+    fn read_chunked(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            self.chunk = match self.chunk {
+                ChunkState::Size => match self.read_chunk_size()? {
+                    0 => ChunkState::Trailers,
+                    size => ChunkState::Data(size),
+                },
+                ChunkState::Data(remaining) => {
+                    let len = self.read_data(buf, remaining)?;
+                    let remaining = remaining - len as u64;
+                    self.chunk = if remaining == 0 { ChunkState::DataEnd } else { ChunkState::Data(remaining) };
+                    return Ok(len);
+                },
+                ChunkState::DataEnd => {
+                    if !self.read_line()?.is_empty() {
+                        return Err(invalid("chunk data longer than announced"));
+                    }
+                    ChunkState::Size
+                },
+                ChunkState::Trailers => {
+                    if self.read_trailer()? { ChunkState::Trailers } else { ChunkState::Done }
+                },
+                ChunkState::Done => return Ok(0),
+            };
+        }
+    }
+}
+
+impl<'a> Read for Body<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.is_complete() {
+            return Ok(0);
+        }
+        self.start()?;
+
+        match self.framing {
+            Framing::NoBody => Ok(0),
+            Framing::Length(_) => {
+                let remaining = self.remaining;
+                let len = self.read_data(buf, remaining)?;
+                self.remaining -= len as u64;
+                Ok(len)
+            },
+            Framing::Chunked => self.read_chunked(buf),
+        }
+    }
+}
+
+#[cfg(test)]
+fn read_body(framing: Framing, data: &[u8]) -> (io::Result<Vec<u8>>, Vec<u8>, Vec<(String, String)>) {
+    let mut conn = BufReader::new(io::Cursor::new(data.to_vec()));
+    let (result, trailers) = {
+        let mut body = Body::detached(framing, false).attach(&mut conn);
+        let mut content = Vec::new();
+        let result = body.read_to_end(&mut content).map(|_| content);
+        let trailers = body.trailers().iter()
+            .map(|header| (header.name.clone(), header.value.clone()))
+            .collect();
+        (result, trailers)
+    };
+
+    let mut rest = Vec::new();
+    conn.read_to_end(&mut rest).unwrap();
+    (result, rest, trailers)
+}
+
+#[test]
+fn reads_bodies_without_eating_the_next_request() {
+    let (content, rest, _) = read_body(Framing::Length(5), b"hello GET / HTTP/1.1");
+    assert!(content.unwrap() == b"hello");
+    assert!(rest == b" GET / HTTP/1.1");
+
+    let data = b"5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nX-Sum: 42\r\n\r\nGET /";
+    let (content, rest, trailers) = read_body(Framing::Chunked, data);
+    assert!(content.unwrap() == b"hello, world");
+    assert!(rest == b"GET /");
+    assert!(trailers == vec![("X-Sum".to_string(), "42".to_string())]);
+}
+
+#[test]
+fn rejects_broken_chunked_bodies() {
+    let bodies: Vec<&[u8]> = vec![
+        b"zz\r\nhello\r\n0\r\n\r\n",
+        b"3\r\nhello\r\n0\r\n\r\n",
+        b"5\r\nhel",
+    ];
+
+    for data in bodies {
+        let (content, _, _) = read_body(Framing::Chunked, data);
+        assert!(content.is_err());
+    }
+}
+
+#[test]
+fn gives_up_draining_large_bodies() {
+    let finish = |framing: Framing, data: Vec<u8>| {
+        let mut conn = BufReader::new(io::Cursor::new(data));
+        let mut body = Body::detached(framing, false).attach(&mut conn);
+        body.finish()
+    };
+
+    let size = MAX_DRAIN_SIZE as usize;
+    assert!(finish(Framing::Length(size as u64), vec![b'a'; size]));
+    assert!(!finish(Framing::Length(size as u64 + 1), vec![b'a'; size + 1]));
+
+    let mut chunked = format!("{:x}\r\n", size + 1).into_bytes();
+    chunked.extend(vec![b'a'; size + 1]);
+    chunked.extend_from_slice(b"\r\n0\r\n\r\n");
+    assert!(!finish(Framing::Chunked, chunked));
+}
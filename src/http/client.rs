@@ -0,0 +1,87 @@
+// Copyright (c) 2015 Guillaume Pasquet
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// What is known about the client at the other end of a connection.
+
+use std::net::SocketAddr;
+
+// Certificate a client authenticated with, once verified against the
+// configured CAs.
+#[derive(Clone, Debug)]
+pub struct ClientCertificate {
+    // Distinguished name in RFC 4514 form, e.g. "CN=alice,O=Example".
+    pub subject: String,
+    // DNS and URI subject alternative names.
+    pub alt_names: Vec<String>,
+}
+
+impl ClientCertificate {
+    // Whether the subject or one of the alternative names is in the list.
+    // Subjects are compared in their RFC 4514 form, names ignoring case.
+    pub fn matches(&self, allowed: &[String]) -> bool {
+        allowed.iter().any(|entry| {
+            *entry == self.subject || self.alt_names.iter().any(|name| name.eq_ignore_ascii_case(entry))
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ClientInfo {
+    // Address and port of the peer.
+    pub address: String,
+    // Whether the connection is over TLS.
+    pub secure: bool,
+    // Host name asked for with SNI.
+    pub server_name: Option<String>,
+    pub certificate: Option<ClientCertificate>,
+}
+
+impl ClientInfo {
+    pub fn new(address: &str) -> ClientInfo {
+        ClientInfo {
+            address: address.to_string(),
+            secure: false,
+            server_name: None,
+            certificate: None,
+        }
+    }
+
+    // Address of the peer without the port, which changes with every
+    // connection.
+    pub fn ip(&self) -> String {
+        match self.address.parse::<SocketAddr>() {
+            Ok(address) => address.ip().to_string(),
+            Err(_) => self.address.clone(),
+        }
+    }
+
+    pub fn scheme(&self) -> &'static str {
+        if self.secure { "https" } else { "http" }
+    }
+
+    // How the client shows up in logs: its address, followed by the subject
+    // of its certificate if it has one.
+    pub fn label(&self) -> String {
+        match self.certificate {
+            Some(ref certificate) => format!("{} [{}]", self.address, certificate.subject),
+            None => self.address.clone(),
+        }
+    }
+}
@@ -0,0 +1,427 @@
+// Copyright (c) 2015 Guillaume Pasquet
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// On-the-fly compression of response bodies. The coding is negotiated with
+// Accept-Encoding (RFC 7231, 5.3.4) and, since the compressed length isn't
+// known up front, the result is sent with the chunked transfer coding.
+
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+
+use brotli::CompressorWriter;
+use flate2::Compression;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use zstd::stream::write::Encoder as ZstdEncoder;
+
+use http::headers::HttpHeaders;
+use http::protocol::HttpVersion;
+use http::request::HttpRequest;
+use http::response::{HttpResponse, HttpStatus};
+
+// Largest chunk sent on the wire.
+const MAX_CHUNK_SIZE: usize = 65536;
+
+const DEFAULT_MIN_SIZE: u64 = 1024;
+
+const DEFAULT_TYPES: [&str; 9] = [
+    "text/*",
+    "application/javascript",
+    "application/json",
+    "application/xml",
+    "application/xhtml+xml",
+    "application/rss+xml",
+    "application/atom+xml",
+    "application/wasm",
+    "image/svg+xml",
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Coding {
+    Brotli,
+    Zstd,
+    Gzip,
+    Deflate,
+}
+
+impl Coding {
+    // Token used in Accept-Encoding and Content-Encoding.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Coding::Brotli  => "br",
+            Coding::Zstd    => "zstd",
+            Coding::Gzip    => "gzip",
+            Coding::Deflate => "deflate",
+        }
+    }
+
+    // Extension of precompressed files in that coding, if they're a thing.
+    pub fn extension(&self) -> Option<&'static str> {
+        match *self {
+            Coding::Brotli  => Some("br"),
+            Coding::Zstd    => Some("zst"),
+            Coding::Gzip    => Some("gz"),
+            Coding::Deflate => None,
+        }
+    }
+
+    // Strongest level worth using when compressing ahead of time.
+    pub fn best_level(&self) -> u32 {
+        match *self {
+            Coding::Brotli  => 11,
+            Coding::Zstd    => 19,
+            Coding::Gzip | Coding::Deflate => 9,
+        }
+    }
+}
+
+impl FromStr for Coding {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Coding, ()> {
+        match s {
+            "br"                 => Ok(Coding::Brotli),
+            "zstd"               => Ok(Coding::Zstd),
+            "gzip" | "x-gzip"    => Ok(Coding::Gzip),
+            "deflate"            => Ok(Coding::Deflate),
+            _ => Err(()),
+        }
+    }
+}
+
+// Picks the coding the client likes best among those offered, in the
+// server's order of preference when it has no favourite. None means the
+// body is sent as is.
+pub fn negotiate(headers: &HttpHeaders, offered: &[Coding]) -> Option<Coding> {
+    if !headers.contains("Accept-Encoding") {
+        return None;
+    }
+
+    let accepted = headers.get_weighted("Accept-Encoding");
+    let wildcard = accepted.iter().find(|item| item.0 == "*").map(|item| item.1);
+
+    let mut best: Option<(Coding, f32)> = None;
+    for &coding in offered {
+        let quality = accepted.iter()
+            .find(|item| Coding::from_str(&item.0) == Ok(coding))
+            .map(|item| item.1)
+            .or(wildcard)
+            .unwrap_or(0.0);
+
+        match best {
+            Some((_, best_quality)) if best_quality >= quality => {},
+            _ if quality > 0.0 => best = Some((coding, quality)),
+            _ => {},
+        }
+    }
+
+    best.map(|(coding, _)| coding)
+}
+
+#[derive(Clone)]
+pub struct CompressionSettings {
+    enabled: bool,
+    codings: Vec<Coding>,
+    types: Vec<String>,
+    min_size: u64,
+    level: u32,
+}
+
+impl CompressionSettings {
+    // Compression is off until enabled in the configuration.
+    pub fn new() -> CompressionSettings {
+        CompressionSettings {
+            enabled: false,
+            codings: vec![Coding::Brotli, Coding::Gzip, Coding::Deflate],
+            types: DEFAULT_TYPES.iter().map(|media_type| media_type.to_string()).collect(),
+            min_size: DEFAULT_MIN_SIZE,
+            level: 6,
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    // Codings offered to clients, most preferred first.
+    pub fn set_codings(&mut self, codings: Vec<Coding>) {
+        self.codings = codings;
+    }
+
+    // Media types worth compressing. "type/*" matches a whole type.
+    pub fn set_types(&mut self, types: Vec<String>) {
+        self.types = types.into_iter().map(|media_type| media_type.to_lowercase()).collect();
+    }
+
+    // Bodies smaller than this aren't worth the overhead.
+    pub fn set_min_size(&mut self, min_size: u64) {
+        self.min_size = min_size;
+    }
+
+    // From 1 (fastest) to 9 (smallest).
+    pub fn set_level(&mut self, level: u32) {
+        self.level = level;
+    }
+
+    pub fn min_size(&self) -> u64 {
+        self.min_size
+    }
+
+    pub fn level(&self) -> u32 {
+        self.level
+    }
+
+    pub fn codings(&self) -> &[Coding] {
+        &self.codings
+    }
+
+    // Whether a body of that type and length should be compressed for
+    // clients that accept it. Bodies of unknown length are streamed, so
+    // they're assumed large enough.
+    pub fn applies_to(&self, content_type: &str, length: Option<u64>) -> bool {
+        self.enabled && !self.codings.is_empty() && length.map_or(true, |length| length >= self.min_size) && self.is_compressible(content_type)
+    }
+
+    // Whether the type is in the list of compressible ones.
+    pub fn is_compressible(&self, content_type: &str) -> bool {
+        let media_type = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+        self.types.iter().any(|pattern| {
+            if pattern.ends_with("/*") {
+                media_type.starts_with(&pattern[..pattern.len() - 1])
+            } else {
+                *pattern == media_type
+            }
+        })
+    }
+}
+
+// Compresses a 200 response when its type and size call for it and the
+// client accepts one of the configured codings.
+pub fn apply(req: &HttpRequest, mut response: HttpResponse, settings: &CompressionSettings) -> HttpResponse {
+    if response.status() != HttpStatus::OK || response.headers.contains("Content-Encoding") {
+        return response;
+    }
+
+    let compressible = match response.headers.content_type() {
+        Some(content_type) => {
+            let length = if response.body().has_length() { Some(response.body().len()) } else { None };
+            settings.applies_to(content_type, length)
+        },
+        None => false,
+    };
+    if !compressible {
+        return response;
+    }
+
+    // Caches must know the body depends on Accept-Encoding even when it's
+    // sent as is.
+    if !response.headers.has_token("Vary", "Accept-Encoding") {
+        response.headers.add("Vary", "Accept-Encoding");
+    }
+
+    // Chunked transfer is needed for the compressed body, which HTTP/1.0
+    // clients don't understand.
+    if *req.version() != HttpVersion::HTTP1dot1 {
+        return response;
+    }
+
+    if let Some(coding) = negotiate(&req.headers, settings.codings()) {
+        // The compressed bytes differ from the file's, so its validator
+        // only holds weakly, and ranges over them can't be served.
+        let etag = response.headers.get("ETag").map(|etag| etag.to_string());
+        if let Some(etag) = etag {
+            if !etag.starts_with("W/") {
+                response.headers.set("ETag", &format!("W/{}", etag));
+            }
+        }
+        response.headers.remove("Accept-Ranges");
+        response.set_coding(coding, settings.level());
+    }
+
+    response
+}
+
+// Sends what is written to it as chunks (RFC 7230, 4.1), buffering small
+// writes so chunks aren't tiny.
+pub struct ChunkedWriter<W: Write> {
+    out: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(out: W) -> ChunkedWriter<W> {
+        ChunkedWriter { out: out, buf: Vec::with_capacity(MAX_CHUNK_SIZE) }
+    }
+
+    fn write_chunk(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            self.out.write_all(format!("{:x}\r\n", self.buf.len()).as_bytes())?;
+            self.out.write_all(&self.buf)?;
+            self.out.write_all(b"\r\n")?;
+            self.buf.clear();
+        }
+        Ok(())
+    }
+
+    // Sends the last chunk. No trailer fields are sent.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_chunk()?;
+        self.out.write_all(b"0\r\n\r\n")?;
+        Ok(self.out)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let len = data.len().min(MAX_CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..len]);
+        if self.buf.len() == MAX_CHUNK_SIZE {
+            self.write_chunk()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_chunk()?;
+        self.out.flush()
+    }
+}
+
+// Brotli's state is kilobytes big, so it's boxed to keep the others small.
+pub enum Encoder<W: Write> {
+    Brotli(Box<CompressorWriter<W>>),
+    Zstd(ZstdEncoder<'static, W>),
+    Gzip(GzEncoder<W>),
+    Deflate(ZlibEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    // Levels above what the coding supports are capped.
+    pub fn new(coding: Coding, level: u32, out: W) -> io::Result<Encoder<W>> {
+        let level = level.max(1).min(coding.best_level());
+        Ok(match coding {
+            Coding::Brotli  => Encoder::Brotli(Box::new(CompressorWriter::new(out, 4096, level, 22))),
+            Coding::Zstd    => Encoder::Zstd(ZstdEncoder::new(out, level as i32)?),
+            Coding::Gzip    => Encoder::Gzip(GzEncoder::new(out, Compression::new(level))),
+            // "deflate" in HTTP is the zlib format (RFC 1950).
+            Coding::Deflate => Encoder::Deflate(ZlibEncoder::new(out, Compression::new(level))),
+        })
+    }
+
+    // Writes the end of the compressed stream and hands back the output.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Brotli(mut encoder) => {
+                encoder.flush()?;
+                Ok(encoder.into_inner())
+            },
+            Encoder::Zstd(encoder) => encoder.finish(),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Deflate(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match *self {
+            Encoder::Brotli(ref mut encoder) => encoder.write(data),
+            Encoder::Zstd(ref mut encoder) => encoder.write(data),
+            Encoder::Gzip(ref mut encoder) => encoder.write(data),
+            Encoder::Deflate(ref mut encoder) => encoder.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Encoder::Brotli(ref mut encoder) => encoder.flush(),
+            Encoder::Zstd(ref mut encoder) => encoder.flush(),
+            Encoder::Gzip(ref mut encoder) => encoder.flush(),
+            Encoder::Deflate(ref mut encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[test]
+fn negotiates_codings_by_quality() {
+    let offered = [Coding::Brotli, Coding::Gzip, Coding::Deflate];
+    let negotiate_with = |value: &str| {
+        let mut headers = HttpHeaders::new();
+        headers.add("Accept-Encoding", value);
+        negotiate(&headers, &offered)
+    };
+
+    assert!(negotiate(&HttpHeaders::new(), &offered) == None);
+    assert!(negotiate_with("gzip, deflate, br") == Some(Coding::Brotli));
+    assert!(negotiate_with("gzip;q=1.0, br;q=0.5") == Some(Coding::Gzip));
+    assert!(negotiate_with("br;q=0, *;q=0.1") == Some(Coding::Gzip));
+    assert!(negotiate_with("identity") == None);
+    assert!(negotiate_with("") == None);
+}
+
+#[test]
+fn writes_compressed_chunks() {
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    let content = "hello, world. ".repeat(10000);
+    let mut encoder = Encoder::new(Coding::Gzip, 6, ChunkedWriter::new(Vec::new())).unwrap();
+    encoder.write_all(content.as_bytes()).unwrap();
+    let wire = encoder.finish().unwrap().finish().unwrap();
+    assert!(wire.ends_with(b"\r\n0\r\n\r\n"));
+
+    // Strip the chunk framing back off.
+    let mut compressed = Vec::new();
+    let mut rest = &wire[..];
+    loop {
+        let line_end = rest.iter().position(|&b| b == b'\n').unwrap();
+        let size = usize::from_str_radix(std::str::from_utf8(&rest[..line_end - 1]).unwrap(), 16).unwrap();
+        if size == 0 {
+            break;
+        }
+        compressed.extend_from_slice(&rest[line_end + 1..line_end + 1 + size]);
+        rest = &rest[line_end + 3 + size..];
+    }
+
+    let mut decoded = String::new();
+    GzDecoder::new(&compressed[..]).read_to_string(&mut decoded).unwrap();
+    assert!(decoded == content);
+}
+
+#[test]
+fn compresses_bodies_of_unknown_length() {
+    use std::io::Read;
+
+    use http::traits::FromString;
+
+    let mut settings = CompressionSettings::new();
+    settings.set_enabled(true);
+    settings.set_min_size(1000);
+    let req = HttpRequest::from_string(String::from("GET / HTTP/1.1\r\nHost: a.test\r\nAccept-Encoding: gzip")).ok().unwrap();
+    let respond = |length: Option<u64>| {
+        let response = HttpResponse::builder()
+            .header("Content-Type", "text/plain")
+            .stream(Box::new(io::repeat(b'a').take(10)), length);
+        apply(&req, response, &settings).headers.get("Content-Encoding").map(|coding| coding.to_string())
+    };
+
+    assert!(respond(None) == Some(String::from("gzip")));
+    assert!(respond(Some(10)) == None);
+}
@@ -0,0 +1,154 @@
+// Copyright (c) 2015 Guillaume Pasquet
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// Conditional requests (RFC 7232): the validators of a response are checked
+// against the request's preconditions before it is sent.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use http::date::parse_http_date;
+use http::headers::HttpHeaders;
+use http::request::{HttpMethod, HttpRequest};
+use http::response::{HttpResponse, HttpStatus};
+
+// Headers a 304 carries over from the response it replaces (RFC 7232, 4.1).
+const NOT_MODIFIED_HEADERS: [&str; 6] = [
+    "Cache-Control", "Content-Location", "ETag", "Expires", "Last-Modified", "Vary",
+];
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+}
+
+fn opaque_tag(etag: &str) -> &str {
+    etag.strip_prefix("W/").unwrap_or(etag)
+}
+
+// Strong comparison: both tags are strong and identical.
+pub fn strong_match(a: &str, b: &str) -> bool {
+    !a.starts_with("W/") && !b.starts_with("W/") && a == b
+}
+
+// Weak comparison: identical once the weakness indicators are dropped.
+pub fn weak_match(a: &str, b: &str) -> bool {
+    opaque_tag(a) == opaque_tag(b)
+}
+
+// Whether an If-Match or If-None-Match list matches the current ETag. "*"
+// matches any existing representation.
+fn list_matches(list: &[String], etag: Option<&str>, strong: bool) -> bool {
+    list.iter().any(|tag| {
+        tag == "*" || match etag {
+            Some(etag) if strong => strong_match(tag, etag),
+            Some(etag) => weak_match(tag, etag),
+            None => false,
+        }
+    })
+}
+
+fn header_date(headers: &HttpHeaders, name: &str) -> Option<u64> {
+    headers.get(name).and_then(parse_http_date).map(seconds)
+}
+
+// Evaluates the preconditions in the order given by RFC 7232, 6. Returns the
+// status to answer with instead of the response, if any.
+pub fn evaluate(method: &HttpMethod, headers: &HttpHeaders, etag: Option<&str>, last_modified: Option<SystemTime>) -> Option<HttpStatus> {
+    let last_modified = last_modified.map(seconds);
+    let safe = *method == HttpMethod::GET || *method == HttpMethod::HEAD;
+
+    if headers.contains("If-Match") {
+        if !list_matches(&headers.get_list("If-Match"), etag, true) {
+            return Some(HttpStatus::PRECONDITION_FAILED);
+        }
+    } else if let (Some(since), Some(modified)) = (header_date(headers, "If-Unmodified-Since"), last_modified) {
+        if modified > since {
+            return Some(HttpStatus::PRECONDITION_FAILED);
+        }
+    }
+
+    if headers.contains("If-None-Match") {
+        if list_matches(&headers.get_list("If-None-Match"), etag, false) {
+            return Some(if safe { HttpStatus::NOT_MODIFIED } else { HttpStatus::PRECONDITION_FAILED });
+        }
+    } else if safe {
+        // A date in the future is invalid and ignored.
+        let now = seconds(SystemTime::now());
+        if let (Some(since), Some(modified)) = (header_date(headers, "If-Modified-Since"), last_modified) {
+            if since <= now && modified <= since {
+                return Some(HttpStatus::NOT_MODIFIED);
+            }
+        }
+    }
+
+    None
+}
+
+// Replaces a successful response by a 304 or 412 when the request's
+// preconditions call for it. Other responses are left alone.
+pub fn apply(req: &HttpRequest, response: HttpResponse) -> HttpResponse {
+    let status = response.status();
+    let code = status.code();
+    if (code < 200 || code >= 300) && status != HttpStatus::REQUEST_RANGE_NOT_SATISFIABLE {
+        return response;
+    }
+
+    let last_modified = response.headers.get("Last-Modified").and_then(parse_http_date);
+    match evaluate(req.method(), &req.headers, response.headers.get("ETag"), last_modified) {
+        Some(HttpStatus::NOT_MODIFIED) => {
+            let mut builder = HttpResponse::builder().status(HttpStatus::NOT_MODIFIED);
+            for name in NOT_MODIFIED_HEADERS.iter() {
+                for value in response.headers.get_all(name) {
+                    builder = builder.header(name, value);
+                }
+            }
+            builder.build()
+        },
+        Some(status) => HttpResponse::quick_error(status, "Precondition failed".to_string()),
+        None => response,
+    }
+}
+
+#[test]
+fn evaluates_preconditions_in_order() {
+    use std::time::Duration;
+
+    let modified = Some(UNIX_EPOCH + Duration::from_secs(784111777));
+    let check = |fields: &[(&str, &str)], method: HttpMethod| {
+        let mut headers = HttpHeaders::new();
+        for &(name, value) in fields {
+            headers.add(name, value);
+        }
+        evaluate(&method, &headers, Some("\"abc\""), modified)
+    };
+
+    assert!(check(&[], HttpMethod::GET) == None);
+    assert!(check(&[("If-None-Match", "\"xyz\", W/\"abc\"")], HttpMethod::GET) == Some(HttpStatus::NOT_MODIFIED));
+    assert!(check(&[("If-None-Match", "*")], HttpMethod::PUT) == Some(HttpStatus::PRECONDITION_FAILED));
+    assert!(check(&[("If-None-Match", "\"xyz\"")], HttpMethod::GET) == None);
+    assert!(check(&[("If-Match", "W/\"abc\"")], HttpMethod::GET) == Some(HttpStatus::PRECONDITION_FAILED));
+    assert!(check(&[("If-Match", "\"abc\"")], HttpMethod::GET) == None);
+
+    // If-None-Match takes precedence over If-Modified-Since.
+    let since = "Sun, 06 Nov 1994 08:49:37 GMT";
+    assert!(check(&[("If-Modified-Since", since)], HttpMethod::GET) == Some(HttpStatus::NOT_MODIFIED));
+    assert!(check(&[("If-Modified-Since", since), ("If-None-Match", "\"xyz\"")], HttpMethod::GET) == None);
+    assert!(check(&[("If-Modified-Since", "Sat, 05 Nov 1994 08:49:37 GMT")], HttpMethod::GET) == None);
+    assert!(check(&[("If-Unmodified-Since", "Sat, 05 Nov 1994 08:49:37 GMT")], HttpMethod::GET) == Some(HttpStatus::PRECONDITION_FAILED));
+}
@@ -0,0 +1,169 @@
+// Copyright (c) 2015 Guillaume Pasquet
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// HTTP dates (RFC 7231, 7.1.1.1), always in GMT.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAYS: [&'static str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&'static str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun",
+    "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// Converts days since the epoch to a (year, month, day) civil date.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+// Converts a civil date to days since the epoch.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146097 + doe - 719468
+}
+
+fn parse_month(s: &str) -> Option<u32> {
+    MONTHS.iter().position(|month| *month == s).map(|pos| pos as u32 + 1)
+}
+
+fn parse_time(s: &str) -> Option<i64> {
+    let parts: Vec<&str> = s.split(':').collect();
+    if parts.len() != 3 || parts.iter().any(|part| part.len() != 2) {
+        return None;
+    }
+
+    let hours: i64 = parts[0].parse().ok()?;
+    let minutes: i64 = parts[1].parse().ok()?;
+    let seconds: i64 = parts[2].parse().ok()?;
+    if hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+    Some(hours * 3600 + minutes * 60 + seconds)
+}
+
+fn to_system_time(year: i64, month: u32, day: u32, seconds: i64) -> Option<SystemTime> {
+    // Four-digit years only, so a forged date can't overflow.
+    if !(1..=31).contains(&day) || !(1970..=9999).contains(&year) {
+        return None;
+    }
+    let secs = days_from_civil(year, month, day).checked_mul(86400)?.checked_add(seconds)?;
+    UNIX_EPOCH.checked_add(Duration::from_secs(secs as u64))
+}
+
+// Parses the three formats allowed by RFC 7231: IMF-fixdate, the obsolete
+// RFC 850 format and asctime.
+pub fn parse_http_date(s: &str) -> Option<SystemTime> {
+    let fields: Vec<&str> = s.split_whitespace().collect();
+
+    match fields.len() {
+        // Sun, 06 Nov 1994 08:49:37 GMT
+        6 if fields[5] == "GMT" => {
+            let day = fields[1].parse().ok()?;
+            let month = parse_month(fields[2])?;
+            let year = fields[3].parse().ok()?;
+            to_system_time(year, month, day, parse_time(fields[4])?)
+        },
+        // Sunday, 06-Nov-94 08:49:37 GMT
+        4 if fields[3] == "GMT" => {
+            let date: Vec<&str> = fields[1].split('-').collect();
+            if date.len() != 3 {
+                return None;
+            }
+            let day = date[0].parse().ok()?;
+            let month = parse_month(date[1])?;
+            let year: i64 = date[2].parse().ok()?;
+            // Two-digit years that look more than 50 years in the future are
+            // in the past century (RFC 7231, 7.1.1.1).
+            let year = if year < 70 { 2000 + year } else if year < 100 { 1900 + year } else { year };
+            to_system_time(year, month, day, parse_time(fields[2])?)
+        },
+        // Sun Nov  6 08:49:37 1994
+        5 => {
+            let month = parse_month(fields[1])?;
+            let day = fields[2].parse().ok()?;
+            let year = fields[4].parse().ok()?;
+            to_system_time(year, month, day, parse_time(fields[3])?)
+        },
+        _ => None,
+    }
+}
+
+// Formats a time as an IMF-fixdate, e.g. "Sun, 06 Nov 1994 08:49:37 GMT".
+// Times before the epoch are clamped to it.
+pub fn format_http_date(time: SystemTime) -> String {
+    let secs = match time.duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_secs() as i64,
+        Err(_) => 0,
+    };
+
+    let days = secs / 86400;
+    let rem = secs % 86400;
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+pub fn now() -> String {
+    format_http_date(SystemTime::now())
+}
+
+#[test]
+fn formats_imf_fixdate() {
+    assert!(format_http_date(UNIX_EPOCH) == "Thu, 01 Jan 1970 00:00:00 GMT");
+    assert!(format_http_date(UNIX_EPOCH + Duration::from_secs(784111777)) == "Sun, 06 Nov 1994 08:49:37 GMT");
+    assert!(format_http_date(UNIX_EPOCH + Duration::from_secs(951782400)) == "Tue, 29 Feb 2000 00:00:00 GMT");
+}
+
+#[test]
+fn parses_all_http_date_formats() {
+    let expected = Some(UNIX_EPOCH + Duration::from_secs(784111777));
+    assert!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT") == expected);
+    assert!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT") == expected);
+    assert!(parse_http_date("Sun Nov  6 08:49:37 1994") == expected);
+    assert!(parse_http_date("Sun, 06 Nov 1994 08:49:37 CET") == None);
+    assert!(parse_http_date("yesterday") == None);
+    assert!(parse_http_date("Sun, 06 Nov 300000000000 08:49:37 GMT") == None);
+    assert!(parse_http_date("Sun Nov  6 08:49:37 10000") == None);
+}
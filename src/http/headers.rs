@@ -0,0 +1,231 @@
+// Copyright (c) 2015 Guillaume Pasquet
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// Collection of HTTP header fields. Names are matched case-insensitively, the
+// order in which fields were added is kept and a name may appear many times.
+
+use std::slice;
+
+pub struct HttpHeader {
+    pub name: String,
+    pub value: String
+}
+
+pub struct HttpHeaders {
+    headers: Vec<HttpHeader>,
+}
+
+// Splits a comma-separated list value, leaving commas inside quoted strings
+// alone. Empty elements are dropped.
+fn split_list(value: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for c in value.chars() {
+        if escaped {
+            escaped = false;
+        } else if quoted && c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if c == ',' && !quoted {
+            items.push(current.trim().to_string());
+            current.clear();
+            continue;
+        }
+        current.push(c);
+    }
+    items.push(current.trim().to_string());
+
+    items.into_iter().filter(|item| !item.is_empty()).collect()
+}
+
+impl HttpHeaders {
+    pub fn new() -> HttpHeaders {
+        HttpHeaders { headers: Vec::new() }
+    }
+
+    // Appends a field, keeping any existing one with the same name.
+    pub fn add(&mut self, name: &str, value: &str) {
+        self.headers.push(HttpHeader {
+            name: name.to_string(),
+            value: value.to_string(),
+        });
+    }
+
+    // Replaces every field with that name by a single one.
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.add(name, value);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.headers.retain(|header| !header.name.eq_ignore_ascii_case(name));
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    // First value of a field.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| header.value.as_str())
+    }
+
+    // Every value of a field, in the order they were received.
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.headers.iter()
+            .filter(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| header.value.as_str())
+            .collect()
+    }
+
+    // Elements of a list-based field (RFC 7230, 7), across all its lines.
+    pub fn get_list(&self, name: &str) -> Vec<String> {
+        self.get_all(name).iter().flat_map(|value| split_list(value)).collect()
+    }
+
+    // Whether a list-based field holds a token, ignoring case.
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_list(name).iter().any(|item| item.eq_ignore_ascii_case(token))
+    }
+
+    // Elements of a list-based field with their quality value (RFC 7231,
+    // 5.3.1), lowercased and stripped of other parameters. Elements without
+    // a q parameter have a quality of 1.
+    pub fn get_weighted(&self, name: &str) -> Vec<(String, f32)> {
+        self.get_list(name).iter()
+            .map(|item| {
+                let mut parts = item.split(';');
+                let value = parts.next().unwrap_or("").trim().to_lowercase();
+                let quality = parts
+                    .filter_map(|param| {
+                        let mut param = param.splitn(2, '=');
+                        match (param.next(), param.next()) {
+                            (Some(key), Some(q)) if key.trim().eq_ignore_ascii_case("q") => q.trim().parse::<f32>().ok(),
+                            _ => None,
+                        }
+                    })
+                    .next()
+                    .unwrap_or(1.0);
+                (value, quality.max(0.0).min(1.0))
+            })
+            .collect()
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, HttpHeader> {
+        self.headers.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+
+    pub fn host(&self) -> Option<&str> {
+        self.get("Host")
+    }
+
+    pub fn user_agent(&self) -> Option<&str> {
+        self.get("User-Agent")
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.get("Content-Type")
+    }
+
+    pub fn authorization(&self) -> Option<&str> {
+        self.get("Authorization")
+    }
+
+    pub fn accept(&self) -> Vec<String> {
+        self.get_list("Accept")
+    }
+
+    pub fn connection(&self) -> Vec<String> {
+        self.get_list("Connection")
+    }
+
+    pub fn if_none_match(&self) -> Vec<String> {
+        self.get_list("If-None-Match")
+    }
+
+    // Name/value pairs of every Cookie field.
+    pub fn cookies(&self) -> Vec<(String, String)> {
+        self.get_all("Cookie").iter()
+            .flat_map(|value| value.split(';'))
+            .filter_map(|pair| {
+                let mut parts = pair.splitn(2, '=');
+                let name = parts.next().unwrap_or("").trim();
+                match parts.next() {
+                    Some(value) if !name.is_empty() => Some((name.to_string(), value.trim().to_string())),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.cookies().into_iter()
+            .find(|cookie| cookie.0 == name)
+            .map(|cookie| cookie.1)
+    }
+}
+
+impl<'a> IntoIterator for &'a HttpHeaders {
+    type Item = &'a HttpHeader;
+    type IntoIter = slice::Iter<'a, HttpHeader>;
+
+    fn into_iter(self) -> slice::Iter<'a, HttpHeader> {
+        self.headers.iter()
+    }
+}
+
+#[test]
+fn lookups_ignore_case_and_keep_order() {
+    let mut headers = HttpHeaders::new();
+    headers.add("Accept", "text/html, application/json;q=0.9");
+    headers.add("X-Tag", "one");
+    headers.add("accept", "*/*");
+    headers.add("If-None-Match", "\"a,b\", W/\"c\"");
+    headers.add("Cookie", "id=42; theme=dark");
+
+    assert!(headers.get("ACCEPT") == Some("text/html, application/json;q=0.9"));
+    assert!(headers.get_all("Accept") == vec!["text/html, application/json;q=0.9", "*/*"]);
+    assert!(headers.accept() == vec!["text/html", "application/json;q=0.9", "*/*"]);
+    assert!(headers.if_none_match() == vec!["\"a,b\"", "W/\"c\""]);
+    assert!(headers.cookie("theme") == Some("dark".to_string()));
+
+    let names: Vec<&str> = headers.iter().map(|header| header.name.as_str()).collect();
+    assert!(names == vec!["Accept", "X-Tag", "accept", "If-None-Match", "Cookie"]);
+
+    headers.add("Accept-Encoding", "gzip;q=0.5, br");
+    assert!(headers.get_weighted("accept-encoding") == vec![("gzip".to_string(), 0.5), ("br".to_string(), 1.0)]);
+
+    headers.set("x-tag", "two");
+    assert!(headers.get_all("X-Tag") == vec!["two"]);
+}
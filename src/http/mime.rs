@@ -0,0 +1,172 @@
+// Copyright (c) 2015 Guillaume Pasquet
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// Maps file extensions to media types.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+const BUILTIN_TYPES: &'static [(&'static str, &'static str)] = &[
+    ("html",  "text/html"),
+    ("htm",   "text/html"),
+    ("css",   "text/css"),
+    ("txt",   "text/plain"),
+    ("md",    "text/markdown"),
+    ("csv",   "text/csv"),
+    ("xml",   "text/xml"),
+    ("js",    "application/javascript"),
+    ("mjs",   "application/javascript"),
+    ("json",  "application/json"),
+    ("map",   "application/json"),
+    ("wasm",  "application/wasm"),
+    ("pdf",   "application/pdf"),
+    ("zip",   "application/zip"),
+    ("gz",    "application/gzip"),
+    ("tar",   "application/x-tar"),
+    ("rss",   "application/rss+xml"),
+    ("atom",  "application/atom+xml"),
+    ("xhtml", "application/xhtml+xml"),
+    ("png",   "image/png"),
+    ("jpg",   "image/jpeg"),
+    ("jpeg",  "image/jpeg"),
+    ("gif",   "image/gif"),
+    ("webp",  "image/webp"),
+    ("avif",  "image/avif"),
+    ("svg",   "image/svg+xml"),
+    ("ico",   "image/x-icon"),
+    ("bmp",   "image/bmp"),
+    ("woff",  "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf",   "font/ttf"),
+    ("otf",   "font/otf"),
+    ("mp3",   "audio/mpeg"),
+    ("ogg",   "audio/ogg"),
+    ("wav",   "audio/wav"),
+    ("mp4",   "video/mp4"),
+    ("webm",  "video/webm"),
+];
+
+#[derive(Clone)]
+pub struct MimeTypes {
+    types: HashMap<String, String>,
+    default_type: String,
+    charset: Option<String>,
+}
+
+impl MimeTypes {
+    pub fn new() -> MimeTypes {
+        let mut types = HashMap::new();
+        for &(extension, media_type) in BUILTIN_TYPES {
+            types.insert(extension.to_string(), media_type.to_string());
+        }
+
+        MimeTypes {
+            types: types,
+            default_type: "application/octet-stream".to_string(),
+            charset: None,
+        }
+    }
+
+    pub fn insert(&mut self, extension: &str, media_type: &str) {
+        self.types.insert(extension.trim_start_matches('.').to_lowercase(), media_type.to_string());
+    }
+
+    // Type used for files with an unknown or no extension.
+    pub fn set_default_type(&mut self, media_type: &str) {
+        self.default_type = media_type.to_string();
+    }
+
+    // Charset added to textual types, e.g. "utf-8".
+    pub fn set_charset(&mut self, charset: Option<String>) {
+        self.charset = charset;
+    }
+
+    // Reads entries in the /etc/mime.types format: a media type followed by
+    // its extensions on each line, '#' starting a comment.
+    pub fn load_str(&mut self, content: &str) {
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or("");
+            let mut fields = line.split_whitespace();
+
+            if let Some(media_type) = fields.next() {
+                for extension in fields {
+                    self.insert(extension, media_type);
+                }
+            }
+        }
+    }
+
+    pub fn load_file(&mut self, filename: &str) -> Result<(), String> {
+        let mut content = String::new();
+        match File::open(filename).and_then(|mut file| file.read_to_string(&mut content)) {
+            Ok(_) => {
+                self.load_str(&content);
+                Ok(())
+            },
+            Err(e) => Err(format!("Failed to read MIME types file {}: {}", filename, e)),
+        }
+    }
+
+    // Media type without parameters.
+    pub fn media_type(&self, path: &Path) -> &str {
+        let extension = path.extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase());
+
+        match extension.and_then(|extension| self.types.get(&extension)) {
+            Some(media_type) => media_type,
+            None => &self.default_type,
+        }
+    }
+
+    // Value for the Content-Type header of a file.
+    pub fn content_type(&self, path: &Path) -> String {
+        let media_type = self.media_type(path);
+
+        match self.charset {
+            Some(ref charset) if is_textual(media_type) => format!("{}; charset={}", media_type, charset),
+            _ => media_type.to_string(),
+        }
+    }
+}
+
+fn is_textual(media_type: &str) -> bool {
+    media_type.starts_with("text/")
+        || media_type.ends_with("+xml")
+        || media_type.ends_with("+json")
+        || media_type == "application/javascript"
+        || media_type == "application/json"
+}
+
+#[test]
+fn looks_up_builtin_and_loaded_types() {
+    let mut types = MimeTypes::new();
+    types.load_str("# comment\napplication/x-thing  thing thg\n\ntext/x-custom cst # trailing\n");
+    types.insert(".JS", "text/javascript");
+    types.set_charset(Some("utf-8".to_string()));
+
+    assert!(types.content_type(Path::new("/a/logo.PNG")) == "image/png");
+    assert!(types.content_type(Path::new("b.thg")) == "application/x-thing");
+    assert!(types.content_type(Path::new("c.cst")) == "text/x-custom; charset=utf-8");
+    assert!(types.content_type(Path::new("app.js")) == "text/javascript; charset=utf-8");
+    assert!(types.content_type(Path::new("README")) == "application/octet-stream");
+}
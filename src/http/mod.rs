@@ -20,5 +20,17 @@
 
 pub mod traits;
 pub mod protocol;
+pub mod date;
+pub mod headers;
+pub mod body;
+pub mod parser;
 pub mod request;
 pub mod response;
+pub mod mime;
+pub mod url;
+pub mod range;
+pub mod conditional;
+pub mod compress;
+pub mod client;
+pub mod accesslog;
+pub mod auth;
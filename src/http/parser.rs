@@ -0,0 +1,334 @@
+// Copyright (c) 2015 Guillaume Pasquet
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// Incremental parser for the head of an HTTP request (request line and
+// header fields). It is fed raw bytes as they come off the socket and keeps
+// its state between calls, so a head split over several reads is fine.
+
+use std::str;
+use std::str::FromStr;
+
+use http::protocol::HttpVersion;
+use http::headers::HttpHeaders;
+use http::request::{HttpMethod, HttpRequest, ParseError};
+
+// Heads larger than this are refused rather than buffered forever.
+pub const MAX_HEAD_SIZE: usize = 65536;
+
+pub enum ParseStatus {
+    // The head is complete. Holds the number of bytes of the last slice that
+    // belong to it; anything after that is body or the next request.
+    Complete(usize),
+    // Every byte was consumed, more are needed.
+    Partial,
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum State {
+    Start,
+    Method,
+    Target,
+    Version,
+    RequestLineEnd,
+    HeaderStart,
+    HeaderName,
+    HeaderValueStart,
+    HeaderValue,
+    HeaderValueEnd,
+    HeadEnd,
+    Done,
+}
+
+pub struct RequestParser {
+    state: State,
+    size: usize,
+    token: Vec<u8>,
+    method: Option<HttpMethod>,
+    target: String,
+    version: Option<HttpVersion>,
+    header_name: String,
+    headers: HttpHeaders,
+}
+
+fn is_tchar(b: u8) -> bool {
+    match b {
+        b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => true,
+        b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'*' | b'+' | b'-' | b'.' |
+        b'^' | b'_' | b'`' | b'|' | b'~' => true,
+        _ => false,
+    }
+}
+
+fn is_ows(b: u8) -> bool {
+    b == b' ' || b == b'\t'
+}
+
+// Visible ASCII, whitespace and obs-text are allowed in field values.
+fn is_field_byte(b: u8) -> bool {
+    is_ows(b) || (b >= 0x21 && b != 0x7f)
+}
+
+// Header values are usually ASCII. Anything that isn't valid UTF-8 is read as
+// ISO-8859-1 so no byte is lost.
+fn decode_value(raw: &[u8]) -> String {
+    match str::from_utf8(raw) {
+        Ok(value) => value.to_string(),
+        Err(_) => raw.iter().map(|&b| b as char).collect(),
+    }
+}
+
+// Only HTTP/1.x can be spoken over this framing. Other well-formed versions
+// are unsupported rather than malformed.
+fn parse_version(version: String) -> Result<HttpVersion, ParseError> {
+    match HttpVersion::from_str(&version) {
+        Ok(HttpVersion::HTTP2) => Err(ParseError::UnsupportedVersion(version)),
+        Ok(version) => Ok(version),
+        Err(_) => {
+            let well_formed = {
+                let bytes = version.as_bytes();
+                bytes.len() == 8 && version.starts_with("HTTP/")
+                    && bytes[5].is_ascii_digit() && bytes[6] == b'.' && bytes[7].is_ascii_digit()
+            };
+
+            if well_formed {
+                Err(ParseError::UnsupportedVersion(version))
+            } else {
+                Err(ParseError::Malformed)
+            }
+        }
+    }
+}
+
+impl RequestParser {
+    pub fn new() -> RequestParser {
+        RequestParser {
+            state: State::Start,
+            size: 0,
+            token: Vec::new(),
+            method: None,
+            target: String::new(),
+            version: None,
+            header_name: String::new(),
+            headers: HttpHeaders::new(),
+        }
+    }
+
+    // Feeds more bytes to the parser. Must not be called again once the head
+    // is complete.
+    pub fn parse(&mut self, data: &[u8]) -> Result<ParseStatus, ParseError> {
+        for (i, &b) in data.iter().enumerate() {
+            self.size += 1;
+            if self.size > MAX_HEAD_SIZE {
+                return Err(ParseError::HeadTooLarge);
+            }
+
+            self.step(b)?;
+
+            if self.state == State::Done {
+                return Ok(ParseStatus::Complete(i + 1));
+            }
+        }
+
+        Ok(ParseStatus::Partial)
+    }
+
+    // Builds the request once parsing is complete.
+    pub fn into_request<'a>(self) -> Result<HttpRequest<'a>, ParseError> {
+        if self.state != State::Done {
+            return Err(ParseError::Malformed);
+        }
+
+        match (self.method, self.version) {
+            (Some(method), Some(version)) => HttpRequest::new(method, self.target, version, self.headers),
+            _ => Err(ParseError::Malformed),
+        }
+    }
+
+    fn step(&mut self, b: u8) -> Result<(), ParseError> {
+        self.state = match self.state {
+            // Empty lines before the request line are tolerated.
+            State::Start => match b {
+                b'\r' | b'\n' => State::Start,
+                _ if is_tchar(b) => {
+                    self.token.push(b);
+                    State::Method
+                },
+                _ => return Err(ParseError::Malformed),
+            },
+            State::Method => match b {
+                b' ' => {
+                    let method = self.take_token()?;
+                    match HttpMethod::from_str(&method) {
+                        Ok(method) => self.method = Some(method),
+                        Err(_) => return Err(ParseError::UnknownMethod(method)),
+                    }
+                    State::Target
+                },
+                _ if is_tchar(b) => {
+                    self.token.push(b);
+                    State::Method
+                },
+                _ => return Err(ParseError::Malformed),
+            },
+            State::Target => match b {
+                b' ' if !self.token.is_empty() => {
+                    self.target = self.take_token()?;
+                    State::Version
+                },
+                0x21..=0x7e => {
+                    self.token.push(b);
+                    State::Target
+                },
+                _ => return Err(ParseError::Malformed),
+            },
+            State::Version => match b {
+                b'\r' | b'\n' => {
+                    let version = self.take_token()?;
+                    self.version = Some(parse_version(version)?);
+                    if b == b'\r' { State::RequestLineEnd } else { State::HeaderStart }
+                },
+                0x21..=0x7e => {
+                    self.token.push(b);
+                    State::Version
+                },
+                _ => return Err(ParseError::Malformed),
+            },
+            State::RequestLineEnd => match b {
+                b'\n' => State::HeaderStart,
+                _ => return Err(ParseError::Malformed),
+            },
+            State::HeaderStart => match b {
+                b'\r' => State::HeadEnd,
+                b'\n' => State::Done,
+                // Obsolete line folding (RFC 7230, 3.2.4) is rejected.
+                _ if is_ows(b) => return Err(ParseError::Malformed),
+                _ if is_tchar(b) => {
+                    self.token.push(b);
+                    State::HeaderName
+                },
+                _ => return Err(ParseError::Malformed),
+            },
+            // No whitespace is allowed between the field name and colon.
+            State::HeaderName => match b {
+                b':' => {
+                    self.header_name = self.take_token()?;
+                    State::HeaderValueStart
+                },
+                _ if is_tchar(b) => {
+                    self.token.push(b);
+                    State::HeaderName
+                },
+                _ => return Err(ParseError::Malformed),
+            },
+            State::HeaderValueStart | State::HeaderValue => match b {
+                b'\r' | b'\n' => {
+                    self.push_header();
+                    if b == b'\r' { State::HeaderValueEnd } else { State::HeaderStart }
+                },
+                _ if is_ows(b) && self.state == State::HeaderValueStart => State::HeaderValueStart,
+                _ if is_field_byte(b) => {
+                    self.token.push(b);
+                    State::HeaderValue
+                },
+                _ => return Err(ParseError::Malformed),
+            },
+            State::HeaderValueEnd => match b {
+                b'\n' => State::HeaderStart,
+                _ => return Err(ParseError::Malformed),
+            },
+            State::HeadEnd => match b {
+                b'\n' => State::Done,
+                _ => return Err(ParseError::Malformed),
+            },
+            State::Done => return Err(ParseError::Malformed),
+        };
+
+        Ok(())
+    }
+
+    // Tokens are only ever built from ASCII bytes.
+    fn take_token(&mut self) -> Result<String, ParseError> {
+        let token = self.token.split_off(0);
+        String::from_utf8(token).map_err(|_| ParseError::Malformed)
+    }
+
+    fn push_header(&mut self) {
+        let len = self.token.iter().rposition(|&b| !is_ows(b)).map_or(0, |pos| pos + 1);
+        let value = decode_value(&self.token[..len]);
+        self.token.clear();
+
+        self.headers.add(&self.header_name, &value);
+        self.header_name.clear();
+    }
+}
+
+#[test]
+fn parses_head_split_across_reads() {
+    let request = b"GET /blob HTTP/1.1\r\nHost: john.com\r\n\r\n";
+    let mut parser = RequestParser::new();
+
+    for (i, b) in request.iter().enumerate() {
+        match parser.parse(&[*b]).ok().unwrap() {
+            ParseStatus::Complete(consumed) => {
+                assert!(consumed == 1);
+                assert!(i == request.len() - 1);
+            },
+            ParseStatus::Partial => assert!(i < request.len() - 1),
+        }
+    }
+
+    let req = parser.into_request().ok().unwrap();
+    assert!(req.path == "/blob");
+}
+
+#[test]
+fn reports_bytes_consumed_by_head() {
+    let request = b"GET / HTTP/1.1\r\nReferer: http://john.com:80/ \t\r\n\r\nGET /next";
+    let mut parser = RequestParser::new();
+
+    match parser.parse(request).ok().unwrap() {
+        ParseStatus::Complete(consumed) => assert!(&request[consumed..] == b"GET /next"),
+        ParseStatus::Partial => panic!("head should be complete"),
+    }
+
+    assert!(parser.headers.get("referer") == Some("http://john.com:80/"));
+}
+
+#[test]
+fn rejects_malformed_heads() {
+    let requests: Vec<&[u8]> = vec![
+        b"GET / HTTP/1.1\r\nX-Folded: a\r\n b\r\n\r\n",
+        b"GET / HTTP/1.1\r\nHost : john.com\r\n\r\n",
+        b"GET /caf\xc3\xa9 HTTP/1.1\r\n\r\n",
+        b"GET / HTTP/1.1\r\nX-Bad: a\x00b\r\n\r\n",
+    ];
+
+    for request in requests {
+        assert!(RequestParser::new().parse(request).is_err());
+    }
+}
+
+#[test]
+fn keeps_non_utf8_header_bytes() {
+    let mut parser = RequestParser::new();
+    parser.parse(b"GET / HTTP/1.1\r\nX-Name: caf\xe9\r\n\r\n").ok().unwrap();
+
+    assert!(parser.headers.get("X-Name") == Some("caf\u{e9}"));
+}
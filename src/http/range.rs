@@ -0,0 +1,140 @@
+// Copyright (c) 2015 Guillaume Pasquet
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// Byte ranges requested with the Range header (RFC 7233).
+
+use std::str::FromStr;
+
+// Above this many ranges the header is ignored and the whole file is sent.
+const MAX_RANGES: usize = 16;
+
+// Inclusive range of byte offsets.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    // Value of the Content-Range header for this range.
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub enum RangeError {
+    // Not a byte range set we understand, the header must be ignored.
+    Invalid,
+    // None of the ranges overlap the representation.
+    Unsatisfiable,
+}
+
+fn parse_offset(s: &str) -> Result<u64, RangeError> {
+    let s = s.trim();
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(RangeError::Invalid);
+    }
+    u64::from_str(s).map_err(|_| RangeError::Invalid)
+}
+
+// Parses a Range header against a representation of `length` bytes. Ranges
+// are returned sorted, with overlapping ones merged.
+pub fn parse(header: &str, length: u64) -> Result<Vec<ByteRange>, RangeError> {
+    let header = header.trim();
+    if !header.as_bytes().get(..6).is_some_and(|unit| unit.eq_ignore_ascii_case(b"bytes=")) {
+        return Err(RangeError::Invalid);
+    }
+
+    let specs: Vec<&str> = header[6..].split(',').map(|spec| spec.trim()).filter(|spec| !spec.is_empty()).collect();
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return Err(RangeError::Invalid);
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs {
+        let dash = match spec.find('-') {
+            Some(dash) => dash,
+            None => return Err(RangeError::Invalid),
+        };
+        let (first, last) = (&spec[..dash], &spec[dash + 1..]);
+
+        if first.trim().is_empty() {
+            // Suffix range: the last N bytes.
+            let suffix = parse_offset(last)?;
+            if suffix > 0 && length > 0 {
+                let start = length.saturating_sub(suffix);
+                ranges.push(ByteRange { start: start, end: length - 1 });
+            }
+        } else {
+            let start = parse_offset(first)?;
+            let end = if last.trim().is_empty() { u64::MAX } else { parse_offset(last)? };
+            if end < start {
+                return Err(RangeError::Invalid);
+            }
+            if start < length {
+                ranges.push(ByteRange { start: start, end: end.min(length - 1) });
+            }
+        }
+    }
+
+    if ranges.is_empty() {
+        return Err(RangeError::Unsatisfiable);
+    }
+
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<ByteRange> = Vec::new();
+    for range in ranges {
+        match merged.last_mut() {
+            Some(ref mut last) if range.start <= last.end.saturating_add(1) => {
+                last.end = last.end.max(range.end);
+                continue;
+            },
+            _ => {}
+        }
+        merged.push(range);
+    }
+
+    Ok(merged)
+}
+
+#[test]
+fn parses_byte_ranges() {
+    assert!(parse("bytes=0-499", 1000).unwrap() == vec![ByteRange { start: 0, end: 499 }]);
+    assert!(parse("bytes=900-", 1000).unwrap() == vec![ByteRange { start: 900, end: 999 }]);
+    assert!(parse("bytes=-100", 1000).unwrap() == vec![ByteRange { start: 900, end: 999 }]);
+    assert!(parse("bytes=-5000", 1000).unwrap() == vec![ByteRange { start: 0, end: 999 }]);
+    assert!(parse("bytes=500-600, 0-99,550-700", 1000).unwrap() == vec![
+        ByteRange { start: 0, end: 99 },
+        ByteRange { start: 500, end: 700 },
+    ]);
+    assert!(parse("bytes=0-9999", 1000).unwrap() == vec![ByteRange { start: 0, end: 999 }]);
+
+    assert!(parse("bytes=1000-", 1000).unwrap_err() == RangeError::Unsatisfiable);
+    assert!(parse("bytes=-0", 1000).unwrap_err() == RangeError::Unsatisfiable);
+    assert!(parse("items=0-1", 1000).unwrap_err() == RangeError::Invalid);
+    assert!(parse("bytes=5-1", 1000).unwrap_err() == RangeError::Invalid);
+    assert!(parse("bytes=a-b", 1000).unwrap_err() == RangeError::Invalid);
+    assert!(parse("bytes\u{e9}=0-1", 1000).unwrap_err() == RangeError::Invalid);
+}
@@ -18,13 +18,20 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
 
+use std::fmt;
 use std::str::FromStr;
+use std::sync::Arc;
 
+use http::body::{Body, Connection, Framing};
+use http::client::ClientInfo;
+use http::headers::HttpHeaders;
+use http::parser::{ParseStatus, RequestParser};
 use http::protocol::HttpVersion;
 use http::traits::FromString;
+use http::url::{percent_decode, QueryParams, RequestTarget};
 
-#[derive(PartialEq)]
-enum HttpMethod {
+#[derive(Clone, PartialEq)]
+pub enum HttpMethod {
     OPTIONS,
     GET,
     HEAD,
@@ -68,102 +75,197 @@ impl ToString for HttpMethod {
     }
 }
 
-struct HttpHeader {
-    name: String,
-    value: String
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    // The request doesn't follow the HTTP/1.x syntax.
+    Malformed,
+    // The request line and headers exceed the parser's limit.
+    HeadTooLarge,
+    UnknownMethod(String),
+    UnsupportedVersion(String),
+    InvalidContentLength(String),
+    // Content-Length and Transfer-Encoding don't agree on the body's framing.
+    AmbiguousLength,
+    UnsupportedTransferEncoding(String),
+    UnsupportedExpectation(String),
 }
 
-impl FromStr for HttpHeader {
-    type Err = ();
-    
-    fn from_str(s: &str) -> Result<HttpHeader, ()> {
-        let header_def: Vec<&str> = s.split(": ").collect();
-        if header_def.len() < 2 {
-            return Err(());
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::Malformed                   => write!(f, "malformed request"),
+            ParseError::HeadTooLarge                => write!(f, "request head too large"),
+            ParseError::UnknownMethod(ref m)        => write!(f, "unknown method {:?}", m),
+            ParseError::UnsupportedVersion(ref v)   => write!(f, "unsupported HTTP version {:?}", v),
+            ParseError::InvalidContentLength(ref l) => write!(f, "invalid Content-Length {:?}", l),
+            ParseError::AmbiguousLength             => write!(f, "both Content-Length and Transfer-Encoding given"),
+            ParseError::UnsupportedTransferEncoding(ref t) => write!(f, "unsupported Transfer-Encoding {:?}", t),
+            ParseError::UnsupportedExpectation(ref e) => write!(f, "unsupported expectation {:?}", e),
         }
-        
-        return Ok(HttpHeader{
-            name: header_def[0].to_string(),
-            value: header_def[1].to_string(),
-        });
     }
 }
 
-pub struct HttpRequest {
+pub struct HttpRequest<'a> {
     method: HttpMethod,
+    // Request-target as received.
+    pub target: String,
+    // Path and query of the target, still percent-encoded.
     pub path: String,
+    pub query: Option<String>,
+    // Host given in an absolute-form target.
+    authority: Option<String>,
     http_version: HttpVersion,
-    host: String,
-    user_agent: String,
-    length: usize,
+    pub headers: HttpHeaders,
+    pub body: Body<'a>,
+    // Connection the request came in on.
+    client: Arc<ClientInfo>,
 }
 
-impl ToString for HttpRequest {
+impl<'a> ToString for HttpRequest<'a> {
     fn to_string(&self) -> String {
         return format!(
             "{} {} {} \"{}\"",
             self.method.to_string(),
-            self.path,
+            self.target,
             self.http_version.to_string(),
-            self.user_agent
+            self.headers.user_agent().unwrap_or("")
         );
     }
 }
 
-impl FromString for HttpRequest {
-    type Err = ();
-    
-    fn from_string(request_string: String) -> Result<HttpRequest, ()> {
-        // Slicing and dicing.
-        let mut header_lines: Vec<&str> = request_string.split("\r\n").collect();
-        
-        // Splitting the first header.
-        let first_line: Vec<&str> = header_lines[0].split(" ").collect();
-        
-        if first_line.len() < 3 {
-            return Err(());
+// Works out how the body of a request is delimited (RFC 7230, 3.3.3).
+fn body_framing(headers: &HttpHeaders) -> Result<Framing, ParseError> {
+    let codings = headers.get_list("Transfer-Encoding");
+    if !codings.is_empty() {
+        if headers.contains("Content-Length") {
+            return Err(ParseError::AmbiguousLength);
+        }
+        // Chunked is the only coding supported, and it must come last.
+        if codings.len() > 1 || !codings[0].eq_ignore_ascii_case("chunked") {
+            return Err(ParseError::UnsupportedTransferEncoding(codings.join(", ")));
         }
-        
-        let req_meth = HttpMethod::from_str(first_line[0])
-            .ok()
-            .expect("Unknown request method.");
-        let req_path = first_line[1];
-        let req_version = HttpVersion::from_str(first_line[2])
-            .ok()
-            .expect("Uknown HTTP version.");
-        
-        let mut req_host: String = "".to_string();
-        let mut req_user_agent: String = "".to_string();
-        let mut req_length = 0usize;
-        
-        // Pop the first line now.
-        header_lines.remove(0);
-        for line in &header_lines {
-            let header = HttpHeader::from_str(line);
-            match header {
-                Ok(v) => {
-                    let sname: &str = &v.name;
-                    match sname {
-                        "Host" => req_host = v.value,
-                        "User-Agent" => req_user_agent = v.value,
-                        "Content-Length" => req_length = usize::from_str(&v.value).unwrap(),
-                        _ => {}
-                    }
-                },
-                Err(e) => {
-                    // Pass.
-                }
+        return Ok(Framing::Chunked);
+    }
+
+    // Repeated lengths are only acceptable if they all agree.
+    let mut length: Option<u64> = None;
+    for value in headers.get_all("Content-Length") {
+        match u64::from_str(value) {
+            Ok(parsed) if length.is_none() || length == Some(parsed) => length = Some(parsed),
+            _ => return Err(ParseError::InvalidContentLength(value.to_string())),
+        }
+    }
+
+    match length {
+        Some(0) | None => Ok(Framing::NoBody),
+        Some(length) => Ok(Framing::Length(length)),
+    }
+}
+
+impl<'a> HttpRequest<'a> {
+    pub fn new(method: HttpMethod, target: String, version: HttpVersion, headers: HttpHeaders) -> Result<HttpRequest<'a>, ParseError> {
+        let parsed = RequestTarget::parse(&target).map_err(|_| ParseError::Malformed)?;
+        let framing = body_framing(&headers)?;
+
+        // 100-continue is the only expectation there is. HTTP/1.0 clients
+        // don't know about it, so it's ignored for them.
+        let mut expect_continue = false;
+        if let Some(expectation) = headers.get("Expect") {
+            if !expectation.eq_ignore_ascii_case("100-continue") {
+                return Err(ParseError::UnsupportedExpectation(expectation.to_string()));
             }
+            expect_continue = version != HttpVersion::HTTP1dot0 && framing != Framing::NoBody;
+        }
+
+        Ok(HttpRequest {
+            method:       method,
+            target:       target,
+            path:         parsed.path,
+            query:        parsed.query,
+            authority:    parsed.authority,
+            http_version: version,
+            headers:      headers,
+            body:         Body::detached(framing, expect_continue),
+            client:       Arc::new(ClientInfo::new("")),
+        })
+    }
+
+    // Hooks the body up to the connection the request came from.
+    pub fn attach_body<'b>(self, connection: &'b mut dyn Connection) -> HttpRequest<'b> {
+        HttpRequest {
+            method:       self.method,
+            target:       self.target,
+            path:         self.path,
+            query:        self.query,
+            authority:    self.authority,
+            http_version: self.http_version,
+            headers:      self.headers,
+            body:         self.body.attach(connection),
+            client:       self.client,
+        }
+    }
+
+    pub fn set_client(&mut self, client: Arc<ClientInfo>) {
+        self.client = client;
+    }
+
+    pub fn client(&self) -> &ClientInfo {
+        &self.client
+    }
+
+    pub fn method(&self) -> &HttpMethod {
+        &self.method
+    }
+
+    pub fn version(&self) -> &HttpVersion {
+        &self.http_version
+    }
+
+    // Path with %XX escapes decoded. None if they don't decode to UTF-8.
+    pub fn decoded_path(&self) -> Option<String> {
+        percent_decode(&self.path).ok().and_then(|bytes| String::from_utf8(bytes).ok())
+    }
+
+    pub fn query_params(&self) -> QueryParams {
+        QueryParams::parse(self.query.as_ref().map_or("", |query| query.as_str()))
+    }
+
+    // Host the request is for. An absolute-form target wins over the Host
+    // header (RFC 7230, 5.4).
+    pub fn host(&self) -> Option<&str> {
+        match self.authority {
+            Some(ref authority) => Some(authority),
+            None => self.headers.host(),
+        }
+    }
+
+    // Whether the client wants the connection kept open after the response.
+    // HTTP/1.1 defaults to persistent connections, HTTP/1.0 must opt in.
+    pub fn keep_alive(&self) -> bool {
+        if self.headers.has_token("Connection", "close") {
+            return false;
+        }
+
+        match self.http_version {
+            HttpVersion::HTTP1dot0 => self.headers.has_token("Connection", "keep-alive"),
+            _ => true,
+        }
+    }
+}
+
+impl<'a> FromString for HttpRequest<'a> {
+    type Err = ParseError;
+    
+    // Parses a complete request head. The terminating empty line is optional.
+    fn from_string(request_string: String) -> Result<HttpRequest<'a>, ParseError> {
+        let mut data = request_string.into_bytes();
+        data.extend_from_slice(b"\r\n\r\n");
+
+        let mut parser = RequestParser::new();
+        match parser.parse(&data)? {
+            ParseStatus::Complete(_) => parser.into_request(),
+            ParseStatus::Partial => Err(ParseError::Malformed),
         }
-        
-        return Ok(HttpRequest {
-            method:       req_meth,
-            path:         req_path.to_string(),
-            http_version: req_version,
-            host:         req_host,
-            user_agent:   req_user_agent,
-            length:       req_length,
-        });
     }
 }
 
@@ -176,11 +278,59 @@ fn from_string_works() {
             assert!(v.method == HttpMethod::GET);
             assert!(v.path == "/blob".to_string());
             assert!(v.http_version == HttpVersion::HTTP1dot1);
-            assert!(v.host == "john.com".to_string());
-            assert!(v.user_agent == "rust-test".to_string());
+            assert!(v.headers.host() == Some("john.com"));
+            assert!(v.headers.user_agent() == Some("rust-test"));
         },
         Err(e) => {
-            panic!(e);
+            panic!("{}", e);
         }
     }
 }
+
+#[test]
+fn keep_alive_follows_version_defaults() {
+    let cases = vec![
+        ("GET / HTTP/1.1\r\nHost: john.com", true),
+        ("GET / HTTP/1.1\r\nConnection: close", false),
+        ("GET / HTTP/1.0\r\nHost: john.com", false),
+        ("GET / HTTP/1.0\r\nConnection: Keep-Alive", true),
+    ];
+
+    for (request, expected) in cases {
+        let req = HttpRequest::from_string(request.to_string()).ok().unwrap();
+        assert!(req.keep_alive() == expected, "{}", request);
+    }
+}
+
+#[test]
+fn from_string_reports_errors() {
+    let cases = vec![
+        ("BREW /pot HTTP/1.1", ParseError::UnknownMethod("BREW".to_string())),
+        ("GET / HTTP/3.0", ParseError::UnsupportedVersion("HTTP/3.0".to_string())),
+        ("GET / HTTX/1.1", ParseError::Malformed),
+        ("GET /\r\nHost: john.com", ParseError::Malformed),
+        ("POST / HTTP/1.1\r\nContent-Length: -1", ParseError::InvalidContentLength("-1".to_string())),
+        ("POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2", ParseError::InvalidContentLength("2".to_string())),
+        ("POST / HTTP/1.1\r\nTransfer-Encoding: gzip", ParseError::UnsupportedTransferEncoding("gzip".to_string())),
+        ("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 2", ParseError::AmbiguousLength),
+        ("POST / HTTP/1.1\r\nExpect: magic", ParseError::UnsupportedExpectation("magic".to_string())),
+    ];
+
+    for (request, expected) in cases {
+        match HttpRequest::from_string(request.to_string()) {
+            Ok(_) => panic!("{} should not parse", request),
+            Err(e) => assert!(e == expected, "{}: {}", request, e),
+        }
+    }
+}
+
+#[test]
+fn absolute_form_targets_override_host() {
+    let request = "GET http://john.com/my%20file.html?v=2&v=3 HTTP/1.1\r\nHost: other.com";
+    let req = HttpRequest::from_string(request.to_string()).ok().unwrap();
+
+    assert!(req.host() == Some("john.com"));
+    assert!(req.path == "/my%20file.html");
+    assert!(req.decoded_path() == Some("/my file.html".to_string()));
+    assert!(req.query_params().get_all("v") == vec!["2", "3"]);
+}
@@ -21,12 +21,24 @@
 // This is an implementation of a standard HTTP response. To wrap data into.
 
 
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::str::FromStr;
 
+use http::compress::{ChunkedWriter, Coding, Encoder};
+use http::date;
+use http::headers::HttpHeaders;
 use http::protocol::HttpVersion;
 use http::traits::FromU16;
 
-enum HttpStatus {
+const SERVER_NAME: &'static str = concat!("irontray/", env!("CARGO_PKG_VERSION"));
+
+const CHUNK_SIZE: usize = 65536;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HttpStatus {
     // 100s
     CONTINUE,
     SWITCH_PROTO,
@@ -104,7 +116,7 @@ impl FromStr for HttpStatus {
             "203 Non-Authoritative Information"   => Ok(HttpStatus::NON_AUTHORITATIVE_INFO),
             "204 No Content"                      => Ok(HttpStatus::NO_CONTENT),
             "205 Reset Content"                   => Ok(HttpStatus::RESET_CONTENT),
-            "206 Reset Content"                   => Ok(HttpStatus::PARTIAL_CONTENT),
+            "206 Partial Content"                 => Ok(HttpStatus::PARTIAL_CONTENT),
             
             // 300s
             "300 Multiple Choices"                => Ok(HttpStatus::MULTIPLE_CHOICES),
@@ -242,7 +254,7 @@ impl ToString for HttpStatus {
             HttpStatus::NON_AUTHORITATIVE_INFO          => "203 Non-Authoritative Information".to_string(),
             HttpStatus::NO_CONTENT                      => "204 No Content".to_string(),
             HttpStatus::RESET_CONTENT                   => "205 Reset Content".to_string(),
-            HttpStatus::PARTIAL_CONTENT                 => "206 Reset Content".to_string(),
+            HttpStatus::PARTIAL_CONTENT                 => "206 Partial Content".to_string(),
             
             // 300s
             HttpStatus::MULTIPLE_CHOICES                => "300 Multiple Choices".to_string(),
@@ -296,59 +308,367 @@ impl ToString for HttpStatus {
     }
 }
 
+impl HttpStatus {
+    // Numeric status code, e.g. 404.
+    pub fn code(&self) -> u16 {
+        let status = self.to_string();
+        u16::from_str(&status[..3]).unwrap()
+    }
+
+    // Reason phrase, e.g. "Not Found".
+    pub fn reason(&self) -> String {
+        let status = self.to_string();
+        status[4..].to_string()
+    }
+
+    // Informational, 204 and 304 responses never have a body.
+    pub fn allows_body(&self) -> bool {
+        let code = self.code();
+        code >= 200 && code != 204 && code != 304
+    }
+}
+
+// Piece of a body assembled from several sources.
+pub enum Segment {
+    Bytes(Vec<u8>),
+    // Byte offset and length of a slice of the body's file.
+    FileRange(u64, u64),
+}
+
+impl Segment {
+    pub fn len(&self) -> u64 {
+        match *self {
+            Segment::Bytes(ref bytes) => bytes.len() as u64,
+            Segment::FileRange(_, length) => length,
+        }
+    }
+}
+
+pub enum ResponseBody {
+    Empty,
+    Bytes(Vec<u8>),
+    // Streamed from disk, the given number of bytes from the current offset.
+    File(File, u64),
+    // Literal bytes interleaved with slices of a file, as in a
+    // multipart/byteranges body.
+    Segments(File, Vec<Segment>),
+    // Read as it's sent, e.g. from an upstream server. Its length, when
+    // known in advance.
+    Stream(Box<dyn Read>, Option<u64>),
+}
+
+// Copies `length` bytes of the file from its current offset.
+fn copy_file<W: Write>(file: &mut File, length: u64, out: &mut W) -> io::Result<()> {
+    let mut buf = vec![0u8; cmp::min(length, CHUNK_SIZE as u64) as usize];
+    let mut remaining = length;
+
+    while remaining > 0 {
+        let max = cmp::min(remaining, CHUNK_SIZE as u64) as usize;
+        let len = match file.read(&mut buf[..max]) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while being sent")),
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        out.write_all(&buf[..len])?;
+        remaining -= len as u64;
+    }
+    Ok(())
+}
+
+// Copies a stream to its end, passing on what's read right away rather
+// than waiting for a full chunk. Returns the number of bytes copied.
+fn copy_stream<R: Read + ?Sized, W: Write>(reader: &mut R, out: &mut W) -> io::Result<u64> {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut copied = 0;
+
+    loop {
+        let len = match reader.read(&mut buf) {
+            Ok(0) => return Ok(copied),
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        out.write_all(&buf[..len])?;
+        out.flush()?;
+        copied += len as u64;
+    }
+}
+
+impl ResponseBody {
+    pub fn len(&self) -> u64 {
+        match *self {
+            ResponseBody::Empty => 0,
+            ResponseBody::Bytes(ref bytes) => bytes.len() as u64,
+            ResponseBody::File(_, length) => length,
+            ResponseBody::Segments(_, ref segments) => segments.iter().map(|segment| segment.len()).sum(),
+            ResponseBody::Stream(_, length) => length.unwrap_or(0),
+        }
+    }
+
+    // Whether the length is known before the body is sent.
+    pub fn has_length(&self) -> bool {
+        !matches!(*self, ResponseBody::Stream(_, None))
+    }
+
+    // Files are sent in fixed-size chunks so memory use doesn't depend on
+    // their size.
+    fn write_to<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        match *self {
+            ResponseBody::Empty => Ok(()),
+            ResponseBody::Bytes(ref bytes) => out.write_all(bytes),
+            ResponseBody::File(ref mut file, length) => copy_file(file, length, out),
+            ResponseBody::Segments(ref mut file, ref segments) => {
+                for segment in segments {
+                    match *segment {
+                        Segment::Bytes(ref bytes) => out.write_all(bytes)?,
+                        Segment::FileRange(offset, length) => {
+                            file.seek(SeekFrom::Start(offset))?;
+                            copy_file(file, length, out)?;
+                        },
+                    }
+                }
+                Ok(())
+            },
+            ResponseBody::Stream(ref mut reader, Some(length)) => {
+                if copy_stream(&mut reader.take(length), out)? < length {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "stream ended before the end of the body"));
+                }
+                Ok(())
+            },
+            ResponseBody::Stream(ref mut reader, None) => copy_stream(reader, out).map(|_| ()),
+        }
+    }
+}
+
 pub struct HttpResponse {
     http_version: HttpVersion,
     status: HttpStatus,
-    content_type: String,
-    length: usize,
-    content: String,
+    pub headers: HttpHeaders,
+    keep_alive: bool,
+    // Only the head is sent, as in answer to a HEAD request.
+    head_only: bool,
+    body: ResponseBody,
+    // Content coding applied to the body as it is sent, along with the
+    // compression level.
+    coding: Option<(Coding, u32)>,
 }
 
-impl ToString for HttpResponse {
-    fn to_string(&self) -> String {
-        let mut buf: String = "".to_string();
-        
-        buf = format!("{} {}", self.http_version.to_string(), self.status.to_string());
-        buf = format!("{}\r\nContent-Type: {}", buf, self.content_type);
-        buf = format!("{}\r\nContent-Length: {}", buf, self.length);
-        
-        // End of header and content.
-        buf = format!("{}\r\n\r\n{}", buf, self.content);
-        
-        return buf;
+pub struct HttpResponseBuilder {
+    response: HttpResponse,
+}
+
+impl HttpResponseBuilder {
+    pub fn status(mut self, status: HttpStatus) -> HttpResponseBuilder {
+        self.response.status = status;
+        self
+    }
+
+    // Adds a header, keeping earlier ones with the same name.
+    pub fn header(mut self, name: &str, value: &str) -> HttpResponseBuilder {
+        self.response.headers.add(name, value);
+        self
+    }
+
+    pub fn body(self, content: String) -> HttpResponse {
+        self.bytes(content.into_bytes())
+    }
+
+    pub fn bytes(mut self, content: Vec<u8>) -> HttpResponse {
+        self.response.body = ResponseBody::Bytes(content);
+        self.response
+    }
+
+    // Streams `length` bytes of the file, from its current offset.
+    pub fn file(mut self, file: File, length: u64) -> HttpResponse {
+        self.response.body = ResponseBody::File(file, length);
+        self.response
+    }
+
+    pub fn segments(mut self, file: File, segments: Vec<Segment>) -> HttpResponse {
+        self.response.body = ResponseBody::Segments(file, segments);
+        self.response
+    }
+
+    // Copies the body from a reader as it's sent. Without a length, the
+    // body is sent chunked if the connection stays open, or until it's
+    // closed otherwise.
+    pub fn stream(mut self, reader: Box<dyn Read>, length: Option<u64>) -> HttpResponse {
+        self.response.body = ResponseBody::Stream(reader, length);
+        self.response
+    }
+
+    // Finishes a response without a body.
+    pub fn build(self) -> HttpResponse {
+        self.response
     }
 }
 
 impl HttpResponse {
-    pub fn success_with_content(content: String) -> HttpResponse {
-        HttpResponse {
-            http_version: HttpVersion::HTTP1dot1,
-            status: HttpStatus::OK,
-            content_type: "text/html".to_string(),
-            length: content.len(),
-            content: content,
+    pub fn builder() -> HttpResponseBuilder {
+        HttpResponseBuilder {
+            response: HttpResponse {
+                http_version: HttpVersion::HTTP1dot1,
+                status: HttpStatus::OK,
+                headers: HttpHeaders::new(),
+                keep_alive: false,
+                head_only: false,
+                body: ResponseBody::Empty,
+                coding: None,
+            },
         }
     }
-    
+
+    pub fn status(&self) -> HttpStatus {
+        self.status
+    }
+
+    pub fn body(&self) -> &ResponseBody {
+        &self.body
+    }
+
+    // Responses close the connection unless told otherwise.
+    pub fn set_keep_alive(&mut self, keep_alive: bool) {
+        self.keep_alive = keep_alive;
+    }
+
+    // Headers are sent as they would be with the body, but the body isn't.
+    pub fn set_head_only(&mut self, head_only: bool) {
+        self.head_only = head_only;
+    }
+
+    // Compresses the body on the way out. Its length is then unknown, so it
+    // is sent chunked: only do this for HTTP/1.1 clients.
+    pub fn set_coding(&mut self, coding: Coding, level: u32) {
+        self.headers.set("Content-Encoding", coding.name());
+        self.coding = Some((coding, level));
+    }
+
+    // Headers as sent, with Date, Server, Content-Length and Connection
+    // filled in.
+    fn head_headers(&self) -> HttpHeaders {
+        let mut headers = HttpHeaders::new();
+
+        if !self.headers.contains("Date") {
+            headers.add("Date", &date::now());
+        }
+        if !self.headers.contains("Server") {
+            headers.add("Server", SERVER_NAME);
+        }
+
+        for header in self.headers.iter() {
+            headers.add(&header.name, &header.value);
+        }
+
+        headers.remove("Content-Length");
+        headers.remove("Transfer-Encoding");
+        if self.status.allows_body() {
+            if self.is_chunked() {
+                headers.add("Transfer-Encoding", "chunked");
+            } else if self.body.has_length() {
+                headers.add("Content-Length", &self.body.len().to_string());
+            }
+        }
+
+        if self.keep_alive {
+            headers.set("Connection", "keep-alive");
+        } else {
+            headers.set("Connection", "close");
+        }
+
+        headers
+    }
+
+    // Bodies whose length isn't known up front are chunked, unless the
+    // connection is closed after them.
+    fn is_chunked(&self) -> bool {
+        self.coding.is_some() || (self.keep_alive && !self.body.has_length())
+    }
+
+    pub fn head_to_string(&self) -> String {
+        let mut buf = format!("{} {}\r\n", self.http_version.to_string(), self.status.to_string());
+
+        for header in self.head_headers().iter() {
+            buf = format!("{}{}: {}\r\n", buf, header.name, header.value);
+        }
+
+        // End of header.
+        format!("{}\r\n", buf)
+    }
+
+    // Sends the whole response. The body is consumed in the process.
+    pub fn write_to<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        out.write_all(self.head_to_string().as_bytes())?;
+        if self.status.allows_body() && !self.head_only {
+            match self.coding {
+                Some((coding, level)) => {
+                    let mut encoder = Encoder::new(coding, level, ChunkedWriter::new(&mut *out))?;
+                    self.body.write_to(&mut encoder)?;
+                    encoder.finish()?.finish()?;
+                },
+                None if self.is_chunked() => {
+                    let mut chunked = ChunkedWriter::new(&mut *out);
+                    self.body.write_to(&mut chunked)?;
+                    chunked.finish()?;
+                },
+                None => self.body.write_to(out)?,
+            }
+        }
+        out.flush()
+    }
+
     // Quick way to create a 404 error.
     pub fn quick_not_found(info: String) -> HttpResponse {
-        HttpResponse {
-            http_version: HttpVersion::HTTP1dot1,
-            status: HttpStatus::NOT_FOUND,
-            content_type: "text/html".to_string(),
-            length: info.len(),
-            content: info,
-        }
+        HttpResponse::quick_error(HttpStatus::NOT_FOUND, info)
+    }
+
+    // Quick way to create any other error.
+    pub fn quick_error(status: HttpStatus, info: String) -> HttpResponse {
+        HttpResponse::builder()
+            .status(status)
+            .header("Content-Type", "text/html")
+            .body(info)
+    }
+
+    // Sends the client to another URL, with a short page linking to it for
+    // clients that don't follow redirects.
+    pub fn redirect(status: HttpStatus, location: &str) -> HttpResponse {
+        let link = location.replace('&', "&amp;").replace('"', "&quot;").replace('<', "&lt;").replace('>', "&gt;");
+        HttpResponse::builder()
+            .status(status)
+            .header("Location", location)
+            .header("Content-Type", "text/html")
+            .body(format!("<html><head><title>{0}</title></head><body><h1>{1}</h1><p>The document has moved <a href=\"{2}\">here</a>.</p></body></html>\n",
+                          status.to_string(), status.reason(), link))
     }
 
     // Quick way to create a 500 error.
     pub fn quick_server_error(info: String) -> HttpResponse {
-        HttpResponse {
-            http_version: HttpVersion::HTTP1dot1,
-            status: HttpStatus::INTERNAL_SERVER_ERROR,
-            content_type: "text/html".to_string(),
-            length: info.len(),
-            content: info,
-        }
+        HttpResponse::quick_error(HttpStatus::INTERNAL_SERVER_ERROR, info)
     }
 }
+
+#[test]
+fn builder_fills_in_standard_headers() {
+    let mut response = HttpResponse::builder()
+        .status(HttpStatus::CREATED)
+        .header("Content-Type", "text/plain")
+        .header("Set-Cookie", "a=1")
+        .header("Set-Cookie", "b=2")
+        .header("Content-Length", "999")
+        .body("hello".to_string());
+    response.set_keep_alive(true);
+
+    let mut out: Vec<u8> = Vec::new();
+    response.write_to(&mut out).unwrap();
+    let text = String::from_utf8(out).unwrap();
+    assert!(text.starts_with("HTTP/1.1 201 Created\r\nDate: "));
+    assert!(text.contains("\r\nServer: irontray/"));
+    assert!(text.contains("\r\nSet-Cookie: a=1\r\nSet-Cookie: b=2\r\n"));
+    assert!(text.contains("\r\nContent-Length: 5\r\n"));
+    assert!(text.ends_with("\r\nConnection: keep-alive\r\n\r\nhello"));
+
+    assert!(HttpStatus::CREATED.code() == 201);
+    assert!(HttpStatus::IM_A_TEAPOT.reason() == "I'm a teapot");
+    assert!(!HttpStatus::NOT_MODIFIED.allows_body());
+}
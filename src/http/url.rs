@@ -0,0 +1,212 @@
+// Copyright (c) 2015 Guillaume Pasquet
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// Helpers for request-target URLs (RFC 3986).
+
+fn hex_value(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+// Decodes %XX escapes. Fails on truncated or non-hexadecimal escapes.
+pub fn percent_decode(s: &str) -> Result<Vec<u8>, ()> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if i + 2 >= bytes.len() {
+                return Err(());
+            }
+            match (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                (Some(high), Some(low)) => decoded.push(high * 16 + low),
+                _ => return Err(()),
+            }
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    Ok(decoded)
+}
+
+// Escapes everything but unreserved characters and the delimiters allowed
+// in a path segment, e.g. to build a link to a file name.
+pub fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for &b in s.as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' |
+            b'-' | b'.' | b'_' | b'~' | b'!' | b'$' | b'&' | b'\'' |
+            b'(' | b')' | b'*' | b'+' | b',' | b';' | b'=' | b':' | b'@' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+// Percent-decodes into a string, replacing invalid UTF-8.
+fn decode_lossy(s: &str) -> String {
+    match percent_decode(s) {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        Err(_) => s.to_string(),
+    }
+}
+
+// Parts of a request-target (RFC 7230, 5.3). The path and query are kept
+// percent-encoded.
+#[derive(PartialEq, Debug)]
+pub struct RequestTarget {
+    pub authority: Option<String>,
+    pub path: String,
+    pub query: Option<String>,
+}
+
+impl RequestTarget {
+    pub fn parse(target: &str) -> Result<RequestTarget, ()> {
+        // Fragments aren't supposed to be sent, drop them if they are.
+        let target = target.split('#').next().unwrap_or("");
+
+        // Asterisk-form, for OPTIONS.
+        if target == "*" {
+            return Ok(RequestTarget { authority: None, path: "*".to_string(), query: None });
+        }
+
+        let mut authority = None;
+        let mut rest = target;
+
+        if !target.starts_with('/') {
+            let lower = target.to_lowercase();
+            let scheme_len = if lower.starts_with("http://") {
+                7
+            } else if lower.starts_with("https://") {
+                8
+            } else if !target.contains('/') && target.contains(':') {
+                // Authority-form, for CONNECT.
+                return Ok(RequestTarget { authority: Some(target.to_string()), path: String::new(), query: None });
+            } else {
+                return Err(());
+            };
+
+            // Absolute-form: the authority runs up to the path or query.
+            let after_scheme = &target[scheme_len..];
+            let end = after_scheme.find(|c| c == '/' || c == '?').unwrap_or(after_scheme.len());
+            let host = &after_scheme[..end];
+            if host.is_empty() || host.contains('@') {
+                return Err(());
+            }
+            authority = Some(host.to_string());
+            rest = &after_scheme[end..];
+        }
+
+        let (path, query) = match rest.find('?') {
+            Some(pos) => (&rest[..pos], Some(rest[pos + 1..].to_string())),
+            None => (rest, None),
+        };
+
+        Ok(RequestTarget {
+            authority: authority,
+            path: if path.is_empty() { "/".to_string() } else { path.to_string() },
+            query: query,
+        })
+    }
+}
+
+// Parameters of an application/x-www-form-urlencoded query string, decoded
+// and in their original order. A name may appear several times.
+pub struct QueryParams {
+    params: Vec<(String, String)>,
+}
+
+impl QueryParams {
+    pub fn parse(query: &str) -> QueryParams {
+        let params = query.split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let pair = pair.replace('+', " ");
+                let mut parts = pair.splitn(2, '=');
+                let name = decode_lossy(parts.next().unwrap_or(""));
+                let value = decode_lossy(parts.next().unwrap_or(""));
+                (name, value)
+            })
+            .collect();
+
+        QueryParams { params: params }
+    }
+
+    // First value of a parameter.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params.iter()
+            .find(|param| param.0 == name)
+            .map(|param| param.1.as_str())
+    }
+
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.params.iter()
+            .filter(|param| param.0 == name)
+            .map(|param| param.1.as_str())
+            .collect()
+    }
+}
+
+#[test]
+fn percent_decode_handles_escapes() {
+    assert!(percent_decode("/my%20file.html").unwrap() == b"/my file.html");
+    assert!(percent_decode("%e9%C3%A9").unwrap() == b"\xe9\xc3\xa9");
+    assert!(percent_decode("100%").is_err());
+    assert!(percent_decode("%2").is_err());
+    assert!(percent_decode("%zz").is_err());
+    assert!(percent_encode("a b/caf\u{e9}?.txt") == "a%20b%2Fcaf%C3%A9%3F.txt");
+}
+
+#[test]
+fn parses_request_targets() {
+    let target = RequestTarget::parse("/my%20file.html?v=2#top").unwrap();
+    assert!(target.authority == None);
+    assert!(target.path == "/my%20file.html");
+    assert!(target.query == Some("v=2".to_string()));
+
+    let target = RequestTarget::parse("HTTP://john.com:8000?q").unwrap();
+    assert!(target.authority == Some("john.com:8000".to_string()));
+    assert!(target.path == "/");
+    assert!(target.query == Some("q".to_string()));
+
+    assert!(RequestTarget::parse("john.com:443").unwrap().authority == Some("john.com:443".to_string()));
+    assert!(RequestTarget::parse("*").unwrap().path == "*");
+    assert!(RequestTarget::parse("ftp://john.com/").is_err());
+    assert!(RequestTarget::parse("relative/path").is_err());
+}
+
+#[test]
+fn parses_query_params() {
+    let params = QueryParams::parse("tag=a&tag=b%20c&q=x+y&empty&=anon");
+    assert!(params.get_all("tag") == vec!["a", "b c"]);
+    assert!(params.get("q") == Some("x y"));
+    assert!(params.get("empty") == Some(""));
+    assert!(params.get("") == Some("anon"));
+    assert!(params.get("missing") == None);
+}
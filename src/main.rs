@@ -18,107 +18,383 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
 
-use std::io::Write;
-use std::io::Read;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
 use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::ptr;
 use std::thread;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use std::env;
-use std::str;
-use std::path::PathBuf;
-use std::fs::File;
 extern crate getopts;
+extern crate flate2;
+extern crate brotli;
+extern crate zstd;
+extern crate rustls;
+extern crate rustls_pemfile;
+extern crate webpki;
+extern crate regex;
+extern crate ring;
+extern crate base64;
+extern crate x509_parser;
+extern crate bcrypt;
+#[cfg(unix)]
+extern crate libc;
 use getopts::Options;
 
+#[macro_use]
+extern crate log;
+extern crate syslog;
+use syslog::Facility;
+
 mod http;
-use http::request::HttpRequest;
-use http::response::HttpResponse;
-use http::traits::FromString;
+use http::client::ClientInfo;
+use http::compress;
+use http::conditional;
+use http::parser::{ParseStatus, RequestParser};
+use http::protocol::HttpVersion;
+use http::date;
+use http::request::{HttpMethod, HttpRequest, ParseError};
+use http::response::{HttpResponse, HttpStatus};
 
 mod config;
 use config::httpconfig::HttpConfig;
+use config::location::{Handler, Location};
+use config::redirect;
+use config::rewrite;
+use config::siteconfig::SiteConfig;
+use config::vhost;
 
-#[macro_use]
-extern crate log;
-use log::LogLevelFilter;
-extern crate syslog;
-use syslog::Facility;
+mod files;
+use files::path;
 
-fn serve_client(mut client: TcpStream, config: Arc<HttpConfig>) {
-    let mut buf = [0u8; 512];
-    let mut buffer = String::new();
+mod proxy;
 
-    loop {
-        let len = client.read(&mut buf[0..512])
-            .ok()
-            .expect("Couldn't read from TCP client");
+mod tls;
 
-        if len > 0 {
-            buffer = buffer + str::from_utf8(&buf).unwrap();
+// Feeds the parser with whatever the client sends until a full request head
+// is read. Bytes following the head stay buffered in `conn`. None means the
+// client went away or stayed idle for too long.
+fn read_request<'a, S: Read>(conn: &mut BufReader<S>) -> Option<Result<HttpRequest<'a>, ParseError>> {
+    let mut parser = RequestParser::new();
+
+    loop {
+        let (consumed, complete) = {
+            let data = match conn.fill_buf() {
+                Ok(data) if data.is_empty() => return None,
+                Ok(data) => data,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return None,
+            };
 
-            if buffer.contains("\r\n\r\n") {
-                // End of headers.
-                break;
+            match parser.parse(data) {
+                Ok(ParseStatus::Complete(consumed)) => (consumed, true),
+                Ok(ParseStatus::Partial) => (data.len(), false),
+                Err(e) => return Some(Err(e)),
             }
+        };
+
+        conn.consume(consumed);
+        if complete {
+            return Some(parser.into_request());
         }
     }
+}
 
-    let req = HttpRequest::from_string(buffer)
-        .ok()
-        .expect("Couldn't read request.");
-
-    let root_path: &str = *config.get_root_path();
-    let mut file_path: PathBuf = PathBuf::new();
-    file_path.push(root_path);
-    file_path.push(req.path.clone().trim_matches('/'));
-
-    if file_path.is_dir() {
-        file_path.push(*config.get_index());
-    }
-
-    let response: HttpResponse;
-    match File::open(file_path) {
-        Ok(mut file) => {
-            let mut content = "".to_string();
-            match file.read_to_string(&mut content) {
-                Ok(file_length) => {
-                    info!(
-                        "{} {} 200 {}",
-                        client.peer_addr().unwrap(),
-                        req.to_string(),
-                        file_length
-                    );
-                    response = HttpResponse::success_with_content(content);
-                },
-                Err(e) => {
-                    info!(
-                        "{} {} 404 0",
-                        client.peer_addr().unwrap(),
-                        req.to_string()
-                    );
-                    error!("Couldn't read file: {}", e.to_string());
-                    response = HttpResponse::quick_not_found("File not found!".to_string());
-                }
-            }
+fn parse_error_status(e: &ParseError) -> HttpStatus {
+    match *e {
+        ParseError::HeadTooLarge          => HttpStatus::REQUEST_HEADER_FIELDS_TOO_LARGE,
+        ParseError::UnknownMethod(_)      => HttpStatus::NOT_IMPLEMENTED,
+        ParseError::UnsupportedVersion(_) => HttpStatus::HTTP_VERSION_NOT_SUPPORTED,
+        ParseError::UnsupportedTransferEncoding(_) => HttpStatus::NOT_IMPLEMENTED,
+        ParseError::UnsupportedExpectation(_) => HttpStatus::EXPECTATION_FAILED,
+        _                                 => HttpStatus::BAD_REQUEST,
+    }
+}
+
+// Gets a freshly accepted socket ready to be served. Returns the peer's
+// address, for logging.
+fn prepare_client(client: &TcpStream, config: &HttpConfig) -> Option<String> {
+    let peer = match client.peer_addr() {
+        Ok(addr) => addr.to_string(),
+        Err(_) => return None,
+    };
+
+    let timeout = config.get_keepalive_timeout();
+    if timeout > 0 {
+        if let Err(e) = client.set_read_timeout(Some(Duration::from_secs(timeout))) {
+            error!("Couldn't set read timeout for {}: {}", peer, e);
+            return None;
+        }
+    }
+
+    Some(peer)
+}
+
+// Refuses clients of the HTTPS listener whose certificate isn't one of the
+// allowed subjects.
+fn check_client(req: &HttpRequest, config: &HttpConfig) -> Option<HttpResponse> {
+    let allowed = match config.get_tls() {
+        Some(tls_config) if req.client().secure => tls_config.get_client_subjects(),
+        _ => return None,
+    };
+
+    match req.client().certificate {
+        _ if allowed.is_empty() => None,
+        Some(ref certificate) if certificate.matches(allowed) => None,
+        _ => Some(HttpResponse::quick_error(HttpStatus::FORBIDDEN, String::from("Client certificate not allowed"))),
+    }
+}
+
+// Picks the server a request is for by its host. HTTP/1.1 requests must
+// name one (RFC 7230, 5.4), and over TLS it must be the same server as for
+// the name given with SNI.
+fn select_site<'c>(req: &HttpRequest, config: &'c HttpConfig) -> Result<&'c SiteConfig, HttpStatus> {
+    if req.headers.get_all("Host").len() > 1 {
+        return Err(HttpStatus::BAD_REQUEST);
+    }
+
+    let name = match req.host() {
+        Some(host) => match vhost::host_name(host) {
+            Some(name) => name,
+            None => return Err(HttpStatus::BAD_REQUEST),
         },
-        Err(e) => {
-            info!(
-                "{} {} 500 0",
-                client.peer_addr().unwrap(),
-                req.to_string()
-            );
-            error!("Couldn't open file: {:?}", e);
-            response = HttpResponse::quick_server_error("File not found!".to_string());
+        None if *req.version() == HttpVersion::HTTP1dot1 => return Err(HttpStatus::BAD_REQUEST),
+        None => return config.get_default_site().ok_or(HttpStatus::MISDIRECTED_REQUEST),
+    };
+
+    let found = config.find_vhost(&name);
+    if let Some(ref server_name) = req.client().server_name {
+        let expected = config.find_vhost(server_name);
+        let same = match (found, expected) {
+            (Some(found), Some(expected)) => ptr::eq(found, expected),
+            (None, None) => true,
+            _ => false,
+        };
+        if !same {
+            return Err(HttpStatus::MISDIRECTED_REQUEST);
         }
     }
-    client.write(response.to_string().as_bytes());
+
+    match found {
+        Some(vhost) => Ok(vhost.get_site()),
+        None => config.get_default_site().ok_or(HttpStatus::MISDIRECTED_REQUEST),
+    }
+}
+
+// What a location asks of clients: a certificate, credentials and one of
+// the methods it answers, in that order.
+fn check_location(req: &HttpRequest, location: &Location) -> Option<HttpResponse> {
+    if let Some(rule) = location.get_client_certificate() {
+        let allowed = match req.client().certificate {
+            Some(ref certificate) => rule.subjects.is_empty() || certificate.matches(&rule.subjects),
+            None => false,
+        };
+        if !allowed {
+            return Some(HttpResponse::quick_error(HttpStatus::FORBIDDEN, String::from("Client certificate required")));
+        }
+    }
+
+    if let Some(auth) = location.get_auth() {
+        if auth.check(&req.headers).is_none() {
+            let mut response = HttpResponse::quick_error(HttpStatus::UNAUTHORIZED, String::from("Authentication required"));
+            response.headers.set("WWW-Authenticate", &auth.challenge());
+            return Some(response);
+        }
+    }
+
+    // Allowing GET allows HEAD as well.
+    let methods = location.get_methods();
+    let head_via_get = *req.method() == HttpMethod::HEAD && methods.contains(&HttpMethod::GET);
+    if !methods.is_empty() && !methods.contains(req.method()) && !head_via_get {
+        let allow: Vec<String> = methods.iter().map(|method| method.to_string()).collect();
+        let mut response = HttpResponse::quick_error(HttpStatus::METHOD_NOT_ALLOWED, String::from("Method not allowed"));
+        response.headers.set("Allow", &allow.join(", "));
+        return Some(response);
+    }
+
+    None
+}
+
+// Headers and caching settings of a location.
+fn decorate(response: &mut HttpResponse, location: &Location) {
+    for header in location.get_headers() {
+        response.headers.set(&header.0, &header.1);
+    }
+
+    let code = response.status().code();
+    if !(200..400).contains(&code) {
+        return;
+    }
+    if let Some(expires) = location.get_expires() {
+        let time = SystemTime::now() + Duration::from_secs(expires);
+        response.headers.set("Expires", &date::format_http_date(time));
+        response.headers.set("Cache-Control", &format!("max-age={}", expires));
+    }
+    if let Some(cache_control) = location.get_cache_control() {
+        response.headers.set("Cache-Control", cache_control);
+    }
+}
+
+// Runs a request through the location of its server that matches its path,
+// if any.
+fn handle(req: &mut HttpRequest, site: &SiteConfig) -> HttpResponse {
+    let url_path = match path::normalize_path(&req.path) {
+        Ok(url_path) => url_path,
+        Err(e) => return files::serve::resolve_error_response(&e),
+    };
+
+    let location = site.find_location(&url_path);
+    if let Some(response) = location.and_then(|location| check_location(req, location)) {
+        return response;
+    }
+
+    let site = location.map_or(site, |location| location.get_site());
+    let handler = location.map_or(Handler::Static, |location| location.get_handler());
+    let file_path = location.map_or(req.path.clone(), |location| location.map_path(&url_path));
+    let mut response = match handler {
+        Handler::Static => files::serve::serve(req, site, &file_path),
+        Handler::Proxy => match location.and_then(|location| location.get_proxy()) {
+            Some(upstream) => proxy::forward::forward(req, upstream),
+            None => HttpResponse::quick_server_error(String::from("No upstream")),
+        },
+        Handler::Cgi => match location.and_then(|location| location.get_cgi()) {
+            Some(settings) => files::cgi::run(req, site, &url_path, &file_path, settings),
+            None => HttpResponse::quick_server_error(String::from("No CGI settings")),
+        },
+        Handler::Redirect => match location.and_then(|location| location.get_redirect()).and_then(|redirect| redirect.apply(req, &url_path).map(|target| (redirect.get_status(), target))) {
+            Some((status, target)) => HttpResponse::redirect(status, &target),
+            None => HttpResponse::quick_error(HttpStatus::BAD_REQUEST, String::from("Bad redirect")),
+        },
+    };
+
+    if let Some(location) = location {
+        decorate(&mut response, location);
+    }
+    // Upstreams see to their own compression and validators.
+    if handler == Handler::Proxy {
+        return response;
+    }
+    let response = compress::apply(req, response, site.get_compression());
+    conditional::apply(req, response)
+}
+
+// Serves requests until the connection closes, over a plain socket or a TLS
+// stream alike.
+fn serve_client<S: Read + Write>(client: S, info: ClientInfo, config: Arc<HttpConfig>) {
+    let peer = info.label();
+    let info = Arc::new(info);
+    let mut conn = BufReader::new(client);
+    let mut served = 0u64;
+
+    loop {
+        let req = match read_request(&mut conn) {
+            Some(Ok(req)) => req,
+            Some(Err(e)) => {
+                // There's no telling where the next request would start, so
+                // the connection is closed after the error.
+                let status = parse_error_status(&e);
+                info!("{} - {} 0", peer, status.code());
+                error!("Bad request from {}: {}", peer, e);
+
+                let mut response = HttpResponse::quick_error(status, e.to_string());
+                let _ = response.write_to(conn.get_mut());
+                return;
+            },
+            None => return,
+        };
+        served += 1;
+
+        let (mut response, keep_alive, request_line, site) = {
+            let mut req = req.attach_body(&mut conn);
+            req.set_client(info.clone());
+            let site = select_site(&req, &config);
+            let mut response = match (check_client(&req, &config), site) {
+                (Some(response), _) => response,
+                (None, Err(status)) => HttpResponse::quick_error(status, String::from("No server for this host")),
+                (None, Ok(site)) => match redirect::find(site.get_redirects(), &req) {
+                    Some((status, location)) => HttpResponse::redirect(status, &location),
+                    None => match rewrite::rewrite(site.get_rewrites(), &mut req, site.get_root_path(), site.get_symlinks(), site.get_rewrite_log()) {
+                        Ok(()) => handle(&mut req, site),
+                        Err(e) => {
+                            error!("Couldn't rewrite request from {}: {}", peer, e);
+                            HttpResponse::quick_server_error(String::from("Rewrite failed"))
+                        }
+                    },
+                },
+            };
+
+            // Whatever the handler left of the body must go before the next
+            // request can be read. HTTP/1.0 clients can't be sent chunked
+            // bodies, so one whose length isn't known ends the connection.
+            let reusable = req.body.finish();
+            let delimited = response.body().has_length() || *req.version() == HttpVersion::HTTP1dot1;
+            response.set_head_only(*req.method() == HttpMethod::HEAD);
+            (response, reusable && delimited && req.keep_alive() && served < config.get_keepalive_requests(), req.to_string(), site.ok())
+        };
+        response.set_keep_alive(keep_alive);
+
+        info!("{} {} {} {}", peer, request_line, response.status().code(), response.body().len());
+        if let Some(access_log) = site.and_then(|site| site.get_access_log()) {
+            access_log.write(&peer, &request_line, response.status().code(), response.body().len());
+        }
+        if let Err(e) = response.write_to(conn.get_mut()) {
+            error!("Couldn't write response to {}: {}", peer, e);
+            return;
+        }
+
+        if !keep_alive {
+            return;
+        }
+    }
+}
+
+// Accepts HTTPS connections. Handshakes run in the connection's own thread so
+// a slow client can't hold up the others.
+fn listen_tls(listener: TcpListener, server_config: Arc<rustls::ServerConfig>, config: Arc<HttpConfig>) {
+    for stream in listener.incoming() {
+        let conf = config.clone();
+        let server_config = server_config.clone();
+        thread::spawn(move || {
+            let stream = stream.unwrap();
+            let peer = match prepare_client(&stream, &conf) {
+                Some(peer) => peer,
+                None => return,
+            };
+
+            match tls::accept(stream, &server_config) {
+                Ok(stream) => {
+                    let mut info = ClientInfo::new(&peer);
+                    info.secure = true;
+                    info.server_name = stream.server_name();
+                    info.certificate = stream.client_certificate();
+                    serve_client(stream, info, conf)
+                },
+                Err(e) => error!("TLS handshake with {} failed: {}", peer, e),
+            }
+        });
+    }
 }
 
 fn print_usage(program: &str, opts: Options) {
-    let brief = format!("Usage: {} [options]", program);
+    let brief = format!("Usage: {0} [options]\n       {0} [options] compress <dir>", program);
     print!("{}", opts.usage(&brief));
 }
 
+// Writes the precompressed siblings of every compressible file under dir,
+// using the compression settings of the configuration.
+fn compress_command(dir: &str, config: &HttpConfig) {
+    let mut summary = files::precompressed::Summary::default();
+    match files::precompressed::compress_dir(Path::new(dir), config.get_site().get_mime_types(), config.get_site().get_compression(), &mut summary) {
+        Ok(()) => println!("{} sidecar files written, {} up to date, {} discarded as no smaller",
+                           summary.written, summary.up_to_date, summary.discarded),
+        Err(e) => {
+            error!("Couldn't compress {}: {}", dir, e);
+            println!("Couldn't compress {}: {}", dir, e);
+        }
+    }
+}
+
 fn main() {
     match syslog::init(Facility::LOG_USER, log::LogLevelFilter::Info, None) {
         Err(e) => println!("Couldn't connect to syslog! {:?}", e),
@@ -168,6 +444,15 @@ fn main() {
         config = Arc::new(HttpConfig::new_defaults().unwrap());
     }
 
+    // Subcommands run once and exit instead of serving.
+    if !matches.free.is_empty() {
+        match (matches.free[0].as_str(), matches.free.get(1)) {
+            ("compress", Some(dir)) if matches.free.len() == 2 => compress_command(dir, &config),
+            _ => print_usage(&program, opts),
+        }
+        return;
+    }
+
     let address_proto: String;
     if matches.opt_present("p") {
         let port = String::from(matches.opt_str("p").unwrap());
@@ -175,6 +460,31 @@ fn main() {
     } else {
         address_proto = format!("{}:{}", ip_address, *config.get_port());
     }
+    if let Some(tls_config) = config.get_tls() {
+        let server_config = match tls::server_config(tls_config) {
+            Ok(server_config) => server_config,
+            Err(e) => {
+                error!("{}", e);
+                println!("{}", e);
+                return;
+            }
+        };
+
+        let address = format!("{}:{}", ip_address, tls_config.get_port());
+        let listener = TcpListener::bind(address.as_str()).unwrap();
+        info!("Listening for HTTPS on {}", address);
+
+        let conf = config.clone();
+        thread::spawn(move || listen_tls(listener, server_config, conf));
+    }
+
+    for group in config.get_upstreams() {
+        if group.health_check().is_some() {
+            let group = group.clone();
+            thread::spawn(move || proxy::health::watch(group));
+        }
+    }
+
     println!("{:?}", address_proto);
     let proto: &str = &address_proto;
     let listener = TcpListener::bind(proto).unwrap();
@@ -183,8 +493,11 @@ fn main() {
 
     for stream in listener.incoming() {
         let conf = config.clone();
-        thread::spawn(|| {
-            serve_client(stream.unwrap(), conf);
+        thread::spawn(move || {
+            let stream = stream.unwrap();
+            if let Some(peer) = prepare_client(&stream, &conf) {
+                serve_client(stream, ClientInfo::new(&peer), conf);
+            }
         });
     }
 }
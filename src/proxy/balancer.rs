@@ -0,0 +1,367 @@
+// Copyright (c) 2015 Guillaume Pasquet
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// Groups of upstream servers requests are spread over, along with what is
+// known of the health of each.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use http::request::HttpRequest;
+use proxy::upstream::Upstream;
+
+// Points on the hash ring for each unit of weight.
+const RING_POINTS: usize = 100;
+
+// What requests are hashed on to stick to a member.
+#[derive(Clone, PartialEq, Debug)]
+pub enum HashKey {
+    // "ip"
+    ClientIp,
+    // "header:X-User-Id"
+    Header(String),
+    // "cookie:session"
+    Cookie(String),
+}
+
+impl FromStr for HashKey {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<HashKey, ()> {
+        match s.find(':') {
+            Some(pos) if pos + 1 < s.len() => match &s[..pos] {
+                "header" => Ok(HashKey::Header(s[pos + 1..].to_string())),
+                "cookie" => Ok(HashKey::Cookie(s[pos + 1..].to_string())),
+                _ => Err(()),
+            },
+            None if s == "ip" => Ok(HashKey::ClientIp),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Strategy {
+    // Each member in turn.
+    RoundRobin,
+    // In turn, as many times as their weight.
+    Weighted,
+    // The member with the fewest requests in flight for its weight.
+    LeastConnections,
+    // The same member for the same key, as long as it's up. Requests
+    // without the key are spread round-robin.
+    Hash(HashKey),
+}
+
+// Members failing max_fails requests in a row are left out for
+// fail_timeout. A max_fails of 0 never leaves them out.
+#[derive(Clone, Copy, Debug)]
+pub struct FailurePolicy {
+    pub max_fails: u32,
+    pub fail_timeout: Duration,
+}
+
+// Request sent to each member every interval. Members that don't answer it
+// with a 2xx or 3xx within the timeout are down until they do.
+#[derive(Clone, Debug)]
+pub struct HealthCheck {
+    pub path: String,
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+pub struct Member {
+    upstream: Upstream,
+    weight: u32,
+    // Requests being proxied to it.
+    active: AtomicUsize,
+    // Failures in a row, and until when it's left out after too many.
+    failures: Mutex<(u32, Option<Instant>)>,
+    // Outcome of the last health check.
+    healthy: AtomicBool,
+}
+
+impl Member {
+    pub fn upstream(&self) -> &Upstream {
+        &self.upstream
+    }
+
+    fn is_up(&self, now: Instant) -> bool {
+        let ejected = self.failures.lock().unwrap().1.is_some_and(|until| now < until);
+        self.healthy.load(Ordering::Relaxed) && !ejected
+    }
+
+    // Records the outcome of a health check. Returns whether it changed.
+    pub fn set_healthy(&self, healthy: bool) -> bool {
+        self.healthy.swap(healthy, Ordering::Relaxed) != healthy
+    }
+}
+
+// A member picked for a request, counted as busy with it until dropped.
+pub struct Lease {
+    member: Arc<Member>,
+    index: usize,
+}
+
+impl Lease {
+    pub fn upstream(&self) -> &Upstream {
+        &self.member.upstream
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.member.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn hash_of(value: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Value of a cookie sent with the request.
+fn cookie(req: &HttpRequest, name: &str) -> Option<String> {
+    req.headers.get_all("Cookie").iter()
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| {
+            let mut pair = pair.trim().splitn(2, '=');
+            match (pair.next(), pair.next()) {
+                (Some(key), Some(value)) if key == name => Some(value.to_string()),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+pub struct UpstreamGroup {
+    name: String,
+    strategy: Strategy,
+    members: Vec<Arc<Member>>,
+    failure_policy: FailurePolicy,
+    health_check: Option<HealthCheck>,
+    // Where the next round-robin pick starts.
+    next: AtomicUsize,
+    // Smooth weighted round-robin state, as nginx does it.
+    current_weights: Mutex<Vec<i64>>,
+    // Sorted points of the consistent hash ring, with their member.
+    ring: Vec<(u64, usize)>,
+}
+
+impl UpstreamGroup {
+    pub fn new(name: &str, strategy: Strategy, servers: Vec<(Upstream, u32)>,
+               failure_policy: FailurePolicy, health_check: Option<HealthCheck>) -> UpstreamGroup {
+        let mut ring = Vec::new();
+        if let Strategy::Hash(_) = strategy {
+            for (index, &(ref upstream, weight)) in servers.iter().enumerate() {
+                for point in 0..weight as usize * RING_POINTS {
+                    ring.push((hash_of(&format!("{}-{}", upstream.address(), point)), index));
+                }
+            }
+            ring.sort();
+        }
+
+        let members: Vec<Arc<Member>> = servers.into_iter()
+            .map(|(upstream, weight)| Arc::new(Member {
+                upstream: upstream,
+                weight: weight.max(1),
+                active: AtomicUsize::new(0),
+                failures: Mutex::new((0, None)),
+                healthy: AtomicBool::new(true),
+            }))
+            .collect();
+
+        UpstreamGroup {
+            name: name.to_string(),
+            strategy: strategy,
+            current_weights: Mutex::new(vec![0; members.len()]),
+            members: members,
+            failure_policy: failure_policy,
+            health_check: health_check,
+            next: AtomicUsize::new(0),
+            ring: ring,
+        }
+    }
+
+    // A lone server, as given with proxy_pass. It's never left out.
+    pub fn single(upstream: Upstream) -> UpstreamGroup {
+        let name = upstream.address().to_string();
+        let policy = FailurePolicy { max_fails: 0, fail_timeout: Duration::from_secs(0) };
+        UpstreamGroup::new(&name, Strategy::RoundRobin, vec![(upstream, 1)], policy, None)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn members(&self) -> &[Arc<Member>] {
+        &self.members
+    }
+
+    pub fn health_check(&self) -> Option<&HealthCheck> {
+        self.health_check.as_ref()
+    }
+
+    fn key(&self, key: &HashKey, req: &HttpRequest) -> Option<String> {
+        match *key {
+            HashKey::ClientIp => Some(req.client().ip()),
+            HashKey::Header(ref name) => req.headers.get(name).map(|value| value.to_string()),
+            HashKey::Cookie(ref name) => cookie(req, name),
+        }
+    }
+
+    // Picks a member that is up for the request, leaving out those already
+    // tried. None when there's none left.
+    pub fn pick(&self, req: &HttpRequest, tried: &[usize]) -> Option<Lease> {
+        let now = Instant::now();
+        let up: Vec<usize> = (0..self.members.len())
+            .filter(|index| !tried.contains(index) && self.members[*index].is_up(now))
+            .collect();
+        if up.is_empty() {
+            return None;
+        }
+
+        let round_robin = || up[self.next.fetch_add(1, Ordering::Relaxed) % up.len()];
+        let index = match self.strategy {
+            Strategy::RoundRobin => round_robin(),
+            Strategy::Weighted => {
+                let mut current = self.current_weights.lock().unwrap();
+                let mut total = 0;
+                for &index in &up {
+                    current[index] += self.members[index].weight as i64;
+                    total += self.members[index].weight as i64;
+                }
+                let best = up.iter().cloned().fold(up[0], |best, index| if current[index] > current[best] { index } else { best });
+                current[best] -= total;
+                best
+            },
+            Strategy::LeastConnections => {
+                // Starting at a different member each time spreads ties.
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                let load = |index: usize| (self.members[index].active.load(Ordering::Relaxed), self.members[index].weight as usize);
+                (0..up.len()).map(|offset| up[(start + offset) % up.len()])
+                    .min_by(|&a, &b| {
+                        let ((active_a, weight_a), (active_b, weight_b)) = (load(a), load(b));
+                        (active_a * weight_b).cmp(&(active_b * weight_a))
+                    })
+                    .unwrap()
+            },
+            Strategy::Hash(ref key) => match self.key(key, req) {
+                Some(value) => {
+                    // The first point after the key's hash whose member is
+                    // up, so only keys of a member that goes down move.
+                    let start = self.ring.partition_point(|point| point.0 < hash_of(&value));
+                    (0..self.ring.len()).map(|offset| self.ring[(start + offset) % self.ring.len()].1)
+                        .find(|index| up.contains(index))
+                        .unwrap()
+                },
+                None => round_robin(),
+            },
+        };
+
+        let member = self.members[index].clone();
+        member.active.fetch_add(1, Ordering::Relaxed);
+        Some(Lease { member: member, index: index })
+    }
+
+    // Records a request that failed before the member gave a response. A
+    // group's only member is never left out, there'd be nothing left.
+    pub fn failed(&self, index: usize) {
+        let policy = self.failure_policy;
+        if policy.max_fails == 0 || self.members.len() == 1 {
+            return;
+        }
+
+        let member = &self.members[index];
+        let mut failures = member.failures.lock().unwrap();
+        failures.0 += 1;
+        if failures.0 >= policy.max_fails {
+            failures.0 = 0;
+            failures.1 = Some(Instant::now() + policy.fail_timeout);
+            error!("Upstream {} of {} failed {} times, leaving it out for {}s",
+                   member.upstream.address(), self.name, policy.max_fails, policy.fail_timeout.as_secs());
+        }
+    }
+
+    pub fn succeeded(&self, index: usize) {
+        self.members[index].failures.lock().unwrap().0 = 0;
+    }
+}
+
+#[test]
+fn spreads_requests_by_strategy() {
+    use http::client::ClientInfo;
+    use http::traits::FromString;
+    use proxy::upstream::UpstreamAddress;
+
+    let group = |strategy: Strategy, weights: &[u32]| {
+        let servers = weights.iter().enumerate()
+            .map(|(index, &weight)| {
+                let address = UpstreamAddress::Tcp(format!("10.0.0.{}", index), 80);
+                (Upstream::new(address, Duration::from_secs(1), Duration::from_secs(1)), weight)
+            })
+            .collect();
+        UpstreamGroup::new("test", strategy, servers, FailurePolicy { max_fails: 2, fail_timeout: Duration::from_secs(60) }, None)
+    };
+    let request = |head: &str| {
+        let mut req = HttpRequest::from_string(format!("GET / HTTP/1.1\r\nHost: a.test\r\n{}", head)).ok().unwrap();
+        req.set_client(Arc::new(ClientInfo::new("192.0.2.1:4000")));
+        req
+    };
+    let req = request("");
+    let picks = |group: &UpstreamGroup, req: &HttpRequest, count: usize| -> Vec<usize> {
+        (0..count).map(|_| group.pick(req, &[]).unwrap().index()).collect()
+    };
+
+    let round_robin = group(Strategy::RoundRobin, &[5, 1, 1]);
+    assert!(picks(&round_robin, &req, 6) == vec![0, 1, 2, 0, 1, 2]);
+
+    let weighted = group(Strategy::Weighted, &[5, 1, 1]);
+    assert!(picks(&weighted, &req, 7) == vec![0, 0, 1, 0, 2, 0, 0]);
+
+    let least = group(Strategy::LeastConnections, &[1, 1]);
+    let busy = least.pick(&req, &[]).unwrap();
+    assert!(picks(&least, &req, 3).iter().all(|&index| index != busy.index()));
+
+    let hash = group(Strategy::Hash(HashKey::from_str("cookie:session").unwrap()), &[1, 1, 1]);
+    let sticky = request("Cookie: theme=dark; session=abc123");
+    let first = hash.pick(&sticky, &[]).unwrap().index();
+    assert!(picks(&hash, &sticky, 5).iter().all(|&index| index == first));
+    // Only the keys of a member that's left out move.
+    hash.failed(first);
+    hash.failed(first);
+    let moved = hash.pick(&sticky, &[]).unwrap().index();
+    assert!(moved != first && picks(&hash, &sticky, 5).iter().all(|&index| index == moved));
+
+    round_robin.members()[0].set_healthy(false);
+    round_robin.members()[1].set_healthy(false);
+    assert!(picks(&round_robin, &req, 2) == vec![2, 2]);
+    assert!(round_robin.pick(&req, &[2]).is_none());
+    assert!(HashKey::from_str("header:").is_err() && HashKey::from_str("ip") == Ok(HashKey::ClientIp));
+}
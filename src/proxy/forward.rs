@@ -0,0 +1,630 @@
+// Copyright (c) 2015 Guillaume Pasquet
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+// Passes a request on to an upstream server and streams its response back
+// to the client.
+
+use std::cell::Cell;
+use std::fmt;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::rc::Rc;
+
+use http::body::{Body, Connection, Framing};
+use http::compress::ChunkedWriter;
+use http::headers::HttpHeaders;
+use http::parser::MAX_HEAD_SIZE;
+use http::request::{HttpMethod, HttpRequest};
+use http::response::{HttpResponse, HttpStatus};
+use http::traits::FromU16;
+use proxy::balancer::{Lease, UpstreamGroup};
+use proxy::upstream::{Upstream, UpstreamConnection};
+
+// Fields that only concern one connection (RFC 7230, 6.1), besides those
+// the Connection header names.
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "Connection", "Keep-Alive", "Proxy-Connection", "Proxy-Authenticate",
+    "Proxy-Authorization", "TE", "Trailer", "Transfer-Encoding", "Upgrade",
+];
+
+// Set from the client's certificate, never taken from the client.
+const CLIENT_CERT_SUBJECT: &str = "X-Client-Cert-Subject";
+const CLIENT_CERT_SAN: &str = "X-Client-Cert-SAN";
+
+const BUFFER_SIZE: usize = 16384;
+
+#[derive(Debug)]
+enum ProxyError {
+    Connect(io::Error),
+    // Writing the request upstream failed.
+    Send(io::Error),
+    // Reading the response failed or timed out.
+    Receive(io::Error),
+    // Reading the request body from the client failed.
+    Client(io::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProxyError::Connect(ref e) => write!(f, "couldn't connect: {}", e),
+            ProxyError::Send(ref e) => write!(f, "couldn't send the request: {}", e),
+            ProxyError::Receive(ref e) => write!(f, "couldn't read the response: {}", e),
+            ProxyError::Client(ref e) => write!(f, "couldn't read the request body: {}", e),
+            ProxyError::Invalid(ref message) => write!(f, "invalid response: {}", message),
+        }
+    }
+}
+
+impl ProxyError {
+    // Only a server that took too long to answer gets a 504; one that
+    // reset the connection or closed it early gets a 502 like the rest.
+    fn response(&self) -> HttpResponse {
+        match *self {
+            ProxyError::Receive(ref e) if matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock) => {
+                HttpResponse::quick_error(HttpStatus::GATEWAY_TIMEOUT, String::from("Gateway timeout"))
+            },
+            ProxyError::Connect(_) | ProxyError::Send(_) | ProxyError::Receive(_) | ProxyError::Invalid(_) => {
+                HttpResponse::quick_error(HttpStatus::BAD_GATEWAY, String::from("Bad gateway"))
+            },
+            ProxyError::Client(_) => HttpResponse::quick_error(HttpStatus::BAD_REQUEST, String::from("Broken request body")),
+        }
+    }
+
+    // Whether the server closed the connection before answering, as it does
+    // with an idle one it timed out just as the request went out.
+    fn is_closed_connection(&self) -> bool {
+        match *self {
+            ProxyError::Send(ref e) | ProxyError::Receive(ref e) => matches!(e.kind(),
+                io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset |
+                io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe),
+            _ => false,
+        }
+    }
+}
+
+// The connection a response is read from, which keeps its member busy until
+// it's dropped. It then goes back to the member's pool if the server keeps
+// it open and the whole body was read, with nothing after it.
+struct PooledConnection {
+    conn: Option<BufReader<UpstreamConnection>>,
+    keep_alive: bool,
+    complete: Rc<Cell<bool>>,
+    lease: Lease,
+}
+
+impl Read for PooledConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.conn.as_mut().unwrap().read(buf)
+    }
+}
+
+impl BufRead for PooledConnection {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.conn.as_mut().unwrap().fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.conn.as_mut().unwrap().consume(amount)
+    }
+}
+
+impl Connection for PooledConnection {
+    // Responses have no interim responses to send back.
+    fn send_continue(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            if self.keep_alive && self.complete.get() && conn.buffer().is_empty() {
+                self.lease.upstream().checkin(conn.into_inner());
+            }
+        }
+    }
+}
+
+// A response body, which lets its connection know once it's been read in
+// full.
+struct ResponseBody {
+    body: Body<'static>,
+    complete: Rc<Cell<bool>>,
+}
+
+impl Read for ResponseBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.body.read(buf)?;
+        // Bodies of a known length aren't read past their last byte.
+        if self.body.is_complete() {
+            self.complete.set(true);
+        }
+        Ok(len)
+    }
+}
+
+// Removes the hop-by-hop fields of a message.
+fn strip_hop_by_hop(headers: &mut HttpHeaders) {
+    for name in headers.get_list("Connection") {
+        headers.remove(&name);
+    }
+    for name in HOP_BY_HOP_HEADERS.iter() {
+        headers.remove(name);
+    }
+}
+
+// Certificate fields come from the client, keep them from splitting the
+// header.
+fn header_safe(value: &str) -> String {
+    value.chars().filter(|c| !c.is_control()).collect()
+}
+
+// Request line and headers sent upstream: the client's, without what only
+// concerned its connection, and with where the request came from.
+fn request_head(req: &HttpRequest, upstream: &Upstream) -> String {
+    let client = req.client();
+    let target = match req.query {
+        Some(ref query) => format!("{}?{}", req.path, query),
+        None => req.path.clone(),
+    };
+
+    let mut headers = HttpHeaders::new();
+    for header in req.headers.iter() {
+        headers.add(&header.name, &header.value);
+    }
+    strip_hop_by_hop(&mut headers);
+    // 100-continue is dealt with on the client's side.
+    headers.remove("Expect");
+    headers.remove(CLIENT_CERT_SUBJECT);
+    headers.remove(CLIENT_CERT_SAN);
+
+    let host = match req.host() {
+        Some(host) => host.to_string(),
+        None => upstream.address().host(),
+    };
+    headers.set("Host", &host);
+
+    let ip = client.ip();
+    let mut forwarded_for = headers.get_list("X-Forwarded-For");
+    forwarded_for.push(ip.clone());
+    headers.set("X-Forwarded-For", &forwarded_for.join(", "));
+    headers.set("X-Forwarded-Proto", client.scheme());
+    if let Some(host) = req.host() {
+        headers.set("X-Forwarded-Host", host);
+    }
+
+    // RFC 7239: IPv6 addresses are bracketed and quoted.
+    let node = if ip.contains(':') { format!("\"[{}]\"", ip) } else { ip };
+    let mut forwarded = headers.get_list("Forwarded");
+    forwarded.push(format!("for={};proto={};host=\"{}\"", node, client.scheme(), host.replace('\\', "\\\\").replace('"', "\\\"")));
+    headers.set("Forwarded", &forwarded.join(", "));
+
+    if let Some(ref certificate) = client.certificate {
+        headers.set(CLIENT_CERT_SUBJECT, &header_safe(&certificate.subject));
+        if !certificate.alt_names.is_empty() {
+            headers.set(CLIENT_CERT_SAN, &header_safe(&certificate.alt_names.join(", ")));
+        }
+    }
+
+    // Content-Length is kept as is, a chunked body is sent chunked again.
+    if req.body.framing() == Framing::Chunked {
+        headers.add("Transfer-Encoding", "chunked");
+    }
+    if !upstream.keeps_alive() {
+        headers.add("Connection", "close");
+    }
+
+    let mut head = format!("{} {} HTTP/1.1\r\n", req.method().to_string(), target);
+    for header in headers.iter() {
+        head.push_str(&format!("{}: {}\r\n", header.name, header.value));
+    }
+    head.push_str("\r\n");
+    head
+}
+
+// Copies the request body upstream as the client sends it.
+fn send_body(body: &mut Body, out: &mut dyn Write) -> Result<(), ProxyError> {
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    loop {
+        let len = match body.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(ProxyError::Client(e)),
+        };
+        out.write_all(&buf[..len]).map_err(ProxyError::Send)?;
+        out.flush().map_err(ProxyError::Send)?;
+    }
+}
+
+fn send_request(req: &mut HttpRequest, upstream: &Upstream, stream: &mut UpstreamConnection) -> Result<(), ProxyError> {
+    stream.write_all(request_head(req, upstream).as_bytes()).map_err(ProxyError::Send)?;
+
+    match req.body.framing() {
+        Framing::NoBody => {},
+        Framing::Length(_) => send_body(&mut req.body, stream)?,
+        Framing::Chunked => {
+            let mut chunked = ChunkedWriter::new(&mut *stream);
+            send_body(&mut req.body, &mut chunked)?;
+            chunked.finish().map_err(ProxyError::Send)?;
+        },
+    }
+    stream.flush().map_err(ProxyError::Send)
+}
+
+// Reads a line of the response head, without its line ending.
+fn read_line<R: BufRead>(conn: &mut R, size: &mut usize) -> Result<String, ProxyError> {
+    let mut line = Vec::new();
+    conn.take((MAX_HEAD_SIZE - *size) as u64 + 1).read_until(b'\n', &mut line).map_err(ProxyError::Receive)?;
+    *size += line.len();
+
+    if line.is_empty() && *size == 0 {
+        return Err(ProxyError::Receive(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before the response")));
+    }
+    if *size > MAX_HEAD_SIZE || line.pop() != Some(b'\n') {
+        return Err(ProxyError::Invalid(String::from("head too large or cut short")));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    if line.contains(&b'\r') {
+        return Err(ProxyError::Invalid(String::from("stray carriage return")));
+    }
+    // Non-UTF-8 values are read as ISO-8859-1, as the request parser does.
+    Ok(match String::from_utf8(line) {
+        Ok(line) => line,
+        Err(e) => e.into_bytes().iter().map(|&b| b as char).collect(),
+    })
+}
+
+// Status and headers of the response, skipping interim 1xx responses, and
+// whether the server keeps the connection open after it.
+fn read_head<R: BufRead>(conn: &mut R) -> Result<(HttpStatus, HttpHeaders, bool), ProxyError> {
+    loop {
+        let mut size = 0;
+        let status_line = read_line(conn, &mut size)?;
+        let mut parts = status_line.splitn(3, ' ');
+        let (version, code) = match (parts.next(), parts.next()) {
+            (Some(version), Some(code)) if version.starts_with("HTTP/1.") && code.len() == 3 => {
+                (version, code.parse::<u16>().map_err(|_| ProxyError::Invalid(format!("status line {:?}", status_line)))?)
+            },
+            _ => return Err(ProxyError::Invalid(format!("status line {:?}", status_line))),
+        };
+
+        let mut headers = HttpHeaders::new();
+        loop {
+            let line = read_line(conn, &mut size)?;
+            if line.is_empty() {
+                break;
+            }
+            let mut field = line.splitn(2, ':');
+            let name = field.next().unwrap_or("");
+            match field.next() {
+                Some(value) if !name.is_empty() && !name.contains(|c: char| c.is_whitespace()) => headers.add(name, value.trim()),
+                _ => return Err(ProxyError::Invalid(format!("header line {:?}", line))),
+            }
+        }
+
+        match code {
+            101 => return Err(ProxyError::Invalid(String::from("protocol upgrades aren't supported"))),
+            100..=199 => continue,
+            200..=599 => {},
+            _ => return Err(ProxyError::Invalid(format!("status {}", code))),
+        }
+        // Unknown codes are understood as the x00 of their class (RFC 7231,
+        // 6).
+        let status = match HttpStatus::from_u16(code) {
+            Ok(status) => status,
+            Err(_) => HttpStatus::from_u16(code / 100 * 100).map_err(|_| ProxyError::Invalid(format!("status {}", code)))?,
+        };
+        let keep_alive = version == "HTTP/1.1" && !headers.get_list("Connection").iter().any(|token| token.eq_ignore_ascii_case("close"));
+        return Ok((status, headers, keep_alive));
+    }
+}
+
+// How the response body is delimited (RFC 7230, 3.3.3). None means it
+// lasts until the upstream closes the connection.
+fn response_framing(req: &HttpRequest, status: HttpStatus, headers: &HttpHeaders) -> Result<Option<Framing>, ProxyError> {
+    if *req.method() == HttpMethod::HEAD || !status.allows_body() {
+        return Ok(Some(Framing::NoBody));
+    }
+
+    let codings = headers.get_list("Transfer-Encoding");
+    if !codings.is_empty() {
+        let chunked = codings.last().is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"));
+        return Ok(if chunked { Some(Framing::Chunked) } else { None });
+    }
+
+    let mut length = None;
+    for value in headers.get_all("Content-Length") {
+        match value.trim().parse::<u64>() {
+            Ok(parsed) if length.is_none() || length == Some(parsed) => length = Some(parsed),
+            _ => return Err(ProxyError::Invalid(format!("Content-Length {:?}", value))),
+        }
+    }
+    Ok(length.map(Framing::Length))
+}
+
+// Requests that can be sent again as they are: idempotent, without a body
+// that was already read off the client.
+fn can_resend(req: &HttpRequest) -> bool {
+    let idempotent = matches!(*req.method(),
+        HttpMethod::GET | HttpMethod::HEAD | HttpMethod::OPTIONS | HttpMethod::TRACE | HttpMethod::PUT | HttpMethod::DELETE);
+    idempotent && req.body.framing() == Framing::NoBody
+}
+
+fn exchange(req: &mut HttpRequest, upstream: &Upstream, mut conn: UpstreamConnection)
+            -> Result<(BufReader<UpstreamConnection>, HttpStatus, HttpHeaders, bool), ProxyError> {
+    send_request(req, upstream, &mut conn)?;
+    let mut conn = BufReader::new(conn);
+    let (status, headers, keep_alive) = read_head(&mut conn)?;
+    Ok((conn, status, headers, keep_alive))
+}
+
+fn proxy(req: &mut HttpRequest, lease: Lease) -> Result<HttpResponse, ProxyError> {
+    let upstream = lease.upstream();
+    let conn = upstream.checkout().map_err(ProxyError::Connect)?;
+    let reused = conn.is_reused();
+    let (conn, status, mut headers, keep_alive) = match exchange(req, upstream, conn) {
+        // A pooled connection the server closed in the meantime.
+        Err(ref e) if reused && e.is_closed_connection() && can_resend(req) => {
+            info!("Connection to {} was closed, sending {} again on a new one", upstream.address(), req.path);
+            exchange(req, upstream, upstream.open().map_err(ProxyError::Connect)?)?
+        },
+        result => result?,
+    };
+    let framing = response_framing(req, status, &headers)?;
+
+    // Length and framing are worked out again for the client.
+    let length = headers.get("Content-Length").and_then(|length| length.trim().parse::<u64>().ok());
+    strip_hop_by_hop(&mut headers);
+    headers.remove("Content-Length");
+
+    let mut builder = HttpResponse::builder().status(status);
+    for header in headers.iter() {
+        builder = builder.header(&header.name, &header.value);
+    }
+
+    let complete = Rc::new(Cell::new(framing == Some(Framing::NoBody)));
+    let conn = PooledConnection {
+        conn: Some(conn),
+        keep_alive: keep_alive,
+        complete: complete.clone(),
+        lease: lease,
+    };
+
+    Ok(match framing {
+        // The length of what a GET would have got.
+        Some(Framing::NoBody) if *req.method() == HttpMethod::HEAD => builder.stream(Box::new(io::empty()), length),
+        Some(Framing::NoBody) => builder.build(),
+        Some(framing) => {
+            let body = Body::detached(framing, false).attach_owned(Box::new(conn));
+            builder.stream(Box::new(ResponseBody { body: body, complete: complete }), length.filter(|_| framing != Framing::Chunked))
+        },
+        // Never reused, the end of the connection is the end of the body.
+        None => builder.stream(Box::new(conn), None),
+    })
+}
+
+// Forwards the request to a member of the group and returns its response,
+// whose body is read off the member as it's sent. Members that can't be
+// connected to are passed over for the next one; once none is left the
+// request gets a 502. Timeouts reading the response are answered with 504,
+// and a group whose members are all down with 503.
+pub fn forward(req: &mut HttpRequest, group: &UpstreamGroup) -> HttpResponse {
+    let mut tried = Vec::new();
+
+    loop {
+        let lease = match group.pick(req, &tried) {
+            Some(lease) => lease,
+            None if !tried.is_empty() => return HttpResponse::quick_error(HttpStatus::BAD_GATEWAY, String::from("Bad gateway")),
+            None => {
+                error!("Every server of upstream {} is down", group.name());
+                return HttpResponse::quick_error(HttpStatus::SERVICE_UNAVAILABLE, String::from("Service unavailable"));
+            }
+        };
+        let index = lease.index();
+        let address = lease.upstream().address().clone();
+
+        match proxy(req, lease) {
+            Ok(response) => {
+                group.succeeded(index);
+                return response;
+            },
+            Err(e) => {
+                error!("Proxying {} to {} failed: {}", req.path, address, e);
+                match e {
+                    ProxyError::Client(_) => return e.response(),
+                    // Nothing was sent yet, another member can have it.
+                    ProxyError::Connect(_) => {
+                        group.failed(index);
+                        tried.push(index);
+                    },
+                    _ => {
+                        group.failed(index);
+                        return e.response();
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn forwards_requests_and_streams_responses() {
+    use std::net::TcpListener;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use http::client::{ClientCertificate, ClientInfo};
+    use http::traits::FromString;
+    use proxy::upstream::UpstreamAddress;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut conn = BufReader::new(stream);
+        let mut head = String::new();
+        while conn.read_line(&mut head).unwrap() > 2 {}
+
+        let response = "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close, X-Secret\r\nX-Secret: 1\r\nX-App: yes\r\n\r\n5\r\nhello\r\n0\r\n\r\n";
+        conn.get_mut().write_all(response.as_bytes()).unwrap();
+        head
+    });
+
+    let mut req = HttpRequest::from_string(String::from(
+        "GET /app?x=1 HTTP/1.1\r\nHost: example.org\r\nConnection: keep-alive, X-Hop\r\nX-Hop: 1\r\nX-Forwarded-For: 10.0.0.1\r\nX-Client-Cert-Subject: CN=forged")).ok().unwrap();
+    let mut client = ClientInfo::new("192.0.2.7:5000");
+    client.secure = true;
+    client.certificate = Some(ClientCertificate { subject: String::from("CN=alice"), alt_names: vec![String::from("alice.test")] });
+    req.set_client(Arc::new(client));
+
+    let address = UpstreamAddress::from_str(&format!("http://127.0.0.1:{}", port)).unwrap();
+    let upstream = UpstreamGroup::single(Upstream::new(address, Duration::from_secs(5), Duration::from_secs(5)));
+    let mut response = forward(&mut req, &upstream);
+    response.set_keep_alive(true);
+    let mut out = Vec::new();
+    response.write_to(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let head = server.join().unwrap();
+
+    assert!(head.starts_with("GET /app?x=1 HTTP/1.1\r\n"));
+    assert!(head.contains("\r\nX-Forwarded-For: 10.0.0.1, 192.0.2.7\r\n"));
+    assert!(head.contains("\r\nForwarded: for=192.0.2.7;proto=https;host=\"example.org\"\r\n"));
+    assert!(head.contains("\r\nX-Client-Cert-Subject: CN=alice\r\nX-Client-Cert-SAN: alice.test\r\n"));
+    assert!(!head.contains("X-Hop") && !head.contains("forged") && !head.contains("keep-alive"));
+    assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(out.contains("\r\nX-App: yes\r\n") && !out.contains("X-Secret"));
+    assert!(out.ends_with("\r\n\r\n5\r\nhello\r\n0\r\n\r\n"));
+
+    let closed = Upstream::new(UpstreamAddress::Tcp(String::from("127.0.0.1"), port), Duration::from_secs(1), Duration::from_secs(1));
+    assert!(forward(&mut req, &UpstreamGroup::single(closed)).status() == HttpStatus::BAD_GATEWAY);
+}
+
+#[test]
+fn reuses_connections_and_resends_on_closed_ones() {
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use http::client::ClientInfo;
+    use http::traits::FromString;
+    use proxy::upstream::{PoolSettings, UpstreamAddress};
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let read_head = |conn: &mut BufReader<::std::net::TcpStream>| {
+        let mut head = String::new();
+        while conn.read_line(&mut head).unwrap() > 2 {}
+        head
+    };
+    let server = thread::spawn(move || {
+        let mut first = BufReader::new(listener.accept().unwrap().0);
+        let head = read_head(&mut first);
+        first.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\none").unwrap();
+        // The second request comes on the same connection, which is closed
+        // without an answer as if it had timed out.
+        let resent = read_head(&mut first);
+        drop(first);
+
+        let mut second = BufReader::new(listener.accept().unwrap().0);
+        let again = read_head(&mut second);
+        second.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\ntwo").unwrap();
+        (head, resent, again)
+    });
+
+    let pool = PoolSettings { max_idle: 4, idle_timeout: Duration::from_secs(30), max_lifetime: Duration::from_secs(60) };
+    let address = UpstreamAddress::Tcp(String::from("127.0.0.1"), port);
+    let group = UpstreamGroup::single(Upstream::new(address, Duration::from_secs(5), Duration::from_secs(5)).with_pool(pool));
+    let get = |path: &str| {
+        let mut req = HttpRequest::from_string(format!("GET {} HTTP/1.1\r\nHost: example.org", path)).ok().unwrap();
+        req.set_client(Arc::new(ClientInfo::new("192.0.2.7:5000")));
+        let mut out = Vec::new();
+        forward(&mut req, &group).write_to(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+
+    assert!(get("/1").ends_with("\r\n\r\none"));
+    assert!(get("/2").ends_with("\r\n\r\ntwo"));
+    let (head, resent, again) = server.join().unwrap();
+    assert!(head.starts_with("GET /1 ") && !head.contains("Connection"));
+    assert!(resent.starts_with("GET /2 ") && again == resent);
+}
+
+#[test]
+fn answers_timeouts_with_504_and_other_failures_with_502() {
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use http::client::ClientInfo;
+    use http::traits::FromString;
+    use proxy::upstream::UpstreamAddress;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || {
+        // Reset before answering, then too slow to answer.
+        let (reset, _) = listener.accept().unwrap();
+        drop(reset);
+        let (slow, _) = listener.accept().unwrap();
+        thread::sleep(Duration::from_secs(2));
+        drop(slow);
+    });
+
+    let address = UpstreamAddress::Tcp(String::from("127.0.0.1"), port);
+    let group = UpstreamGroup::single(Upstream::new(address, Duration::from_secs(5), Duration::from_secs(1)));
+    let get = || {
+        let mut req = HttpRequest::from_string(String::from("GET / HTTP/1.1\r\nHost: example.org")).ok().unwrap();
+        req.set_client(Arc::new(ClientInfo::new("192.0.2.7:5000")));
+        forward(&mut req, &group).status()
+    };
+
+    assert!(get() == HttpStatus::BAD_GATEWAY);
+    assert!(get() == HttpStatus::GATEWAY_TIMEOUT);
+    server.join().unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn sends_localhost_to_unix_sockets_without_a_host() {
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use http::client::ClientInfo;
+    use http::traits::FromString;
+    use proxy::upstream::UpstreamAddress;
+
+    let mut req = HttpRequest::from_string(String::from("GET / HTTP/1.0")).ok().unwrap();
+    req.set_client(Arc::new(ClientInfo::new("192.0.2.7:5000")));
+    let upstream = |address: &str| Upstream::new(UpstreamAddress::from_str(address).unwrap(), Duration::from_secs(1), Duration::from_secs(1));
+
+    assert!(request_head(&req, &upstream("unix:/run/app.sock")).contains("\r\nHost: localhost\r\n"));
+    assert!(request_head(&req, &upstream("http://10.0.0.1:8080")).contains("\r\nHost: 10.0.0.1:8080\r\n"));
+}
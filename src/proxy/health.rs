@@ -0,0 +1,72 @@
+// Copyright (c) 2015 Guillaume Pasquet
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// Active health checks of the members of upstream groups.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::Arc;
+use std::thread;
+
+use proxy::balancer::{HealthCheck, UpstreamGroup};
+use proxy::upstream::Upstream;
+
+// Longest status line read from a member.
+const MAX_STATUS_LINE: u64 = 1024;
+
+// Asks a member for the check's path. Any 2xx or 3xx will do.
+fn probe(upstream: &Upstream, check: &HealthCheck) -> Result<(), String> {
+    let upstream = Upstream::new(upstream.address().clone(), check.timeout, check.timeout);
+    let mut stream = upstream.connect().map_err(|e| format!("couldn't connect: {}", e))?;
+
+    let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: irontray health check\r\nConnection: close\r\n\r\n",
+                          check.path, upstream.address().host());
+    stream.write_all(request.as_bytes()).map_err(|e| format!("couldn't send the request: {}", e))?;
+
+    let mut status_line = String::new();
+    BufReader::new(stream).take(MAX_STATUS_LINE).read_line(&mut status_line).map_err(|e| format!("no response: {}", e))?;
+    match status_line.split(' ').nth(1).and_then(|code| code.parse::<u16>().ok()) {
+        Some(200..=399) => Ok(()),
+        Some(code) => Err(format!("answered {}", code)),
+        None => Err(format!("invalid status line {:?}", status_line.trim_end())),
+    }
+}
+
+// Checks every member of the group in turn, every interval, for as long as
+// the server runs.
+pub fn watch(group: Arc<UpstreamGroup>) {
+    let check = match group.health_check() {
+        Some(check) => check.clone(),
+        None => return,
+    };
+
+    loop {
+        for member in group.members() {
+            match probe(member.upstream(), &check) {
+                Ok(()) => if member.set_healthy(true) {
+                    info!("Upstream {} of {} is back up", member.upstream().address(), group.name());
+                },
+                Err(e) => if member.set_healthy(false) {
+                    error!("Upstream {} of {} is down: {}", member.upstream().address(), group.name(), e);
+                },
+            }
+        }
+        thread::sleep(check.interval);
+    }
+}
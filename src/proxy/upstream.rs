@@ -0,0 +1,351 @@
+// Copyright (c) 2015 Guillaume Pasquet
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+// Upstream servers requests are proxied to, and the connections to them.
+
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Where an upstream server listens.
+#[derive(Clone, PartialEq, Debug)]
+pub enum UpstreamAddress {
+    // "http://host:port", on port 80 if none is given.
+    Tcp(String, u16),
+    // "unix:/run/app.sock"
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for UpstreamAddress {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<UpstreamAddress, ()> {
+        #[cfg(unix)]
+        {
+            if let Some(path) = s.strip_prefix("unix:") {
+                if path.is_empty() {
+                    return Err(());
+                }
+                return Ok(UpstreamAddress::Unix(PathBuf::from(path)));
+            }
+        }
+
+        // Only an authority: the path of the request is passed on as is.
+        let authority = s.strip_prefix("http://").ok_or(())?;
+        let authority = authority.strip_suffix('/').unwrap_or(authority);
+        if authority.is_empty() || authority.contains(['/', '?', '#', '@']) {
+            return Err(());
+        }
+
+        let (host, port) = match authority.rfind(':') {
+            Some(pos) if !authority[pos..].contains(']') => {
+                (&authority[..pos], authority[pos + 1..].parse::<u16>().map_err(|_| ())?)
+            },
+            _ => (authority, 80),
+        };
+        let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+        if host.is_empty() {
+            return Err(());
+        }
+        Ok(UpstreamAddress::Tcp(host.to_string(), port))
+    }
+}
+
+impl UpstreamAddress {
+    // The Host sent to the server when the client gave none. A socket path
+    // is no host name, so those requests go to localhost.
+    pub fn host(&self) -> String {
+        match *self {
+            UpstreamAddress::Tcp(..) => self.to_string(),
+            #[cfg(unix)]
+            UpstreamAddress::Unix(_) => String::from("localhost"),
+        }
+    }
+}
+
+impl fmt::Display for UpstreamAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UpstreamAddress::Tcp(ref host, port) if host.contains(':') => write!(f, "[{}]:{}", host, port),
+            UpstreamAddress::Tcp(ref host, port) => write!(f, "{}:{}", host, port),
+            #[cfg(unix)]
+            UpstreamAddress::Unix(ref path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+// A connection to an upstream server.
+pub enum UpstreamStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl UpstreamStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match *self {
+            UpstreamStream::Tcp(ref stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            UpstreamStream::Unix(ref stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    // Whether an idle connection can still carry a request. One the server
+    // closed reads as the end of the stream, and one it sent something on
+    // out of turn can't be trusted either.
+    fn is_usable(&mut self) -> bool {
+        if self.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let usable = match self.read(&mut [0u8]) {
+            Err(ref e) => e.kind() == io::ErrorKind::WouldBlock,
+            Ok(_) => false,
+        };
+        self.set_nonblocking(false).is_ok() && usable
+    }
+
+    fn set_timeout(&self, timeout: Duration) -> io::Result<()> {
+        match *self {
+            UpstreamStream::Tcp(ref stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))
+            },
+            #[cfg(unix)]
+            UpstreamStream::Unix(ref stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))
+            },
+        }
+    }
+}
+
+impl Read for UpstreamStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            UpstreamStream::Tcp(ref mut stream) => stream.read(buf),
+            #[cfg(unix)]
+            UpstreamStream::Unix(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for UpstreamStream {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match *self {
+            UpstreamStream::Tcp(ref mut stream) => stream.write(data),
+            #[cfg(unix)]
+            UpstreamStream::Unix(ref mut stream) => stream.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            UpstreamStream::Tcp(ref mut stream) => stream.flush(),
+            #[cfg(unix)]
+            UpstreamStream::Unix(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+// How many connections are kept open once their request is done, and for
+// how long. A max_idle of 0 closes each after its request.
+#[derive(Clone, Copy, Debug)]
+pub struct PoolSettings {
+    pub max_idle: usize,
+    // How long one waits for the next request.
+    pub idle_timeout: Duration,
+    // How long one is used at all, counted from when it was opened.
+    pub max_lifetime: Duration,
+}
+
+// A connection from the pool, or a new one.
+pub struct UpstreamConnection {
+    stream: UpstreamStream,
+    opened: Instant,
+    reused: bool,
+}
+
+impl UpstreamConnection {
+    // Whether it already carried a request, so the server may have closed
+    // it just as this one was sent.
+    pub fn is_reused(&self) -> bool {
+        self.reused
+    }
+}
+
+impl Read for UpstreamConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for UpstreamConnection {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.stream.write(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+// A connection waiting in the pool, since when.
+struct IdleConnection {
+    connection: UpstreamConnection,
+    since: Instant,
+}
+
+pub struct Upstream {
+    address: UpstreamAddress,
+    connect_timeout: Duration,
+    // How long to wait for each read or write once connected.
+    read_timeout: Duration,
+    pool: PoolSettings,
+    // Most recently used last.
+    idle: Mutex<Vec<IdleConnection>>,
+}
+
+impl Upstream {
+    pub fn new(address: UpstreamAddress, connect_timeout: Duration, read_timeout: Duration) -> Upstream {
+        Upstream {
+            address: address,
+            connect_timeout: connect_timeout,
+            read_timeout: read_timeout,
+            pool: PoolSettings { max_idle: 0, idle_timeout: Duration::from_secs(0), max_lifetime: Duration::from_secs(0) },
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Keeps connections open between requests.
+    pub fn with_pool(mut self, pool: PoolSettings) -> Upstream {
+        self.pool = pool;
+        self
+    }
+
+    pub fn address(&self) -> &UpstreamAddress {
+        &self.address
+    }
+
+    pub fn keeps_alive(&self) -> bool {
+        self.pool.max_idle > 0
+    }
+
+    fn is_expired(&self, idle: &IdleConnection, now: Instant) -> bool {
+        now.duration_since(idle.since) >= self.pool.idle_timeout
+            || now.duration_since(idle.connection.opened) >= self.pool.max_lifetime
+    }
+
+    // The most recently used idle connection that is still open, or a new
+    // one.
+    pub fn checkout(&self) -> io::Result<UpstreamConnection> {
+        loop {
+            let mut idle = match self.idle.lock().unwrap().pop() {
+                Some(idle) => idle,
+                None => break,
+            };
+            if !self.is_expired(&idle, Instant::now()) && idle.connection.stream.is_usable() {
+                idle.connection.reused = true;
+                return Ok(idle.connection);
+            }
+        }
+        self.open()
+    }
+
+    // A new connection, whatever the pool holds.
+    pub fn open(&self) -> io::Result<UpstreamConnection> {
+        Ok(UpstreamConnection {
+            stream: self.connect()?,
+            opened: Instant::now(),
+            reused: false,
+        })
+    }
+
+    // Puts a connection whose response was read in full back in the pool.
+    // Expired connections are closed, and the oldest ones when there are
+    // too many.
+    pub fn checkin(&self, connection: UpstreamConnection) {
+        let now = Instant::now();
+        let idle = IdleConnection { connection: connection, since: now };
+        if !self.keeps_alive() || self.is_expired(&idle, now) {
+            return;
+        }
+
+        let mut pool = self.idle.lock().unwrap();
+        pool.retain(|idle| !self.is_expired(idle, now));
+        pool.push(idle);
+        if pool.len() > self.pool.max_idle {
+            pool.remove(0);
+        }
+    }
+
+    // Opens a connection, trying each address the host resolves to in turn.
+    pub fn connect(&self) -> io::Result<UpstreamStream> {
+        let stream = match self.address {
+            UpstreamAddress::Tcp(ref host, port) => {
+                let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", host));
+                let mut connected = None;
+                for addr in (host.as_str(), port).to_socket_addrs()? {
+                    match TcpStream::connect_timeout(&addr, self.connect_timeout) {
+                        Ok(stream) => {
+                            connected = Some(stream);
+                            break;
+                        },
+                        Err(e) => last_error = e,
+                    }
+                }
+                let stream = connected.ok_or(last_error)?;
+                stream.set_nodelay(true)?;
+                UpstreamStream::Tcp(stream)
+            },
+            #[cfg(unix)]
+            UpstreamAddress::Unix(ref path) => UpstreamStream::Unix(UnixStream::connect(path)?),
+        };
+
+        stream.set_timeout(self.read_timeout)?;
+        Ok(stream)
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn parses_upstream_addresses() {
+    let parse = |s: &str| UpstreamAddress::from_str(s);
+
+    assert!(parse("http://127.0.0.1:8080") == Ok(UpstreamAddress::Tcp("127.0.0.1".to_string(), 8080)));
+    assert!(parse("http://backend/") == Ok(UpstreamAddress::Tcp("backend".to_string(), 80)));
+    assert!(parse("http://[::1]:9000") == Ok(UpstreamAddress::Tcp("::1".to_string(), 9000)));
+    assert!(parse("http://[::1]:9000").unwrap().to_string() == "[::1]:9000");
+    assert!(parse("http://[::1]:9000").unwrap().host() == "[::1]:9000");
+    assert!(parse("unix:/run/app.sock").unwrap().host() == "localhost");
+    assert!(parse("unix:/run/app.sock") == Ok(UpstreamAddress::Unix(PathBuf::from("/run/app.sock"))));
+    assert!(parse("http://backend/app").is_err());
+    assert!(parse("https://backend").is_err());
+    assert!(parse("http://backend:http").is_err());
+}
@@ -0,0 +1,153 @@
+// Copyright (c) 2015 Guillaume Pasquet
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// Names of a client certificate, for access rules, logs and upstreams. The
+// certificate has already been verified; this only reads the subject's
+// distinguished name and the alternative names out of it.
+
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+use rustls::pki_types::CertificateDer;
+use x509_parser::der_parser::asn1_rs::{Any, BmpString, Tag};
+use x509_parser::extensions::GeneralName;
+use x509_parser::parse_x509_certificate;
+use x509_parser::x509::{AttributeTypeAndValue, X509Name};
+
+use http::client::ClientCertificate;
+
+// Whether clients are asked for a certificate during the handshake.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ClientAuth {
+    Off,
+    // A certificate is verified if the client sends one, but it may not.
+    Optional,
+    // Clients without a valid certificate fail the handshake.
+    Required,
+}
+
+impl FromStr for ClientAuth {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<ClientAuth, ()> {
+        match s {
+            "off"      => Ok(ClientAuth::Off),
+            "optional" => Ok(ClientAuth::Optional),
+            "required" => Ok(ClientAuth::Required),
+            _ => Err(()),
+        }
+    }
+}
+
+// Short names of the usual attribute types (RFC 4514, 3).
+const ATTRIBUTES: [(&str, &str); 10] = [
+    ("2.5.4.3", "CN"),
+    ("2.5.4.5", "serialNumber"),
+    ("2.5.4.6", "C"),
+    ("2.5.4.7", "L"),
+    ("2.5.4.8", "ST"),
+    ("2.5.4.9", "STREET"),
+    ("2.5.4.10", "O"),
+    ("2.5.4.11", "OU"),
+    ("0.9.2342.19200300.100.1.25", "DC"),
+    ("1.2.840.113549.1.9.1", "emailAddress"),
+];
+
+fn format_value(value: &Any) -> String {
+    match value.tag() {
+        Tag::BmpString => BmpString::try_from(value.clone()).map(|value| value.string()).unwrap_or_default(),
+        Tag::T61String => value.as_bytes().iter().map(|&b| b as char).collect(),
+        // UTF8String, PrintableString, IA5String and the like.
+        _ => String::from_utf8_lossy(value.as_bytes()).into_owned(),
+    }
+}
+
+// Escapes an attribute value (RFC 4514, 2.4).
+fn escape_value(value: &str) -> String {
+    let mut escaped = String::new();
+    let last = value.chars().count().saturating_sub(1);
+
+    for (i, c) in value.chars().enumerate() {
+        let special = match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' => true,
+            '#' => i == 0,
+            ' ' => i == 0 || i == last,
+            _ => false,
+        };
+        if special {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn format_attribute(attribute: &AttributeTypeAndValue) -> String {
+    let oid = attribute.attr_type().to_id_string();
+    let name = ATTRIBUTES.iter().find(|known| known.0 == oid).map_or(oid.as_str(), |known| known.1);
+    format!("{}={}", name, escape_value(&format_value(attribute.attr_value())))
+}
+
+// RFC 4514 string of a Name. The most specific part comes first, e.g.
+// "CN=alice,O=Example,C=FR".
+pub fn format_name(name: &X509Name) -> String {
+    let mut rdns: Vec<String> = name.iter_rdn()
+        .map(|rdn| rdn.iter().map(format_attribute).collect::<Vec<_>>().join("+"))
+        .collect();
+
+    rdns.reverse();
+    rdns.join(",")
+}
+
+pub fn describe(certificate: &CertificateDer) -> Option<ClientCertificate> {
+    let (_, leaf) = parse_x509_certificate(certificate).ok()?;
+    let mut alt_names = Vec::new();
+    if let Some(extension) = leaf.subject_alternative_name().ok()? {
+        for name in &extension.value.general_names {
+            match *name {
+                GeneralName::DNSName(name) | GeneralName::URI(name) => alt_names.push(name.to_string()),
+                _ => {}
+            }
+        }
+    }
+
+    Some(ClientCertificate {
+        subject: format_name(leaf.subject()),
+        alt_names: alt_names,
+    })
+}
+
+#[test]
+fn formats_distinguished_names() {
+    use x509_parser::prelude::FromDer;
+
+    let mut name = vec![0x30, 0x3e];
+    name.extend_from_slice(&[0x31, 0x0b, 0x30, 0x09, 0x06, 0x03, 0x55, 0x04, 0x06, 0x13, 0x02, b'F', b'R']);
+    name.extend_from_slice(&[0x31, 0x0d, 0x30, 0x0b, 0x06, 0x03, 0x55, 0x04, 0x0a, 0x1e, 0x04, 0x00, b'A', 0x00, b'c']);
+    name.extend_from_slice(&[0x31, 0x12, 0x30, 0x10, 0x06, 0x03, 0x55, 0x04, 0x03, 0x0c, 0x09]);
+    name.extend_from_slice(b"alice, jr");
+    name.extend_from_slice(&[0x31, 0x0c, 0x30, 0x0a, 0x06, 0x03, 0x2a, 0x03, 0x04, 0x0c, 0x03]);
+    name.extend_from_slice(b"#x ");
+
+    let (_, parsed) = X509Name::from_der(&name).ok().unwrap();
+    assert!(format_name(&parsed) == "1.2.3.4=\\#x\\ ,CN=alice\\, jr,O=Ac,C=FR");
+    assert!(X509Name::from_der(&name[..20]).is_err());
+}
+
@@ -0,0 +1,186 @@
+// Copyright (c) 2015 Guillaume Pasquet
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// TLS termination with rustls. Accepted connections are wrapped in a stream
+// that reads and writes plain HTTP, so requests are served exactly as they
+// are on a plain socket.
+
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+
+use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned, SupportedProtocolVersion};
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::version::{TLS12, TLS13};
+
+use config::tlsconfig::TlsConfig;
+use http::client::ClientCertificate;
+
+pub mod identity;
+pub mod sni;
+
+use tls::identity::ClientAuth;
+use tls::sni::SniResolver;
+
+fn open_pem(path: &Path) -> Result<BufReader<File>, String> {
+    match File::open(path) {
+        Ok(file) => Ok(BufReader::new(file)),
+        Err(e) => Err(format!("Couldn't open {:?}: {}", path, e)),
+    }
+}
+
+pub fn load_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let mut reader = open_pem(path)?;
+    let certificates = match rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>() {
+        Ok(certificates) => certificates,
+        Err(e) => return Err(format!("Couldn't read certificates from {:?}: {}", path, e)),
+    };
+
+    if certificates.is_empty() {
+        return Err(format!("No certificate found in {:?}", path));
+    }
+    Ok(certificates)
+}
+
+pub fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, String> {
+    let mut reader = open_pem(path)?;
+    match rustls_pemfile::private_key(&mut reader) {
+        Ok(Some(key)) => Ok(key),
+        Ok(None) => Err(format!("No private key found in {:?}", path)),
+        Err(e) => Err(format!("Couldn't read private key from {:?}: {}", path, e)),
+    }
+}
+
+// Builds the rustls configuration of the HTTPS listener. When a reload
+// interval is set, a thread watches the certificate files from then on.
+pub fn server_config(config: &TlsConfig) -> Result<Arc<ServerConfig>, String> {
+    let mut provider = ring::default_provider();
+    if !config.get_ciphers().is_empty() {
+        let mut suites = Vec::new();
+        for name in config.get_ciphers() {
+            match provider.cipher_suites.iter().find(|suite| format!("{:?}", suite.suite()) == *name) {
+                Some(suite) => suites.push(*suite),
+                None => return Err(format!("Unknown cipher suite {:?}", name)),
+            }
+        }
+        provider.cipher_suites = suites;
+    }
+
+    let versions: Vec<&'static SupportedProtocolVersion> = config.get_versions().iter()
+        .map(|version| if version == "1.2" { &TLS12 } else { &TLS13 })
+        .collect();
+
+    let provider = Arc::new(provider);
+    let resolver = Arc::new(SniResolver::load(config)?);
+    if config.get_reload_interval() > 0 {
+        let resolver = resolver.clone();
+        let config = config.clone();
+        thread::spawn(move || sni::watch(resolver, config));
+    }
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&versions)
+        .map_err(|e| format!("Invalid TLS settings: {}", e))?;
+    let builder = match (config.get_client_auth(), config.get_client_ca()) {
+        (ClientAuth::Off, _) | (_, None) => builder.with_no_client_auth(),
+        (client_auth, Some(client_ca)) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in load_certificates(client_ca)? {
+                roots.add(certificate).map_err(|e| format!("Invalid CA certificate in {:?}: {}", client_ca, e))?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = if client_auth == ClientAuth::Optional { verifier.allow_unauthenticated() } else { verifier };
+            let verifier = verifier.build().map_err(|e| format!("Invalid client CA bundle {:?}: {}", client_ca, e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+    };
+
+    let mut server = builder.with_cert_resolver(resolver);
+    server.alpn_protocols = config.get_alpn().iter().map(|protocol| protocol.as_bytes().to_vec()).collect();
+
+    Ok(Arc::new(server))
+}
+
+pub struct TlsStream {
+    stream: StreamOwned<ServerConnection, TcpStream>,
+}
+
+// Runs the handshake on a freshly accepted connection. The socket's
+// timeouts apply to it.
+pub fn accept(mut socket: TcpStream, config: &Arc<ServerConfig>) -> io::Result<TlsStream> {
+    let mut connection = ServerConnection::new(config.clone())
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+    while connection.is_handshaking() {
+        connection.complete_io(&mut socket)?;
+    }
+
+    Ok(TlsStream { stream: StreamOwned::new(connection, socket) })
+}
+
+impl TlsStream {
+    pub fn server_name(&self) -> Option<String> {
+        self.stream.conn.server_name().map(|name| name.to_string())
+    }
+
+    // The certificate the client authenticated with, already verified
+    // during the handshake.
+    pub fn client_certificate(&self) -> Option<ClientCertificate> {
+        self.stream.conn.peer_certificates()
+            .and_then(|chain| chain.first())
+            .and_then(identity::describe)
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+// Tells the client the connection is closing on purpose, so it can tell a
+// complete response from a truncated one.
+impl Drop for TlsStream {
+    fn drop(&mut self) {
+        self.stream.conn.send_close_notify();
+        while self.stream.conn.wants_write() {
+            if self.stream.conn.write_tls(&mut self.stream.sock).is_err() {
+                break;
+            }
+        }
+    }
+}
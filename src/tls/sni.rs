@@ -0,0 +1,201 @@
+// Copyright (c) 2015 Guillaume Pasquet
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// Certificate selection by SNI (RFC 6066, 3), with "*.example.org"
+// wildcards and a default certificate for everything else. Certificates are
+// read again when their files change, without restarting the listener.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use rustls::crypto::ring;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use webpki::EndEntityCert;
+
+use config::tlsconfig::{CertificateConfig, TlsConfig};
+use tls::{load_certificates, load_key};
+
+// Host names are compared without case or a trailing dot.
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+// Values looked up by host name. Exact names win over wildcards, which only
+// stand for a single label.
+#[derive(Debug)]
+pub struct NameMap<T> {
+    exact: HashMap<String, T>,
+    wildcard: HashMap<String, T>,
+}
+
+impl<T> NameMap<T> {
+    pub fn new() -> NameMap<T> {
+        NameMap { exact: HashMap::new(), wildcard: HashMap::new() }
+    }
+
+    // The first value given for a name is kept.
+    pub fn insert(&mut self, name: &str, value: T) {
+        let name = normalize(name);
+        let map = if name.starts_with("*.") { &mut self.wildcard } else { &mut self.exact };
+        let key = name.trim_start_matches("*.").to_string();
+        map.entry(key).or_insert(value);
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<&T> {
+        let name = normalize(name);
+        if let Some(value) = self.exact.get(&name) {
+            return Some(value);
+        }
+
+        match name.find('.') {
+            Some(dot) => self.wildcard.get(&name[dot + 1..]),
+            None => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Certificates {
+    names: NameMap<Arc<CertifiedKey>>,
+    default: Arc<CertifiedKey>,
+}
+
+// Loads a certificate and its key, and returns the names it's for.
+fn load_certified_key(config: &CertificateConfig) -> Result<(Arc<CertifiedKey>, Vec<String>), String> {
+    let certificates = load_certificates(config.get_certificate())?;
+    let key = load_key(config.get_key())?;
+
+    let names = if config.get_names().is_empty() {
+        match EndEntityCert::try_from(&certificates[0]) {
+            Ok(leaf) => leaf.valid_dns_names().map(|name| name.to_string()).collect(),
+            Err(e) => return Err(format!("Couldn't parse certificate {:?}: {:?}", config.get_certificate(), e)),
+        }
+    } else {
+        config.get_names().to_vec()
+    };
+
+    let signing_key = match ring::default_provider().key_provider.load_private_key(key) {
+        Ok(signing_key) => signing_key,
+        Err(e) => return Err(format!("Unsupported private key {:?}: {}", config.get_key(), e)),
+    };
+
+    let certified = CertifiedKey::new(certificates, signing_key);
+    if let Err(e) = certified.keys_match() {
+        return Err(format!("Key {:?} doesn't match certificate {:?}: {}", config.get_key(), config.get_certificate(), e));
+    }
+
+    Ok((Arc::new(certified), names))
+}
+
+fn load_all(config: &TlsConfig) -> Result<Certificates, String> {
+    let mut names = NameMap::new();
+    for certificate in config.get_certificates() {
+        let (certified, certificate_names) = load_certified_key(certificate)?;
+        for name in certificate_names {
+            names.insert(&name, certified.clone());
+        }
+    }
+
+    // The default certificate also answers for its own names, after the
+    // others.
+    let (default, default_names) = load_certified_key(config.get_default_certificate())?;
+    for name in default_names {
+        names.insert(&name, default.clone());
+    }
+
+    Ok(Certificates { names: names, default: default })
+}
+
+#[derive(Debug)]
+pub struct SniResolver {
+    certificates: RwLock<Arc<Certificates>>,
+}
+
+impl SniResolver {
+    pub fn load(config: &TlsConfig) -> Result<SniResolver, String> {
+        Ok(SniResolver { certificates: RwLock::new(Arc::new(load_all(config)?)) })
+    }
+
+    // Swaps in freshly read certificates. The current ones stay in use if
+    // any of the new ones can't be loaded.
+    pub fn reload(&self, config: &TlsConfig) -> Result<(), String> {
+        let certificates = Arc::new(load_all(config)?);
+        *self.certificates.write().unwrap() = certificates;
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let certificates = self.certificates.read().unwrap().clone();
+        let selected = hello.server_name().and_then(|name| certificates.names.lookup(name));
+        Some(selected.unwrap_or(&certificates.default).clone())
+    }
+}
+
+fn modification_times(config: &TlsConfig) -> Vec<Option<SystemTime>> {
+    config.get_certificates().iter()
+        .chain(Some(config.get_default_certificate()))
+        .flat_map(|certificate| vec![certificate.get_certificate(), certificate.get_key()])
+        .map(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+        .collect()
+}
+
+// Reloads the certificates whenever one of their files changes, checking
+// every reload_interval seconds.
+pub fn watch(resolver: Arc<SniResolver>, config: TlsConfig) {
+    let interval = Duration::from_secs(config.get_reload_interval());
+    let mut last = modification_times(&config);
+
+    loop {
+        thread::sleep(interval);
+
+        let current = modification_times(&config);
+        if current == last {
+            continue;
+        }
+        last = current;
+
+        match resolver.reload(&config) {
+            Ok(()) => info!("Reloaded TLS certificates"),
+            Err(e) => error!("Keeping the previous TLS certificates: {}", e),
+        }
+    }
+}
+
+#[test]
+fn looks_up_exact_then_wildcard_names() {
+    let mut names = NameMap::new();
+    names.insert("*.example.org", "wildcard");
+    names.insert("www.example.org", "www");
+    names.insert("Example.org.", "apex");
+    names.insert("*.example.org", "ignored");
+
+    assert!(names.lookup("WWW.example.org") == Some(&"www"));
+    assert!(names.lookup("static.example.org") == Some(&"wildcard"));
+    assert!(names.lookup("example.org") == Some(&"apex"));
+    assert!(names.lookup("a.b.example.org") == None);
+    assert!(names.lookup("example.com") == None);
+}